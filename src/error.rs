@@ -10,6 +10,9 @@ pub enum Error {
     ParseTimeError(ParseErrorDetails<chrono::format::ParseError>),
     ParseIntError(ParseErrorDetails<std::num::ParseIntError>),
     UnrecognizedPackage { report_type: String, report_subtype: String },
+    UnsupportedReportVersion { report_type: String, report_subtype: String, version: u32 },
+    UnregisteredReportVersion { report_type: String, report_subtype: String, version: u32, read_as: u32 },
+    MissingRequiredColumn { column: String, report_type: String, report_subtype: String, version: u32 },
     Csv(csv::Error),
     Io(std::io::Error),
     Zip(zip::result::ZipError),
@@ -17,7 +20,7 @@ pub enum Error {
     Parquet(parquet::errors::ParquetError),
     InvalidFilename(std::path::PathBuf),
     MissingColumnHeader(String),
-    DatatypeMismatch { datatype: arrow::datatypes::DataType, value: Option<crate::flatfile::DataValue> },
+    DatatypeMismatch { datatype: arrow::datatypes::DataType, value: String },
     IndexError(usize),
     UnsupportedDataType(arrow::datatypes::DataType),
     NullError,
@@ -26,9 +29,24 @@ pub enum Error {
     ScraperError,
     ZipUrlNoFilename(String),
     FailedToDownload { url: String, path: std::path::PathBuf, status: reqwest::StatusCode },
+    FailedToFetch { url: String, status: reqwest::StatusCode },
+    UnsupportedUrl(String),
+    NoHistoricData { table: String, url: String },
+    DownloadsFailed(usize),
     InvalidYear(String),
     InvalidMonth(String),
     ManageError(crate::manage::state::Error),
+    MissingEndOfReport,
+    RecordCountMismatch { expected: u32, found: usize },
+    InvalidDataModel(String),
+    InvalidPackageDefinition(String),
+    InvalidFilter(String),
+    EmptyProjection { report_type: String, report_subtype: String },
+    PrimaryKeyNotProjected { package: String, columns: Vec<String> },
+    UnreadableRecord { line: Option<u64>, error: String },
+    DataBeforeInformation { line: Option<u64> },
+    InvalidDateTime(String),
+    Toml(toml::de::Error),
 }
 
 impl Display for Error {
@@ -49,6 +67,12 @@ impl Display for Error {
                 write!(f, "Parse integer error: {}", d),
             UnrecognizedPackage { report_type, report_subtype } => 
                 write!(f, "Unrecognized package:\n\tReport type:\t{}\n\tSubtype:\t{}", report_type, report_subtype), 
+            UnsupportedReportVersion { report_type, report_subtype, version } =>
+                write!(f, "Unsupported report version:\n\tReport type:\t{}\n\tSubtype:\t{}\n\tVersion:\t{}", report_type, report_subtype, version),
+            UnregisteredReportVersion { report_type, report_subtype, version, read_as } =>
+                write!(f, "{} {} version {} isn't registered, reading it as version {}", report_type, report_subtype, version, read_as),
+            MissingRequiredColumn { column, report_type, report_subtype, version } =>
+                write!(f, "{} {} version {} has no {} column, which can't be null", report_type, report_subtype, version, column),
             Csv(e) => 
                 write!(f, "CSV error: {}", e),
             Io(e) =>
@@ -64,7 +88,7 @@ impl Display for Error {
             MissingColumnHeader(c) =>
                 write!(f, "Missing column header: {}", c),
             DatatypeMismatch { datatype, value } => 
-                write!(f, "Datatype mismatch. Expected {} but got value {}", datatype, value.as_ref().map(|v| v.clone().as_string().unwrap()).unwrap_or("".to_string())), // TODO rm unwrap
+                write!(f, "Datatype mismatch. Expected {} but got value {}", datatype, value),
             IndexError(i) => 
                 write!(f, "Index error: {}", i),
             UnsupportedDataType(dt) =>
@@ -81,12 +105,46 @@ impl Display for Error {
                 write!(f, "No filename found for {}", s),
             FailedToDownload { url, path, status } =>
                 write!(f, "Failed to download {} to {}. Got status {}", url, path.to_string_lossy(), status),
+            FailedToFetch { url, status } =>
+                write!(f, "Failed to fetch {}. Got status {}", url, status),
+            UnsupportedUrl(u) =>
+                write!(f, "Unsupported url: {}", u),
+            NoHistoricData { table, url } =>
+                write!(f, "No files for {} found in {}", table, url),
+            DownloadsFailed(n) =>
+                write!(f, "{} downloads failed", n),
             InvalidYear(y) => 
                 write!(f, "Invalid year (format is yyyy or yy): {}", y),
             InvalidMonth(m) =>
                 write!(f, "Invalid month (format is mm): {}", m),
             ManageError(e) =>
                 write!(f, "Manage error:\n{}", e),
+            MissingEndOfReport =>
+                write!(f, "No END OF REPORT record found, the file may be truncated"),
+            RecordCountMismatch { expected, found } =>
+                write!(f, "END OF REPORT expected {} records but found {}, the file may be truncated", expected, found),
+            InvalidDataModel(s) =>
+                write!(f, "Invalid data model definition: {}", s),
+            InvalidPackageDefinition(s) =>
+                write!(f, "Invalid package definition: {}", s),
+            InvalidFilter(s) =>
+                write!(f, "Invalid filter, expected COLUMN=VALUE[,VALUE...] or a comparison like COLUMN>=VALUE: {}", s),
+            EmptyProjection { report_type, report_subtype } =>
+                write!(f, "None of the selected columns are in {} {}", report_type, report_subtype),
+            UnreadableRecord { line: Some(line), error } =>
+                write!(f, "Can't read the data record on line {}: {}", line, error),
+            UnreadableRecord { line: None, error } =>
+                write!(f, "Can't read a data record: {}", error),
+            DataBeforeInformation { line: Some(line) } =>
+                write!(f, "The data record on line {} comes before any information record", line),
+            DataBeforeInformation { line: None } =>
+                write!(f, "A data record comes before any information record"),
+            PrimaryKeyNotProjected { package, columns } =>
+                write!(f, "Can't deduplicate {} without its primary key, select {} as well", package, columns.join(", ")),
+            InvalidDateTime(s) =>
+                write!(f, "Invalid datetime {}, expected YYYY/MM/DD[ HH:MM[:SS]]", s),
+            Toml(e) =>
+                write!(f, "{}", e),
         }
     }
 }
@@ -166,6 +224,41 @@ impl<E: Display> Display for ParseErrorDetails<E> {
     }
 }
 
+/// Receives problems that were tolerated rather than returned, such as a
+/// missing END OF REPORT in lenient mode
+///
+/// Library code never prints these itself, it's up to the caller to report
+/// them however suits.
+#[derive(Clone)]
+pub struct WarningHandler(std::sync::Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl WarningHandler {
+    pub fn new<F: Fn(&Error) + Send + Sync + 'static>(f: F) -> Self {
+        WarningHandler(std::sync::Arc::new(f))
+    }
+
+    pub fn warn(&self, warning: &Error) {
+        (self.0)(warning)
+    }
+}
+
+impl fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "WarningHandler")
+    }
+}
+
+impl PartialEq for WarningHandler {
+    fn eq(&self, other: &Self) -> bool {
+        // Compares the closures themselves, vtables can differ between
+        // codegen units
+        std::ptr::eq(
+            std::sync::Arc::as_ptr(&self.0) as *const u8,
+            std::sync::Arc::as_ptr(&other.0) as *const u8,
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
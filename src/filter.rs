@@ -0,0 +1,216 @@
+use chrono::NaiveDateTime;
+use crate::{
+    error::Error,
+    flatfile::{parse_datetime, DataRecord, InformationRecord},
+};
+
+/// Columns to keep and rows to read, applied to each data record as it's
+/// read so that nothing else is held in memory
+///
+/// Predicates only apply to tables with their column, so a filter on `DUID`
+/// leaves the tables of a report without one as they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub columns: Option<Vec<String>>,
+    pub predicates: Vec<Predicate>,
+}
+
+/// A condition on the value of a column, null values never match
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    /// Equal to one of the values
+    In { column: String, values: Vec<String> },
+    Compare { column: String, comparison: Comparison, value: Value },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Bound of a [`Predicate::Compare`], cells are parsed the same way to
+/// compare them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Number(f64),
+    DateTime(NaiveDateTime),
+}
+
+impl Value {
+    fn parse(s: &str) -> Option<Self> {
+        parse_datetime(s)
+            .map(Value::DateTime)
+            .or_else(|| s.parse().ok().map(Value::Number))
+    }
+
+    fn parse_like(&self, s: &str) -> Option<Self> {
+        match self {
+            Value::Number(_) => s.parse().ok().map(Value::Number),
+            Value::DateTime(_) => parse_datetime(s).map(Value::DateTime),
+        }
+    }
+}
+
+impl Predicate {
+    /// Parses `COLUMN=A,B,C`, or a comparison such as
+    /// `SETTLEMENTDATE>=2021/12/01 00:00:00` or `SCADAVALUE<0`
+    pub fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidFilter(s.to_string());
+        let at = s.find(|c: char| c == '=' || c == '<' || c == '>')
+            .ok_or_else(invalid)?;
+        let column = s[..at].trim().to_string();
+        let rest = &s[at..];
+        let (comparison, value) = if let Some(v) = rest.strip_prefix(">=") {
+            (Some(Comparison::Ge), v)
+        } else if let Some(v) = rest.strip_prefix("<=") {
+            (Some(Comparison::Le), v)
+        } else if let Some(v) = rest.strip_prefix('>') {
+            (Some(Comparison::Gt), v)
+        } else if let Some(v) = rest.strip_prefix('<') {
+            (Some(Comparison::Lt), v)
+        } else {
+            (None, &rest[1..])
+        };
+        if column.is_empty() {
+            return Err(invalid())
+        }
+        match comparison {
+            Some(comparison) => {
+                let value = Value::parse(value.trim()).ok_or_else(invalid)?;
+                Ok(Predicate::Compare { column, comparison, value })
+            },
+            None => {
+                let values = value.split(',').map(|v| v.trim().to_string()).collect();
+                Ok(Predicate::In { column, values })
+            },
+        }
+    }
+
+    pub fn column(&self) -> &str {
+        match self {
+            Predicate::In { column, .. } | Predicate::Compare { column, .. } => column,
+        }
+    }
+
+    pub fn matches(&self, cell: Option<&str>) -> bool {
+        let cell = match cell {
+            Some(cell) => cell,
+            None => return false,
+        };
+        match self {
+            Predicate::In { values, .. } => values.iter().any(|v| v == cell),
+            Predicate::Compare { comparison, value, .. } => {
+                let ordering = match (value.parse_like(cell), value) {
+                    (Some(Value::Number(c)), Value::Number(v)) => c.partial_cmp(v),
+                    (Some(Value::DateTime(c)), Value::DateTime(v)) => Some(c.cmp(v)),
+                    _ => None,
+                };
+                match (ordering, comparison) {
+                    (Some(o), Comparison::Lt) => o.is_lt(),
+                    (Some(o), Comparison::Le) => o.is_le(),
+                    (Some(o), Comparison::Gt) => o.is_gt(),
+                    (Some(o), Comparison::Ge) => o.is_ge(),
+                    (None, _) => false,
+                }
+            },
+        }
+    }
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_none() && self.predicates.is_empty()
+    }
+
+    /// Resolves the filter against the columns of a table
+    pub fn for_table(&self, information_record: &InformationRecord) -> TableFilter {
+        let indices = information_record.column_header_indices();
+        let predicates = self.predicates.iter()
+            .filter_map(|p| indices.get(p.column()).map(|idx| (*idx, p.clone())))
+            .collect();
+        let keep = self.columns.as_ref()
+            .map(|columns| information_record.column_headers.iter()
+                .enumerate()
+                .filter(|(_, name)| columns.contains(name))
+                .map(|(idx, _)| idx)
+                .collect());
+        TableFilter { predicates, keep }
+    }
+}
+
+/// A [`Filter`] with its columns found in a table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableFilter {
+    predicates: Vec<(usize, Predicate)>,
+    keep: Option<Vec<usize>>,
+}
+
+impl TableFilter {
+    pub fn matches(&self, record: &DataRecord) -> bool {
+        self.predicates.iter()
+            .all(|(idx, p)| p.matches(record.get(*idx).ok().flatten()))
+    }
+
+    pub fn project_information(&self, information_record: InformationRecord) -> InformationRecord {
+        match &self.keep {
+            Some(keep) => InformationRecord {
+                column_headers: keep.iter()
+                    .map(|idx| information_record.column_headers[*idx].clone())
+                    .collect(),
+                ..information_record
+            },
+            None => information_record,
+        }
+    }
+
+    pub fn project(&self, record: DataRecord) -> DataRecord {
+        match &self.keep {
+            Some(keep) => record.select(keep),
+            None => record,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn parse_predicates() {
+        assert_eq!(
+            Predicate::from_str("DUID=BW01, BW02").unwrap(),
+            Predicate::In { column: "DUID".to_string(), values: vec!["BW01".to_string(), "BW02".to_string()] }
+        );
+        let from = NaiveDate::from_ymd(2021, 12, 1).and_hms(0, 0, 0);
+        assert_eq!(
+            Predicate::from_str("SETTLEMENTDATE>=2021/12/01").unwrap(),
+            Predicate::Compare { column: "SETTLEMENTDATE".to_string(), comparison: Comparison::Ge, value: Value::DateTime(from) }
+        );
+        assert_eq!(
+            Predicate::from_str("SCADAVALUE<0").unwrap(),
+            Predicate::Compare { column: "SCADAVALUE".to_string(), comparison: Comparison::Lt, value: Value::Number(0.0) }
+        );
+        assert!(Predicate::from_str("SCADAVALUE").is_err());
+        assert!(Predicate::from_str("SCADAVALUE>low").is_err());
+        assert!(Predicate::from_str("=1").is_err());
+    }
+
+    #[test]
+    fn match_cells() {
+        let predicate = Predicate::from_str("SETTLEMENTDATE<2021/12/25 10:45:00").unwrap();
+        assert!(predicate.matches(Some("2021/12/25 10:40:00")));
+        assert!(!predicate.matches(Some("2021/12/25 10:45:00")));
+        assert!(!predicate.matches(Some("not a date")));
+        assert!(!predicate.matches(None));
+        let predicate = Predicate::from_str("RRP>=85.33").unwrap();
+        assert!(predicate.matches(Some("85.33")));
+        assert!(!predicate.matches(Some("82.1")));
+        let predicate = Predicate::from_str("REGIONID=NSW1,VIC1").unwrap();
+        assert!(predicate.matches(Some("VIC1")));
+        assert!(!predicate.matches(Some("QLD1")));
+    }
+}
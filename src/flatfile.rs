@@ -1,33 +1,81 @@
-use chrono::naive::{NaiveDate, NaiveTime, NaiveDateTime};
+use chrono::{
+    naive::{NaiveDate, NaiveTime, NaiveDateTime},
+    Timelike,
+};
 use num::NumCast;
 use std::{
     collections::HashMap,
+    convert::TryFrom,
     sync::Arc,
 };
 use arrow::{
     datatypes::{
+        ArrowPrimitiveType,
+        ArrowTimestampType,
         DataType,
+        Field,
+        Schema,
+        SchemaRef,
+        Date32Type,
+        Date64Type,
+        Float32Type,
         Float64Type,
         Int16Type,
+        Int32Type,
+        Int64Type,
         Int8Type,
+        Time32MillisecondType,
+        Time32SecondType,
+        Time64MicrosecondType,
+        Time64NanosecondType,
+        TimestampMicrosecondType,
+        TimestampMillisecondType,
+        TimestampNanosecondType,
         TimestampSecondType,
         TimeUnit,
+        UInt16Type,
+        UInt32Type,
+        UInt64Type,
+        UInt8Type,
     },
     array::{
+        new_null_array,
+        Array,
         ArrayRef,
-        PrimitiveBuilder,
+        BooleanArray,
+        Date32Array,
+        Date64Array,
+        DecimalArray,
+        DecimalBuilder,
+        GenericStringBuilder,
         StringBuilder,
+        StringOffsetSizeTrait,
+        Time32SecondArray,
+        TimestampMicrosecondArray,
+        TimestampMillisecondArray,
+        TimestampNanosecondArray,
+        TimestampSecondArray,
+        PrimitiveArray,
+        PrimitiveBuilder,
         BooleanBuilder,
     },
     record_batch::RecordBatch,
+    util::display::array_value_to_string,
 };
 use crate::{
     error::{
         Error,
         BadPayloadDetails,
         ParseErrorDetails,
+        WarningHandler,
     },
-    packages::Package
+    packages::{
+        Package,
+        registry::REGISTRY,
+    },
+    filter::{Filter, TableFilter},
+    rejects::{Reject, RejectMode},
+    writer::FlatFileWriter,
 };
 
 #[derive(Debug, Clone, PartialEq)]
@@ -35,10 +83,26 @@ pub struct FlatFile(Vec<Record>);
 
 impl FlatFile {
     pub fn read_csv<R: std::io::Read>(rdr: csv::Reader<R>) -> Result<FlatFile, Error> {
+        match FlatFile::read_csv_lenient(rdr)? {
+            (_, Some(e)) => Err(e),
+            (flatfile, None) => Ok(flatfile),
+        }
+    }
+
+    /// Reads a flat file even if its END OF REPORT trailer is missing or
+    /// miscounted, returning that problem alongside it rather than failing
+    pub fn read_csv_lenient<R: std::io::Read>(rdr: csv::Reader<R>) -> Result<(FlatFile, Option<Error>), Error> {
         let records = rdr.into_records()
             .map(|r| r.map_err(Error::Csv).and_then(Record::from_csv_record))
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(FlatFile(records))
+        let trailer = records.iter()
+            .rev()
+            .find_map(|r| match r {
+                Record::Comment(CommentRecord::EOR(eor)) => Some(eor.count_of_records),
+                _ => None
+            });
+        let warning = check_end_of_report(trailer, records.len()).err();
+        Ok((FlatFile(records), warning))
     }
 
     pub fn len(&self) -> usize {
@@ -49,6 +113,24 @@ impl FlatFile {
         &self.0
     }
 
+    /// The EMMS comment record at the top of the file
+    pub fn header(&self) -> Option<&CommentRecordEMMS> {
+        self.0.iter()
+            .find_map(|r| match r {
+                Record::Comment(CommentRecord::EMMS(header)) => Some(header),
+                _ => None
+            })
+    }
+
+    /// Writes the flat file back out as csv, see [`FlatFileWriter`]
+    pub fn write_csv<W: std::io::Write>(&self, wtr: W) -> Result<W, Error> {
+        let mut writer = FlatFileWriter::new(wtr);
+        for record in self.0.iter() {
+            writer.write_record(record)?;
+        }
+        writer.finish()
+    }
+
     pub fn iter(&self) -> FlatFileTableIter<'_> {
         FlatFileTableIter {
             flatfile: &self,
@@ -57,6 +139,276 @@ impl FlatFile {
     }
 }
 
+/// Checks the record count of the END OF REPORT trailer against the number of
+/// rows read, which includes the trailer itself
+///
+/// A missing trailer or a short count usually means the file was truncated.
+fn check_end_of_report(trailer: Option<u32>, rows: usize) -> Result<(), Error> {
+    match trailer {
+        Some(count) if count as usize == rows => Ok(()),
+        Some(count) => Err(Error::RecordCountMismatch { expected: count, found: rows }),
+        None => Err(Error::MissingEndOfReport),
+    }
+}
+
+/// Default number of data records in each table yielded by a [`FlatFileReader`]
+pub const DEFAULT_BATCH_SIZE: usize = 65536;
+
+/// Options for streaming flat files with a [`FlatFileReader`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadOptions {
+    /// Maximum number of data records in each yielded table
+    pub batch_size: usize,
+    /// Warn about, rather than fail on, a missing or mismatched END OF REPORT
+    pub lenient: bool,
+    /// Columns and rows to keep
+    pub filter: Filter,
+    /// Receives the problems tolerated in lenient mode, which are otherwise
+    /// dropped
+    pub warnings: Option<WarningHandler>,
+    /// Keep data records that can't be read, such as one with a bad version
+    /// field, with their table rather than failing, see
+    /// [`FlatFileTable::bad_records`]
+    pub keep_bad_records: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            batch_size: DEFAULT_BATCH_SIZE,
+            lenient: false,
+            filter: Filter::default(),
+            warnings: None,
+            keep_bad_records: false,
+        }
+    }
+}
+
+/// Streams the tables of a flat file csv straight from a `csv::Reader`
+///
+/// Records are never collected for the whole file, instead each report
+/// table is yielded in chunks of at most `batch_size` data records. A large
+/// table is therefore split over several `FlatFileTable`s which all share the
+/// same `InformationRecord`. Data records before the first information
+/// record belong to no table and are an error.
+///
+/// The END OF REPORT trailer is checked before the last table is handed out,
+/// yielding an error instead of it if the file looks truncated (or just
+/// warning in lenient mode). Tables handed out before then may still be from
+/// a bad file, so anything written from them should only be kept once the
+/// reader is exhausted without error.
+pub struct FlatFileReader<R> {
+    records: csv::StringRecordsIntoIter<R>,
+    batch_size: usize,
+    lenient: bool,
+    warnings: Option<WarningHandler>,
+    keep_bad_records: bool,
+    lineage: Lineage,
+    filter: Filter,
+    table_filter: TableFilter,
+    information_record: Option<InformationRecord>,
+    /// Whether an information record has been read, data records before
+    /// the first belong to no table
+    seen_information: bool,
+    data_records: Vec<DataRecord>,
+    bad_records: Vec<BadRecord>,
+    emitted: bool,
+    rows: usize,
+    end_of_report: Option<u32>,
+    checked: bool,
+}
+
+impl<R: std::io::Read> FlatFileReader<R> {
+    pub fn new(rdr: csv::Reader<R>) -> Self {
+        FlatFileReader {
+            records: rdr.into_records(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            lenient: false,
+            warnings: None,
+            keep_bad_records: false,
+            lineage: Lineage::default(),
+            filter: Filter::default(),
+            table_filter: TableFilter::default(),
+            information_record: None,
+            seen_information: false,
+            data_records: Vec::new(),
+            bad_records: Vec::new(),
+            emitted: false,
+            rows: 0,
+            end_of_report: None,
+            checked: false,
+        }
+    }
+
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        FlatFileReader { batch_size: batch_size.max(1), ..self }
+    }
+
+    pub fn lenient(self, lenient: bool) -> Self {
+        FlatFileReader { lenient, ..self }
+    }
+
+    /// Where the problems tolerated in lenient mode go
+    pub fn with_warnings(self, warnings: Option<WarningHandler>) -> Self {
+        FlatFileReader { warnings, ..self }
+    }
+
+    /// Keep data records that can't be read with their table rather than
+    /// failing, see [`FlatFileTable::bad_records`]
+    pub fn keep_bad_records(self, keep_bad_records: bool) -> Self {
+        FlatFileReader { keep_bad_records, ..self }
+    }
+
+    /// Name of the file being read, recorded in the lineage of each table
+    pub fn with_source(self, source_file: &str) -> Self {
+        let lineage = Lineage { source_file: Some(source_file.to_string()), ..self.lineage };
+        FlatFileReader { lineage, ..self }
+    }
+
+    /// Only keep the columns and rows of `filter`, records that don't match
+    /// are dropped as they're read
+    pub fn with_filter(self, filter: &Filter) -> Self {
+        FlatFileReader { filter: filter.clone(), ..self }
+    }
+
+    pub fn with_options(self, options: &ReadOptions) -> Self {
+        self.with_batch_size(options.batch_size)
+            .lenient(options.lenient)
+            .with_warnings(options.warnings.clone())
+            .keep_bad_records(options.keep_bad_records)
+            .with_filter(&options.filter)
+    }
+
+    /// Checks the END OF REPORT trailer, once
+    ///
+    /// On failure the buffered table is dropped, since it's the one that was
+    /// cut short.
+    fn check_trailer(&mut self) -> Result<(), Error> {
+        if self.checked {
+            return Ok(())
+        }
+        self.checked = true;
+        match check_end_of_report(self.end_of_report, self.rows) {
+            Err(e) if self.lenient => {
+                if let Some(warnings) = self.warnings.as_ref() {
+                    warnings.warn(&e);
+                }
+                Ok(())
+            },
+            Err(e) => {
+                self.information_record = None;
+                self.data_records.clear();
+                self.bad_records.clear();
+                Err(e)
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Option<Result<FlatFileTable, Error>> {
+        if let Err(e) = self.check_trailer() {
+            return Some(Err(e))
+        }
+        self.take_table()
+    }
+
+    /// Hands out the data records buffered for the current table
+    ///
+    /// A table without any data records is still yielded once so that empty
+    /// reports are not lost.
+    fn take_table(&mut self) -> Option<Result<FlatFileTable, Error>> {
+        let information_record = self.information_record.as_ref()?;
+        if self.data_records.is_empty() && self.bad_records.is_empty() && self.emitted {
+            return None
+        }
+        self.emitted = true;
+        let data_records = std::mem::take(&mut self.data_records);
+        Some(Ok(FlatFileTable {
+            information_record: information_record.clone(),
+            data_records,
+            bad_records: std::mem::take(&mut self.bad_records),
+            lineage: self.lineage.clone(),
+            projection: self.filter.columns.clone(),
+        }))
+    }
+}
+
+impl<R: std::io::Read> Iterator for FlatFileReader<R> {
+    type Item = Result<FlatFileTable, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (line, record) = match self.records.next() {
+                Some(r) => (
+                    r.as_ref().ok().and_then(|r| r.position()).map(|p| p.line()),
+                    r.map_err(Error::Csv).and_then(Record::from_csv_record),
+                ),
+                None => return self.finish(),
+            };
+            let record = match record {
+                Err(e) if self.keep_bad_records => match BadRecord::from_error(e) {
+                    Ok(_) if !self.seen_information => return Some(Err(Error::DataBeforeInformation { line })),
+                    Ok(bad) => {
+                        self.rows = self.rows + 1;
+                        if self.information_record.is_some() {
+                            self.bad_records.push(bad);
+                        }
+                        continue
+                    },
+                    Err(e) => Err(e),
+                },
+                record => record,
+            };
+            if record.is_ok() {
+                self.rows = self.rows + 1;
+            }
+            match record {
+                Err(e) => return Some(Err(e)),
+                Ok(Record::Information(i)) => {
+                    self.seen_information = true;
+                    let table = self.take_table();
+                    self.table_filter = self.filter.for_table(&i);
+                    let projected = self.table_filter.project_information(i);
+                    // Tables with none of the selected columns are skipped
+                    self.information_record = (!projected.column_headers.is_empty()).then(|| projected);
+                    self.emitted = false;
+                    if table.is_some() {
+                        return table
+                    }
+                },
+                Ok(Record::Data(_)) if !self.seen_information => {
+                    return Some(Err(Error::DataBeforeInformation { line }))
+                },
+                Ok(Record::Data(d)) => {
+                    if self.information_record.is_some() && self.table_filter.matches(&d) {
+                        self.data_records.push(self.table_filter.project(d));
+                        if self.data_records.len() >= self.batch_size {
+                            return self.take_table()
+                        }
+                    }
+                },
+                Ok(Record::Comment(c)) => {
+                    match c {
+                        CommentRecord::EOR(eor) => {
+                            self.end_of_report = Some(eor.count_of_records);
+                            if let Err(e) = self.check_trailer() {
+                                return Some(Err(e))
+                            }
+                        },
+                        CommentRecord::EMMS(header) => self.lineage.header = Some(header),
+                        CommentRecord::BUT(_) => (),
+                    }
+                    let table = self.take_table();
+                    self.information_record = None;
+                    if table.is_some() {
+                        return table
+                    }
+                },
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct FlatFileTableIter<'a> {
     flatfile: &'a FlatFile,
@@ -66,56 +418,245 @@ pub struct FlatFileTableIter<'a> {
 impl<'a> Iterator for FlatFileTableIter<'a> {
     type Item = FlatFileTable;
 
+    /// Every `InformationRecord` starts a table, which runs over the data
+    /// records up to the next information or comment record
     fn next(&mut self) -> Option<Self::Item> {
-        let (idx, table) = FlatFileTable::from_flatfile_at(self.flatfile, self.record_idx);
-        self.record_idx = self.record_idx + idx;
-        table
+        let records = self.flatfile.records();
+        let (start, information_record) = records.iter()
+            .enumerate()
+            .skip(self.record_idx)
+            .find_map(|(i, r)| match r {
+                Record::Information(info) => Some((i, info)),
+                _ => None
+            })?;
+        let mut data_records = Vec::new();
+        self.record_idx = start + 1;
+        while let Some(Record::Data(d)) = records.get(self.record_idx) {
+            data_records.push(d.clone());
+            self.record_idx = self.record_idx + 1;
+        }
+        let lineage = Lineage { source_file: None, header: self.flatfile.header().cloned() };
+        Some(FlatFileTable { information_record: information_record.clone(), data_records, bad_records: Vec::new(), lineage, projection: None })
+    }
+}
+
+/// Where a table was read from, for tracing rows back to their nemweb file
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lineage {
+    pub source_file: Option<String>,
+    pub header: Option<CommentRecordEMMS>,
+}
+
+/// Names of the per-row lineage columns added by
+/// [`FlatFileTable::with_lineage_columns`]
+pub const LINEAGE_COLUMNS: [&str; 3] = ["_SOURCE_FILE", "_PUBLISH_DATETIME", "_REPORT_VERSION"];
+
+impl Lineage {
+    /// Key-value pairs for the metadata of an output file
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let mut metadata = Vec::new();
+        if let Some(source_file) = &self.source_file {
+            metadata.push(("nem_mms.source_file".to_string(), source_file.clone()));
+        }
+        if let Some(header) = &self.header {
+            metadata.push(("nem_mms.system".to_string(), header.system.clone()));
+            metadata.push(("nem_mms.report_id".to_string(), header.report_id.as_str().to_string()));
+            metadata.push(("nem_mms.from".to_string(), header.from.clone()));
+            metadata.push(("nem_mms.to".to_string(), header.to.clone()));
+            metadata.push(("nem_mms.publish_datetime".to_string(), format_datetime(&header.publish_datetime())));
+            metadata.push(("nem_mms.specific_payload_information".to_string(), header.specific_payload_information.join(",")));
+        }
+        metadata
+    }
+
+    /// Key-value pairs for the metadata of an output file written from
+    /// every one of `sources`
+    ///
+    /// With more than one source the header fields, which only describe a
+    /// single file, are left out and the source files are listed one per
+    /// line under `nem_mms.source_files`.
+    pub fn sources_metadata(sources: &[Lineage]) -> Vec<(String, String)> {
+        match sources {
+            [lineage] => lineage.metadata(),
+            _ => {
+                let mut files = Vec::<&str>::new();
+                for file in sources.iter().filter_map(|l| l.source_file.as_deref()) {
+                    if !files.contains(&file) {
+                        files.push(file);
+                    }
+                }
+                match files.is_empty() {
+                    true => Vec::new(),
+                    false => vec![("nem_mms.source_files".to_string(), files.join("\n"))],
+                }
+            },
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct FlatFileTable {
     information_record: InformationRecord,
-    data_records: Vec<DataRecord>
+    data_records: Vec<DataRecord>,
+    bad_records: Vec<BadRecord>,
+    lineage: Lineage,
+    /// Columns the table was read with, package tables are converted with
+    /// only these columns of their schema
+    projection: Option<Vec<String>>,
 }
 
 impl FlatFileTable {
-    fn from_flatfile_at(flatfile: &FlatFile, at: usize) -> (usize, Option<FlatFileTable>) {
-        let mut information_record: Option<InformationRecord> = None;
-        let mut data_records: Vec<DataRecord> = Vec::new();
-        let mut idx = 0;
-        for record in flatfile.records().iter().skip(at) {
-            idx = idx + 1;
-            match information_record {
-                Some(ref i) => match record {
-                    Record::Information(_) | Record::Comment(_) => { return (idx, Some(FlatFileTable { information_record: i.clone(), data_records })) },
-                    Record::Data(d) => { data_records.push(d.clone()); },
-                },
-                None => match record {
-                    Record::Information(i) => {
-                        information_record = Some(i.clone());
-                    },
-                    Record::Data(_) | Record::Comment(_) => (),
-                }
+    pub fn len(&self) -> usize {
+        self.data_records.len()
+    }
+
+    pub fn information_record(&self) -> &InformationRecord {
+        &self.information_record
+    }
+
+    pub fn data_records(&self) -> &[DataRecord] {
+        &self.data_records
+    }
+
+    /// Data records that couldn't be read at all, only kept with
+    /// [`ReadOptions::keep_bad_records`]
+    pub fn bad_records(&self) -> &[BadRecord] {
+        &self.bad_records
+    }
+
+    pub fn lineage(&self) -> &Lineage {
+        &self.lineage
+    }
+
+    /// Appends the [`LINEAGE_COLUMNS`] to a batch converted from this table
+    pub fn with_lineage_columns(&self, batch: &RecordBatch) -> Result<RecordBatch, Error> {
+        let rows = batch.num_rows();
+        let mut source_file = StringBuilder::new(rows);
+        let mut publish_datetime = PrimitiveBuilder::<TimestampSecondType>::new(rows);
+        let mut report_version = PrimitiveBuilder::<Int32Type>::new(rows);
+        for _ in 0..rows {
+            let appended = match &self.lineage.source_file {
+                Some(s) => source_file.append_value(s),
+                None => source_file.append_null(),
             };
+            appended.map_err(Error::Arrow)?;
+            let appended = match &self.lineage.header {
+                Some(h) => publish_datetime.append_value(h.publish_datetime().timestamp()),
+                None => publish_datetime.append_null(),
+            };
+            appended.map_err(Error::Arrow)?;
+            report_version.append_value(self.information_record.report_version as i32)
+                .map_err(Error::Arrow)?;
         }
-        (idx, None)
+        let [source_file_name, publish_datetime_name, report_version_name] = LINEAGE_COLUMNS;
+        let mut fields = batch.schema().fields().clone();
+        fields.push(Field::new(source_file_name, DataType::Utf8, true));
+        fields.push(Field::new(publish_datetime_name, DataType::Timestamp(TimeUnit::Second, None), true));
+        fields.push(Field::new(report_version_name, DataType::Int32, false));
+        let mut columns = batch.columns().to_vec();
+        columns.push(Arc::new(source_file.finish()));
+        columns.push(Arc::new(publish_datetime.finish()));
+        columns.push(Arc::new(report_version.finish()));
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .map_err(Error::Arrow)
     }
 
-    pub fn len(&self) -> usize {
-        self.data_records.len()
+    /// Converts a batch of a package back into a table at the package's
+    /// latest report version, the inverse of [`FlatFileTable::to_arrow`]
+    pub fn from_arrow(package: Package, batch: &RecordBatch) -> Result<Self, Error> {
+        let (report_type, report_subtype) = package.report_type();
+        let report_version = REGISTRY.versions(package)
+            .last()
+            .map(|v| v.version)
+            .unwrap_or(1);
+        let column_headers = batch.schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        let information_record = InformationRecord {
+            report_type: report_type.to_string(),
+            report_subtype: report_subtype.to_string(),
+            report_version,
+            column_headers,
+        };
+        let columns = batch.columns()
+            .iter()
+            .map(format_array)
+            .collect::<Result<Vec<_>, _>>()?;
+        let data_records = (0..batch.num_rows())
+            .map(|row| DataRecord {
+                report_type: report_type.to_string(),
+                report_subtype: report_subtype.to_string(),
+                report_version,
+                data: columns.iter().map(|c| c[row].as_str()).collect(),
+            })
+            .collect();
+        Ok(FlatFileTable { information_record, data_records, bad_records: Vec::new(), lineage: Lineage::default(), projection: None })
     }
 
+    /// Converts the table to arrow using the schema registered for its
+    /// report version
+    ///
+    /// The batch always has the package's current schema, columns that the
+    /// table's version predates are filled with nulls.
     pub fn to_arrow(&self) -> Result<(Package, RecordBatch), Error> {
+        self.to_package_arrow(false)
+    }
+
+    /// Converts the table with its package's [`Package::decimal_schema`], so
+    /// prices and quantities are exact decimals rather than floats
+    pub fn to_arrow_decimal(&self) -> Result<(Package, RecordBatch), Error> {
+        self.to_package_arrow(true)
+    }
+
+    fn to_package_arrow(&self, decimals: bool) -> Result<(Package, RecordBatch), Error> {
         let column_headers = self.information_record.column_header_indices();
-        let package = Package::from_information_record(&self.information_record)
-            .ok_or(Error::UnrecognizedPackage { 
+        let registered = REGISTRY.lookup(
+            &self.information_record.report_type,
+            &self.information_record.report_subtype,
+            self.information_record.report_version
+        )?;
+        let schema = if decimals {
+            registered.package.decimal_schema()
+        } else {
+            registered.package.schema()
+        };
+        let schema = match &self.projection {
+            Some(projection) => Schema::new(
+                schema.fields()
+                    .iter()
+                    .filter(|f| projection.contains(f.name()))
+                    .cloned()
+                    .collect()
+            ),
+            None => schema.clone(),
+        };
+        // A batch needs at least one column
+        if schema.fields().is_empty() {
+            return Err(Error::EmptyProjection {
                 report_type: self.information_record.report_type.clone(),
-                report_subtype: self.information_record.report_subtype.clone() 
-            })?;
-        let schema = package.schema();
+                report_subtype: self.information_record.report_subtype.clone(),
+            })
+        }
+        // Read with another version's schema, see `SchemaRegistry::lookup`
+        let exact = registered.version == self.information_record.report_version;
         let mut columns = Vec::new();
         for field in schema.fields() {
+            let predates = !registered.has_column(field.name())
+                || (!exact && !column_headers.contains_key(field.name()));
+            if predates {
+                if !field.is_nullable() {
+                    return Err(Error::MissingRequiredColumn {
+                        column: field.name().clone(),
+                        report_type: self.information_record.report_type.clone(),
+                        report_subtype: self.information_record.report_subtype.clone(),
+                        version: self.information_record.report_version,
+                    })
+                }
+                columns.push(new_null_array(field.data_type(), self.len()));
+                continue
+            }
             column_headers.get(field.name())
                 .ok_or(Error::MissingColumnHeader(field.name().clone()))
                 .and_then(|idx| self.get_array_ref(*idx, field.data_type(), field.is_nullable()))
@@ -125,159 +666,603 @@ impl FlatFileTable {
                 })?;
         }
         let record_batch = RecordBatch::try_new(
-            Arc::new(schema.clone()),
+            Arc::new(schema),
             columns
         ).map_err(Error::Arrow)?;
-        Ok((package, record_batch))
+        Ok((registered.package, record_batch))
     }
 
-    pub fn get_array_ref(&self, idx: usize, datatype: &DataType, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        use DataType::*;
-        match datatype {
-            Float64 => self.get_float64_array(idx, allow_nulls),
-            Timestamp(TimeUnit::Second, None) => self.get_timestampsecond_array(idx, allow_nulls),
-            Utf8 => self.get_string_array(idx, allow_nulls),
-            Boolean => self.get_boolean_array(idx, allow_nulls),
-            Int16 => self.get_int16_array(idx, allow_nulls),
-            Int8 => self.get_int8_array(idx, allow_nulls),
-            _ => Err(Error::UnsupportedDataType(datatype.clone()))
-        }
+    /// Name used for the output of a table without a package,
+    /// `[REPORT TYPE]_[SUBTYPE]`
+    pub fn inferred_name(&self) -> String {
+        format!("{}_{}", self.information_record.report_type, self.information_record.report_subtype)
+    }
+
+    /// Infers a schema from the column headers and the values seen in the
+    /// table, for reports that don't have a curated package schema
+    ///
+    /// Every column is nullable. Integers are read as `Int64`, other numbers
+    /// as `Float64` and dates as timestamps. Anything else, including
+    /// identifiers with leading zeros like `0001`, is `Utf8`. Columns mixing
+    /// kinds are widened as in [`widen_schema`].
+    pub fn infer_schema(&self) -> Schema {
+        let fields = self.information_record.column_headers.iter()
+            .enumerate()
+            .map(|(idx, name)| Field::new(name, self.infer_datatype(idx), true))
+            .collect();
+        Schema::new(fields)
     }
 
-    fn get_string_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = StringBuilder::new(len);
+    fn infer_datatype(&self, idx: usize) -> DataType {
+        let mut inferred: Option<DataType> = None;
         for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_string()
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Float64, value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
-                },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
+            let datatype = match record.get(idx) {
+                Ok(Some(s)) => infer_value_datatype(s),
+                Ok(None) | Err(_) => continue,
+            };
+            let widened = match inferred {
+                Some(dt) => widen_datatype(&dt, &datatype),
+                None => datatype,
+            };
+            if widened == DataType::Utf8 {
+                return DataType::Utf8
             }
+            inferred = Some(widened);
         }
-        Ok(Arc::new(arr_builder.finish()))
+        inferred.unwrap_or(DataType::Utf8)
+    }
+
+    /// Converts the table to arrow with the given schema, matching fields to
+    /// columns by name
+    pub fn to_arrow_with_schema(&self, schema: SchemaRef) -> Result<RecordBatch, Error> {
+        let column_headers = self.information_record.column_header_indices();
+        let columns = schema.fields()
+            .iter()
+            .map(|field| column_headers.get(field.name())
+                .ok_or(Error::MissingColumnHeader(field.name().clone()))
+                .and_then(|idx| self.get_array_ref(*idx, field.data_type(), field.is_nullable())))
+            .collect::<Result<Vec<_>, _>>()?;
+        RecordBatch::try_new(schema, columns)
+            .map_err(Error::Arrow)
+    }
+
+    /// Builds the column at `idx` by parsing each cell as `datatype`
+    ///
+    /// Every cell is parsed straight from its source text, so `Utf8` columns
+    /// keep exactly what was in the flat file.
+    pub fn get_array_ref(&self, idx: usize, datatype: &DataType, allow_nulls: bool) -> Result<ArrayRef, Error> {
+        array_ref(&self.data_records, idx, datatype, allow_nulls)
+    }
+
+    /// Package of the table's report type, subtype and version
+    pub fn package(&self) -> Result<Package, Error> {
+        REGISTRY.lookup(
+            &self.information_record.report_type,
+            &self.information_record.report_subtype,
+            self.information_record.report_version
+        ).map(|r| r.package)
     }
 
-    fn get_float64_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = PrimitiveBuilder::<Float64Type>::new(len);
+    /// Splits out the cells that can't be converted to their column of
+    /// `schema`, returning a table of the records that are left and the
+    /// rejected cells of each record that had any
+    ///
+    /// Cells are only checked one by one in columns that fail to convert as a
+    /// whole. Depending on `mode` a record with a bad cell is dropped, or the
+    /// cell is blanked if its column is nullable.
+    pub fn reject_bad_records(&self, schema: &Schema, mode: RejectMode) -> (FlatFileTable, Vec<Vec<Reject>>) {
+        let column_headers = self.information_record.column_header_indices();
+        let columns = schema.fields()
+            .iter()
+            .filter_map(|f| column_headers.get(f.name()).map(|idx| (f, *idx)))
+            .filter(|(f, idx)| self.get_array_ref(*idx, f.data_type(), f.is_nullable()).is_err())
+            .collect::<Vec<_>>();
+        let mut rejects = Vec::new();
+        let mut data_records = Vec::with_capacity(self.data_records.len());
         for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_f64()
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Float64, value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
-                },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
+            let mut keep = true;
+            let mut blanks = Vec::new();
+            let mut record_rejects = Vec::new();
+            for (field, idx) in columns.iter() {
+                let error = match array_ref(std::slice::from_ref(record), *idx, field.data_type(), field.is_nullable()) {
+                    Ok(_) => continue,
+                    Err(e) => e,
+                };
+                record_rejects.push(Reject {
+                    source_file: self.lineage.source_file.clone(),
+                    line: record.line(),
+                    report_type: self.information_record.report_type.clone(),
+                    report_subtype: self.information_record.report_subtype.clone(),
+                    column: field.name().clone(),
+                    value: record.get(*idx).ok().flatten().unwrap_or_default().to_string(),
+                    error: error.to_string(),
+                });
+                match mode {
+                    RejectMode::Null if field.is_nullable() && record.get(*idx).is_ok() => blanks.push(*idx),
+                    _ => keep = false,
+                }
+            }
+            if !record_rejects.is_empty() {
+                rejects.push(record_rejects);
+            }
+            if keep {
+                data_records.push(record.with_blanks(&blanks));
             }
         }
-        Ok(Arc::new(arr_builder.finish()))
+        let table = FlatFileTable {
+            information_record: self.information_record.clone(),
+            data_records,
+            bad_records: self.bad_records.clone(),
+            lineage: self.lineage.clone(),
+            projection: self.projection.clone(),
+        };
+        (table, rejects)
     }
 
-    fn get_int16_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = PrimitiveBuilder::<Int16Type>::new(len);
-        for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_i16()
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Int16, value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
-                },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
-            }
+    /// Rejects for the table's [`FlatFileTable::bad_records`], with the
+    /// whole record as the value
+    pub fn reject_unreadable_records(&self) -> Vec<Reject> {
+        self.bad_records.iter()
+            .map(|bad| Reject {
+                source_file: self.lineage.source_file.clone(),
+                line: bad.line(),
+                report_type: self.information_record.report_type.clone(),
+                report_subtype: self.information_record.report_subtype.clone(),
+                column: String::new(),
+                value: bad.record.iter().collect::<Vec<_>>().join(","),
+                error: bad.error.clone(),
+            })
+            .collect()
+    }
+}
+
+/// A data record that couldn't be read, such as one with a bad version field
+/// or too few fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadRecord {
+    pub record: csv::StringRecord,
+    pub error: String,
+}
+
+impl BadRecord {
+    /// The data record an error from [`Record::from_csv_record`] was about,
+    /// or the error back if it wasn't about a data record
+    pub fn from_error(error: Error) -> Result<Self, Error> {
+        // The errors' own messages underline the record for a terminal
+        let (record, message) = match &error {
+            Error::PayloadMissingEntry(d) => (&d.record, format!("Missing field {}", d.idx)),
+            Error::ParseIntError(d) => {
+                let value = d.record.get(d.idx).unwrap_or_default();
+                (&d.record, format!("Can't parse field {} ({}): {}", d.idx, value, d.error))
+            },
+            _ => return Err(error),
+        };
+        if record.get(0) != Some("D") {
+            return Err(error)
         }
-        Ok(Arc::new(arr_builder.finish()))
+        Ok(BadRecord { record: record.clone(), error: message })
     }
 
-    fn get_int8_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = PrimitiveBuilder::<Int8Type>::new(len);
-        for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_i8()
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Int8, value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
-                },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
-            }
+    /// Line of the record in the file it was read from
+    pub fn line(&self) -> Option<u64> {
+        self.record.position().map(|p| p.line())
+    }
+}
+
+fn infer_value_datatype(s: &str) -> DataType {
+    let digits = s.trim_start_matches(|c| c == '-' || c == '+');
+    match DataValue::from_str(s) {
+        // Identifiers like "0001" would lose their leading zeros as numbers
+        Some(DataValue::Integer(_)) if digits.len() > 1 && digits.starts_with('0') => DataType::Utf8,
+        Some(DataValue::Integer(_)) => DataType::Int64,
+        Some(DataValue::Float(_)) => DataType::Float64,
+        Some(DataValue::Date(_)) | Some(DataValue::DateTime(_)) => DataType::Timestamp(TimeUnit::Second, None),
+        _ => DataType::Utf8,
+    }
+}
+
+/// The narrowest inferred type that holds values of both `a` and `b`
+///
+/// Integers and floats widen to `Float64`, any other mix to `Utf8`.
+pub fn widen_datatype(a: &DataType, b: &DataType) -> DataType {
+    match (a, b) {
+        (a, b) if a == b => a.clone(),
+        (DataType::Int64, DataType::Float64) | (DataType::Float64, DataType::Int64) => DataType::Float64,
+        _ => DataType::Utf8,
+    }
+}
+
+/// Widens the fields of an inferred `schema` so they also hold the values of
+/// `other`, see [`widen_datatype`]
+///
+/// Fields only in `other` aren't added, tables are converted with the
+/// columns of the first one inferred.
+pub fn widen_schema(schema: &Schema, other: &Schema) -> Schema {
+    let fields = schema.fields()
+        .iter()
+        .map(|field| match other.field_with_name(field.name()) {
+            Ok(o) => Field::new(field.name(), widen_datatype(field.data_type(), o.data_type()), true),
+            Err(_) => field.clone(),
+        })
+        .collect();
+    Schema::new(fields)
+}
+
+fn array_ref(records: &[DataRecord], idx: usize, datatype: &DataType, allow_nulls: bool) -> Result<ArrayRef, Error> {
+    use DataType::*;
+    match datatype {
+        Float64 => get_primitive_array::<Float64Type, _>(records, idx, datatype, allow_nulls, |s| s.parse().ok()),
+        Float32 => get_primitive_array::<Float32Type, _>(records, idx, datatype, allow_nulls, |s| s.parse().ok()),
+        Timestamp(TimeUnit::Second, None) => get_primitive_array::<TimestampSecondType, _>(records, idx, datatype, allow_nulls, |s| parse_datetime(s).map(|dt| dt.timestamp())),
+        Timestamp(TimeUnit::Millisecond, None) => get_primitive_array::<TimestampMillisecondType, _>(records, idx, datatype, allow_nulls, |s| parse_datetime(s).and_then(|dt| dt.timestamp().checked_mul(1_000))),
+        Timestamp(TimeUnit::Microsecond, None) => get_primitive_array::<TimestampMicrosecondType, _>(records, idx, datatype, allow_nulls, |s| parse_datetime(s).and_then(|dt| dt.timestamp().checked_mul(1_000_000))),
+        Timestamp(TimeUnit::Nanosecond, None) => get_primitive_array::<TimestampNanosecondType, _>(records, idx, datatype, allow_nulls, |s| parse_datetime(s).and_then(|dt| dt.timestamp().checked_mul(1_000_000_000))),
+        Date32 => get_primitive_array::<Date32Type, _>(records, idx, datatype, allow_nulls, |s| parse_date(s).and_then(|days| i32::try_from(days).ok())),
+        Date64 => get_primitive_array::<Date64Type, _>(records, idx, datatype, allow_nulls, |s| parse_date(s).and_then(|days| days.checked_mul(86_400_000))),
+        Time32(TimeUnit::Second) => get_primitive_array::<Time32SecondType, _>(records, idx, datatype, allow_nulls, |s| parse_time(s).map(|secs| secs as i32)),
+        Time32(TimeUnit::Millisecond) => get_primitive_array::<Time32MillisecondType, _>(records, idx, datatype, allow_nulls, |s| parse_time(s).map(|secs| secs as i32 * 1_000)),
+        Time64(TimeUnit::Microsecond) => get_primitive_array::<Time64MicrosecondType, _>(records, idx, datatype, allow_nulls, |s| parse_time(s).map(|secs| secs as i64 * 1_000_000)),
+        Time64(TimeUnit::Nanosecond) => get_primitive_array::<Time64NanosecondType, _>(records, idx, datatype, allow_nulls, |s| parse_time(s).map(|secs| secs as i64 * 1_000_000_000)),
+        Utf8 => get_string_array::<i32>(records, idx, allow_nulls),
+        LargeUtf8 => get_string_array::<i64>(records, idx, allow_nulls),
+        Boolean => get_boolean_array(records, idx, allow_nulls),
+        Int64 => get_primitive_array::<Int64Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        Int32 => get_primitive_array::<Int32Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        Int16 => get_primitive_array::<Int16Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        Int8 => get_primitive_array::<Int8Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        UInt64 => get_primitive_array::<UInt64Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        UInt32 => get_primitive_array::<UInt32Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        UInt16 => get_primitive_array::<UInt16Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        UInt8 => get_primitive_array::<UInt8Type, _>(records, idx, datatype, allow_nulls, parse_integer),
+        Decimal(precision, scale) => get_decimal_array(records, idx, *precision, *scale, allow_nulls),
+        _ => Err(Error::UnsupportedDataType(datatype.clone()))
+    }
+}
+
+fn get_primitive_array<T, F>(records: &[DataRecord], idx: usize, datatype: &DataType, allow_nulls: bool, parse: F) -> Result<ArrayRef, Error>
+where
+    T: ArrowPrimitiveType,
+    F: Fn(&str) -> Option<T::Native>
+{
+    let len = records.len();
+    let mut arr_builder = PrimitiveBuilder::<T>::new(len);
+    for record in records {
+        match record.get(idx)? {
+            Some(v) => {
+                let val = parse(v)
+                    .ok_or(Error::DatatypeMismatch { datatype: datatype.clone(), value: v.to_string() })?;
+                arr_builder.append_value(val)
+                    .map_err(Error::Arrow)?;
+            },
+            None if allow_nulls => arr_builder.append_null()
+                .map_err(Error::Arrow)?,
+            None => return Err(Error::NullError)
         }
-        Ok(Arc::new(arr_builder.finish()))
     }
+    Ok(Arc::new(arr_builder.finish()))
+}
 
-    fn get_boolean_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = BooleanBuilder::new(len);
-        for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_bool()
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Boolean, value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
-                },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
-            }
+fn get_string_array<O: StringOffsetSizeTrait>(records: &[DataRecord], idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
+    let len = records.len();
+    let mut arr_builder = GenericStringBuilder::<O>::new(len);
+    for record in records {
+        match record.get(idx)? {
+            Some(v) => arr_builder.append_value(v)
+                .map_err(Error::Arrow)?,
+            None if allow_nulls => arr_builder.append_null()
+                .map_err(Error::Arrow)?,
+            None => return Err(Error::NullError)
         }
-        Ok(Arc::new(arr_builder.finish()))
     }
+    Ok(Arc::new(arr_builder.finish()))
+}
 
-    fn get_timestampsecond_array(&self, idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
-        let len = self.len();
-        let mut arr_builder = PrimitiveBuilder::<TimestampSecondType>::new(len);
-        for record in &self.data_records {
-            let value = record.data.get(idx)
-                .ok_or(Error::IndexError(idx))?;
-            match value {
-                Some(v) => {
-                    let val = v.clone()
-                        .as_datetime()
-                        .map(|v| v.timestamp())
-                        .ok_or(Error::DatatypeMismatch { datatype: DataType::Timestamp(TimeUnit::Second, None), value: value.clone() })?;
-                    arr_builder.append_value(val)
-                        .map_err(Error::Arrow)?;
+fn get_decimal_array(records: &[DataRecord], idx: usize, precision: usize, scale: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
+    let len = records.len();
+    let mut arr_builder = DecimalBuilder::new(len, precision, scale);
+    for record in records {
+        match record.get(idx)? {
+            Some(v) => {
+                let val = parse_decimal(v, precision, scale)
+                    .ok_or_else(|| Error::DatatypeMismatch { datatype: DataType::Decimal(precision, scale), value: v.to_string() })?;
+                arr_builder.append_value(val)
+                    .map_err(Error::Arrow)?;
+            },
+            None if allow_nulls => arr_builder.append_null()
+                .map_err(Error::Arrow)?,
+            None => return Err(Error::NullError)
+        }
+    }
+    Ok(Arc::new(arr_builder.finish()))
+}
+
+fn get_boolean_array(records: &[DataRecord], idx: usize, allow_nulls: bool) -> Result<ArrayRef, Error> {
+    let len = records.len();
+    let mut arr_builder = BooleanBuilder::new(len);
+    for record in records {
+        match record.get(idx)? {
+            Some(v) => {
+                let val = v.parse::<i64>()
+                    .map(|i| i == 1)
+                    .map_err(|_| Error::DatatypeMismatch { datatype: DataType::Boolean, value: v.to_string() })?;
+                arr_builder.append_value(val)
+                    .map_err(Error::Arrow)?;
+            },
+            None if allow_nulls => arr_builder.append_null()
+                .map_err(Error::Arrow)?,
+            None => return Err(Error::NullError)
+        }
+    }
+    Ok(Arc::new(arr_builder.finish()))
+}
+
+/// Formats each value of an array as it's written in a flat file, nulls are
+/// empty
+pub fn format_array(array: &ArrayRef) -> Result<Vec<String>, Error> {
+    (0..array.len())
+        .map(|i| match array.data_type() {
+            _ if array.is_null(i) => Ok(String::new()),
+            DataType::Timestamp(unit, timezone) => {
+                let any = array.as_any();
+                let seconds = match unit {
+                    TimeUnit::Second => any.downcast_ref::<TimestampSecondArray>().map(|a| a.value(i)),
+                    TimeUnit::Millisecond => any.downcast_ref::<TimestampMillisecondArray>().map(|a| a.value(i).div_euclid(1_000)),
+                    TimeUnit::Microsecond => any.downcast_ref::<TimestampMicrosecondArray>().map(|a| a.value(i).div_euclid(1_000_000)),
+                    TimeUnit::Nanosecond => any.downcast_ref::<TimestampNanosecondArray>().map(|a| a.value(i).div_euclid(1_000_000_000)),
+                }.expect("Checked data type");
+                // Timezone aware values are UTC, flat files are in market time
+                let offset = if timezone.is_some() { NEM_UTC_OFFSET } else { 0 };
+                Ok(format_datetime(&NaiveDateTime::from_timestamp(seconds + offset, 0)))
+            },
+            DataType::Boolean => {
+                let arr = array.as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .expect("Checked data type");
+                Ok(if arr.value(i) { "1" } else { "0" }.to_string())
+            },
+            DataType::Decimal(_, scale) => {
+                let arr = array.as_any()
+                    .downcast_ref::<DecimalArray>()
+                    .expect("Checked data type");
+                Ok(format_decimal(arr.value(i), *scale))
+            },
+            DataType::Date32 => {
+                let arr = array.as_any()
+                    .downcast_ref::<Date32Array>()
+                    .expect("Checked data type");
+                Ok(format_datetime(&NaiveDateTime::from_timestamp(arr.value(i) as i64 * 86_400, 0)))
+            },
+            DataType::Date64 => {
+                let arr = array.as_any()
+                    .downcast_ref::<Date64Array>()
+                    .expect("Checked data type");
+                Ok(format_datetime(&NaiveDateTime::from_timestamp(arr.value(i).div_euclid(1_000), 0)))
+            },
+            DataType::Time32(TimeUnit::Second) => {
+                let arr = array.as_any()
+                    .downcast_ref::<Time32SecondArray>()
+                    .expect("Checked data type");
+                Ok(NaiveTime::from_num_seconds_from_midnight(arr.value(i) as u32, 0).format("%H:%M:%S").to_string())
+            },
+            _ => array_value_to_string(array, i).map_err(Error::Arrow),
+        })
+        .collect()
+}
+
+/// Parses a number as an unscaled decimal value, without going through a
+/// float
+///
+/// Digits past `scale` have to be zeros since they can't be represented, and
+/// the value has to fit in `precision` digits.
+pub fn parse_decimal(s: &str, precision: usize, scale: usize) -> Option<i128> {
+    let (mantissa, exponent) = match s.find(|c: char| c == 'e' || c == 'E') {
+        Some(i) => (&s[..i], s[i + 1..].parse::<i32>().ok()?),
+        None => (s, 0),
+    };
+    let (negative, mantissa) = match mantissa.as_bytes().first()? {
+        b'-' => (true, &mantissa[1..]),
+        b'+' => (false, &mantissa[1..]),
+        _ => (false, mantissa),
+    };
+    let (int, frac) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+    if int.is_empty() && frac.is_empty() {
+        return None
+    }
+    let mut digits = int.chars().chain(frac.chars()).collect::<Vec<_>>();
+    if !digits.iter().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    // The value is `digits * 10^shift` in units of the scale, worked out in
+    // i64 so extreme exponents like `1e-2147483648` can't overflow
+    let shift = i64::from(exponent)
+        .checked_sub(i64::try_from(frac.len()).ok()?)?
+        .checked_add(i64::try_from(scale).ok()?)?;
+    if shift < 0 {
+        let dropped = usize::try_from(shift.checked_neg()?).unwrap_or(usize::MAX);
+        let keep = digits.len().saturating_sub(dropped);
+        if digits[keep..].iter().any(|c| *c != '0') {
+            return None
+        }
+        digits.truncate(keep);
+    }
+    let mut value: i128 = 0;
+    for c in digits {
+        value = value.checked_mul(10)?.checked_add(c.to_digit(10)? as i128)?;
+    }
+    // Zero stays zero however large the exponent, anything else overflows
+    // once the power of ten does
+    if shift > 0 && value != 0 {
+        let power = u32::try_from(shift).ok()
+            .and_then(|shift| 10i128.checked_pow(shift))?;
+        value = value.checked_mul(power)?;
+    }
+    if value >= 10i128.checked_pow(precision as u32)? {
+        return None
+    }
+    Some(if negative { -value } else { value })
+}
+
+/// Formats an unscaled decimal value the way AEMO writes numbers, without
+/// trailing zeros
+pub fn format_decimal(value: i128, scale: usize) -> String {
+    let digits = format!("{:0>width$}", value.unsigned_abs(), width = scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    let frac = frac.trim_end_matches('0');
+    let sign = if value < 0 { "-" } else { "" };
+    if frac.is_empty() {
+        format!("{}{}", sign, int)
+    } else {
+        format!("{}{}.{}", sign, int, frac)
+    }
+}
+
+/// NEM market time (AEST) as an arrow timezone
+pub const NEM_TIMEZONE: &str = "+10:00";
+
+/// Seconds NEM market time is ahead of UTC, there's no daylight saving
+pub const NEM_UTC_OFFSET: i64 = 10 * 60 * 60;
+
+/// How timestamp columns are written
+///
+/// MMS datetimes are in NEM market time. Arrow takes timezone aware values to
+/// be UTC, so for `MarketTime` and `Utc` the values are shifted back ten hours
+/// and only the timezone used to display them differs. `Naive` keeps the
+/// market time values without a timezone, which most tools read as UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+    Naive,
+    MarketTime,
+    Utc,
+}
+
+impl Default for TimestampMode {
+    fn default() -> Self {
+        TimestampMode::MarketTime
+    }
+}
+
+impl TimestampMode {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "naive" => Some(TimestampMode::Naive),
+            "nem" => Some(TimestampMode::MarketTime),
+            "utc" => Some(TimestampMode::Utc),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimestampMode::Naive => "naive",
+            TimestampMode::MarketTime => "nem",
+            TimestampMode::Utc => "utc",
+        }
+    }
+
+    pub fn timezone(&self) -> Option<&'static str> {
+        match self {
+            TimestampMode::Naive => None,
+            TimestampMode::MarketTime => Some(NEM_TIMEZONE),
+            TimestampMode::Utc => Some("UTC"),
+        }
+    }
+
+    /// Converts the naive market time columns of a batch, whatever their
+    /// time unit
+    pub fn apply(&self, batch: RecordBatch) -> Result<RecordBatch, Error> {
+        let timezone = match self.timezone() {
+            Some(timezone) => timezone.to_string(),
+            None => return Ok(batch),
+        };
+        let schema = batch.schema();
+        let mut fields = Vec::new();
+        let mut columns = Vec::new();
+        for (field, column) in schema.fields().iter().zip(batch.columns()) {
+            match field.data_type() {
+                DataType::Timestamp(unit, None) => {
+                    let (unit, converted) = match unit {
+                        TimeUnit::Second => (TimeUnit::Second, shift_timestamps::<TimestampSecondType>(column, NEM_UTC_OFFSET, &timezone)),
+                        TimeUnit::Millisecond => (TimeUnit::Millisecond, shift_timestamps::<TimestampMillisecondType>(column, NEM_UTC_OFFSET * 1_000, &timezone)),
+                        TimeUnit::Microsecond => (TimeUnit::Microsecond, shift_timestamps::<TimestampMicrosecondType>(column, NEM_UTC_OFFSET * 1_000_000, &timezone)),
+                        TimeUnit::Nanosecond => (TimeUnit::Nanosecond, shift_timestamps::<TimestampNanosecondType>(column, NEM_UTC_OFFSET * 1_000_000_000, &timezone)),
+                    };
+                    let datatype = DataType::Timestamp(unit, Some(timezone.clone()));
+                    fields.push(Field::new(field.name(), datatype, field.is_nullable()));
+                    columns.push(converted);
+                },
+                _ => {
+                    fields.push(field.clone());
+                    columns.push(column.clone());
                 },
-                None if allow_nulls => arr_builder.append_null()
-                    .map_err(Error::Arrow)?,
-                None => return Err(Error::NullError)
             }
         }
-        Ok(Arc::new(arr_builder.finish()))
+        let schema = Schema::new_with_metadata(fields, schema.metadata().clone());
+        RecordBatch::try_new(Arc::new(schema), columns)
+            .map_err(Error::Arrow)
     }
 }
 
+/// Moves naive market time values `offset` (in their own unit) back to UTC
+fn shift_timestamps<T: ArrowTimestampType>(column: &ArrayRef, offset: i64, timezone: &str) -> ArrayRef {
+    let values = column.as_any()
+        .downcast_ref::<PrimitiveArray<T>>()
+        .expect("Checked data type")
+        .iter()
+        .map(|v| v.map(|v| v - offset))
+        .collect();
+    Arc::new(PrimitiveArray::<T>::from_opt_vec(values, Some(timezone.to_string())))
+}
+
+/// Formats a datetime the way MMS flat files do
+pub fn format_datetime(dt: &NaiveDateTime) -> String {
+    dt.format("%Y/%m/%d %H:%M:%S").to_string()
+}
+
+/// Parses an integer, failing rather than wrapping or truncating if it's out
+/// of range or has a fractional part
+///
+/// Integers are sometimes written with a zero fraction, as in `1.0`, so
+/// that's accepted for every integer type. Anything else after the point,
+/// including nothing at all as in `1.`, isn't.
+pub fn parse_integer<T: std::str::FromStr>(s: &str) -> Option<T> {
+    let int = match s.find('.') {
+        Some(i) if s.len() > i + 1 && s[i + 1..].chars().all(|c| c == '0') => &s[..i],
+        Some(_) => return None,
+        None => s,
+    };
+    int.parse().ok()
+}
+
+/// Parses an MMS date as days since the epoch, a datetime is only a date if
+/// it's at midnight
+pub fn parse_date(s: &str) -> Option<i64> {
+    let dt = parse_datetime(s)?;
+    if dt.time() != NaiveTime::from_hms(0, 0, 0) {
+        return None
+    }
+    Some(dt.date().signed_duration_since(NaiveDate::from_ymd(1970, 1, 1)).num_days())
+}
+
+/// Parses an MMS time of day, `HH:MM:SS` or `HH:MM`, as seconds since
+/// midnight
+pub fn parse_time(s: &str) -> Option<u32> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .ok()
+        .map(|t| t.num_seconds_from_midnight())
+}
+
+/// Parses an MMS date or datetime, dates are taken to be at midnight
+pub fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s, "%Y/%m/%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y/%m/%d %H:%M"))
+        .ok()
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y/%m/%d").ok().map(|d| d.and_hms(0, 0, 0)))
+}
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Record {
@@ -320,6 +1305,14 @@ impl Record {
             )
         }
     }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        match self {
+            Record::Comment(c) => c.to_csv_record(),
+            Record::Information(i) => i.to_csv_record(),
+            Record::Data(d) => d.to_csv_record(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -357,6 +1350,14 @@ impl CommentRecord {
             )
         }
     }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        match self {
+            CommentRecord::EMMS(c) => c.to_csv_record(),
+            CommentRecord::BUT(c) => c.to_csv_record(),
+            CommentRecord::EOR(c) => c.to_csv_record(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -373,6 +1374,13 @@ impl BlindUpdateReportId {
             _ => None
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlindUpdateReportId::BlindUpdateSubmission => "BLIND_UPDATE_SUBMISSION",
+            BlindUpdateReportId::BlindUpdateResponse => "BLIND_UPDATE_RESPONSE",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -382,6 +1390,10 @@ impl FileId {
     pub fn from_str(s: &str) -> Self {
         FileId(s.to_string())
     }
+
+    pub fn as_str(&self) -> &'_ str {
+        &self.0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -396,6 +1408,49 @@ pub struct CommentRecordEMMS {
 }
 
 impl CommentRecordEMMS {
+    /// Header for a new flat file, the file specific payload information is
+    /// left empty
+    pub fn new(system: &str, report_id: &str, from: &str, to: &str, published: NaiveDateTime) -> Self {
+        CommentRecordEMMS {
+            system: system.to_string(),
+            report_id: FileId::from_str(report_id),
+            from: from.to_string(),
+            to: to.to_string(),
+            publish_date: published.date(),
+            publish_time: published.time(),
+            specific_payload_information: [String::new(), String::new(), String::new()],
+        }
+    }
+
+    pub fn system(&self) -> &'_ str {
+        &self.system
+    }
+
+    pub fn report_id(&self) -> &'_ str {
+        self.report_id.as_str()
+    }
+
+    pub fn publish_datetime(&self) -> NaiveDateTime {
+        self.publish_date.and_time(self.publish_time)
+    }
+
+    pub fn specific_payload_information(&self) -> &'_ [String; 3] {
+        &self.specific_payload_information
+    }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        let publish_date = self.publish_date.format("%Y/%m/%d").to_string();
+        let publish_time = self.publish_time.format("%H:%M:%S").to_string();
+        let mut record = csv::StringRecord::from(vec![
+            "C", self.system.as_str(), self.report_id.as_str(), self.from.as_str(), self.to.as_str(),
+            publish_date.as_str(), publish_time.as_str()
+        ]);
+        for info in self.specific_payload_information.iter() {
+            record.push_field(info);
+        }
+        record
+    }
+
     pub fn from_csv_record(record: csv::StringRecord) -> Result<Self, Error> {
         let system = record.get(1).map(ToString::to_string)
             .ok_or(Error::PayloadMissingEntry(BadPayloadDetails::new(record.clone()).at_index(1)))?;
@@ -441,6 +1496,16 @@ pub struct CommentRecordBUT {
 }
 
 impl CommentRecordBUT {
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        let publish_date = self.publish_date.format("%Y/%m/%d").to_string();
+        let publish_time = self.publish_time.format("%H:%M:%S").to_string();
+        csv::StringRecord::from(vec![
+            "C", self.system.as_str(), self.report_id.as_str(), self.from.as_str(), self.to.as_str(),
+            publish_date.as_str(), publish_time.as_str(), "", "", "",
+            self.market.as_str(), self.payload_id.as_str(), self.payload_response_id.as_str()
+        ])
+    }
+
     pub fn from_csv_record(record: csv::StringRecord) -> Result<Self, Error> {
         let system = record.get(1).map(ToString::to_string)
             .ok_or(Error::PayloadMissingEntry(BadPayloadDetails::new(record.clone()).at_index(1)))?;
@@ -478,6 +1543,18 @@ pub struct CommentRecordEndOfReport {
 }
 
 impl CommentRecordEndOfReport {
+    pub fn new(count_of_records: u32) -> Self {
+        CommentRecordEndOfReport { count_of_records }
+    }
+
+    pub fn count_of_records(&self) -> u32 {
+        self.count_of_records
+    }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        csv::StringRecord::from(vec!["C".to_string(), "END OF REPORT".to_string(), self.count_of_records.to_string()])
+    }
+
     pub fn from_csv_record(record: csv::StringRecord) -> Result<Self, Error> {
         let count_of_records = record.get(2)
             .ok_or(Error::PayloadMissingEntry(BadPayloadDetails::new(record.clone()).at_index(2)))
@@ -521,14 +1598,24 @@ impl InformationRecord {
             .collect();
         Ok(InformationRecord { report_type, report_subtype, report_version, column_headers })
     }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        let report_version = self.report_version.to_string();
+        let mut record = csv::StringRecord::from(vec!["I", self.report_type.as_str(), self.report_subtype.as_str(), report_version.as_str()]);
+        for header in self.column_headers.iter() {
+            record.push_field(header);
+        }
+        record
+    }
 }
 
+/// A row of data, kept as the source text until a schema says how to parse it
 #[derive(Debug, Clone, PartialEq)]
 pub struct DataRecord {
     pub report_type: String,
     pub report_subtype: String,
     pub report_version: u32,
-    pub data: Vec<Option<DataValue>>
+    pub data: csv::StringRecord
 }
 
 impl DataRecord {
@@ -543,12 +1630,69 @@ impl DataRecord {
             .ok_or(Error::PayloadMissingEntry(BadPayloadDetails::new(record.clone()).at_index(3)))
             .and_then(|s| s.parse()
                       .map_err(|e| Error::ParseIntError(ParseErrorDetails::new(record.clone(), 3, e))))?;
-        let data = record.iter()
+        let mut data: csv::StringRecord = record.iter()
             .skip(4)
-            .map(DataValue::from_str)
             .collect();
+        data.set_position(record.position().cloned());
         Ok(DataRecord { report_type, report_subtype, report_version, data })
     }
+
+    /// Line of the record in the file it was read from
+    pub fn line(&self) -> Option<u64> {
+        self.data.position().map(|p| p.line())
+    }
+
+    /// The cells at `indices`, in that order
+    pub fn select(&self, indices: &[usize]) -> DataRecord {
+        let mut data: csv::StringRecord = indices.iter()
+            .map(|idx| self.data.get(*idx).unwrap_or(""))
+            .collect();
+        data.set_position(self.data.position().cloned());
+        DataRecord {
+            report_type: self.report_type.clone(),
+            report_subtype: self.report_subtype.clone(),
+            report_version: self.report_version,
+            data,
+        }
+    }
+
+    /// Copy of the record with the cells at `blanks` emptied
+    pub fn with_blanks(&self, blanks: &[usize]) -> DataRecord {
+        let mut data: csv::StringRecord = self.data.iter()
+            .enumerate()
+            .map(|(i, v)| if blanks.contains(&i) { "" } else { v })
+            .collect();
+        data.set_position(self.data.position().cloned());
+        DataRecord {
+            report_type: self.report_type.clone(),
+            report_subtype: self.report_subtype.clone(),
+            report_version: self.report_version,
+            data,
+        }
+    }
+
+    pub fn to_csv_record(&self) -> csv::StringRecord {
+        let report_version = self.report_version.to_string();
+        let mut record = csv::StringRecord::from(vec!["D", self.report_type.as_str(), self.report_subtype.as_str(), report_version.as_str()]);
+        for field in self.data.iter() {
+            record.push_field(field);
+        }
+        record
+    }
+
+    /// Source text of the cell at `idx`, empty cells are null
+    pub fn get(&self, idx: usize) -> Result<Option<&str>, Error> {
+        match self.data.get(idx) {
+            Some("") => Ok(None),
+            Some(s) => Ok(Some(s)),
+            None => Err(Error::IndexError(idx))
+        }
+    }
+
+    /// Best guess at the value of the cell at `idx` when there's no schema
+    pub fn value(&self, idx: usize) -> Result<Option<DataValue>, Error> {
+        self.get(idx).map(|s| s.and_then(DataValue::from_str))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -616,7 +1760,7 @@ impl DataValue {
         }
     }
 
-    fn from_str(s: &str) -> Option<Self> {
+    pub fn from_str(s: &str) -> Option<Self> {
         if s == "" {
             return None
         }
@@ -644,6 +1788,8 @@ impl DataValue {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filter::Predicate;
+    use arrow::array::{Array, StringArray, Float64Array, Int32Array, TimestampSecondArray};
 
     #[test]
     fn emms_comment_record() {
@@ -757,17 +1903,431 @@ mod tests {
                 report_type: "BID".to_string(),
                 report_subtype: "BIDDAYOFFER_D".to_string(),
                 report_version: 2,
-                data: vec![Some(DataValue::DateTime(NaiveDate::from_ymd(2021, 3, 31).and_hms(0, 0, 0))),
-                           Some(DataValue::String("DUID1".to_string())),
-                           Some(DataValue::String("ENERGY".to_string())),
-                           Some(DataValue::DateTime(NaiveDate::from_ymd(2021, 3, 31).and_hms(0, 0, 0))),
-                           Some(DataValue::DateTime(NaiveDate::from_ymd(2021, 3, 30).and_hms(12, 19, 0))),
-                           Some(DataValue::Integer(1)),
-                           Some(DataValue::String("PARTICIPANTID1".to_string())),
-                           Some(DataValue::Integer(241)),
-                           Some(DataValue::String("1054 F PB1 & PB10 LOSS FACTOR".to_string()))]
+                data: csv::StringRecord::from(vec!["2021/03/31 00:00", "DUID1", "ENERGY", "2021/03/31 00:00", "2021/03/30 12:19", "1",
+                                                   "PARTICIPANTID1", "241", "1054 F PB1 & PB10 LOSS FACTOR"])
             }
         );
         assert_eq!(parsed, expected);
+        match parsed {
+            Record::Data(d) => {
+                assert_eq!(d.value(0).unwrap(), Some(DataValue::DateTime(NaiveDate::from_ymd(2021, 3, 31).and_hms(0, 0, 0))));
+                assert_eq!(d.value(7).unwrap(), Some(DataValue::Integer(241)));
+                assert_eq!(d.get(8).unwrap(), Some("1054 F PB1 & PB10 LOSS FACTOR"));
+                assert!(d.get(9).is_err());
+            },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn schema_driven_columns() {
+        let table = FlatFileTable {
+            information_record: InformationRecord {
+                report_type: "DISPATCH".to_string(),
+                report_subtype: "UNIT_SCADA".to_string(),
+                report_version: 1,
+                column_headers: vec!["SETTLEMENTDATE".to_string(), "DUID".to_string(), "SCADAVALUE".to_string()]
+            },
+            data_records: vec![
+                DataRecord::from_csv_record(csv::StringRecord::from(vec!["D", "DISPATCH", "UNIT_SCADA", "1", "2021/12/25 10:45:00", "0001", "1e3"])).unwrap(),
+                DataRecord::from_csv_record(csv::StringRecord::from(vec!["D", "DISPATCH", "UNIT_SCADA", "1", "2021/12/25 10:45:00", "2021/01/01", ""])).unwrap(),
+            ],
+            bad_records: Vec::new(),
+            lineage: Lineage::default(),
+            projection: None,
+        };
+        let (package, batch) = table.to_arrow().unwrap();
+        assert_eq!(package, Package::DispatchUnitScada);
+        let duids = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(duids.value(0), "0001");
+        assert_eq!(duids.value(1), "2021/01/01");
+        let scada = batch.column(2).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(scada.value(0), 1000.0);
+        assert!(scada.is_null(1));
+    }
+
+    #[test]
+    fn flatfile_reader_batches() {
+        let csv = "C,NEMP.WORLD,DISPATCHSCADA,AEMO,PUBLIC,2021/12/25,10:40:11,0000000354978413,DISPATCHSCADA,0000000354978411
+I,DISPATCH,UNIT_SCADA,1,SETTLEMENTDATE,DUID,SCADAVALUE
+D,DISPATCH,UNIT_SCADA,1,\"2021/12/25 10:45:00\",BARCSF1,0
+D,DISPATCH,UNIT_SCADA,1,\"2021/12/25 10:45:00\",BUTLERSG,9.9
+D,DISPATCH,UNIT_SCADA,1,\"2021/12/25 10:45:00\",CAPTL_WF,47.68
+C,\"END OF REPORT\",6
+";
+        let rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(csv.as_bytes());
+        let tables = FlatFileReader::new(rdr)
+            .with_batch_size(2)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].len(), 2);
+        assert_eq!(tables[1].len(), 1);
+        assert_eq!(tables[0].information_record, tables[1].information_record);
+        assert_eq!(tables[0].information_record.report_subtype, "UNIT_SCADA");
+    }
+
+    fn sample_reader(sample: &str) -> csv::Reader<&[u8]> {
+        csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(sample.as_bytes())
+    }
+
+    fn read_sample(sample: &str) -> FlatFile {
+        FlatFile::read_csv(sample_reader(sample)).unwrap()
+    }
+
+    fn table_summary<I: Iterator<Item = FlatFileTable>>(tables: I) -> Vec<(String, usize)> {
+        tables.map(|t| (t.information_record.report_subtype.clone(), t.len()))
+            .collect()
+    }
+
+    #[test]
+    fn dispatchis_tables() {
+        let flatfile = read_sample(include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV"));
+        let expected = vec![
+            ("CASE_SOLUTION".to_string(), 1),
+            ("LOCAL_PRICE".to_string(), 3),
+            ("PRICE".to_string(), 5),
+            ("REGIONSUM".to_string(), 2),
+            ("INTERCONNECTORRES".to_string(), 2),
+        ];
+        assert_eq!(table_summary(flatfile.iter()), expected);
+
+        let (package, batch) = flatfile.iter()
+            .nth(2)
+            .unwrap()
+            .to_arrow()
+            .unwrap();
+        assert_eq!(package, Package::DispatchPrice);
+        assert_eq!(batch.num_rows(), 5);
+        // Version 4 predates the 1 second FCAS columns
+        assert_eq!(batch.schema().as_ref(), Package::DispatchPrice.schema());
+        let raise1sec = batch.schema().index_of("RAISE1SECRRP").unwrap();
+        assert_eq!(batch.column(raise1sec).null_count(), 5);
+    }
+
+    #[test]
+    fn predispatchis_tables() {
+        let sample = include_str!("../tests/data/PUBLIC_PREDISPATCHIS_202112251100_20211225103153.CSV");
+        let expected = vec![
+            ("CASE_SOLUTION".to_string(), 1),
+            ("LOCAL_PRICE".to_string(), 1),
+            ("REGION_PRICES".to_string(), 4),
+            ("REGION_SOLUTION".to_string(), 2),
+            ("INTERCONNECTOR_SOLN".to_string(), 1),
+        ];
+        assert_eq!(table_summary(read_sample(sample).iter()), expected);
+
+        let streamed = FlatFileReader::new(sample_reader(sample))
+            .map(Result::unwrap);
+        assert_eq!(table_summary(streamed), expected);
+    }
+
+    #[test]
+    fn truncated_flatfiles() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let truncated = sample.lines()
+            .take(12)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let miscounted = sample.replace("END OF REPORT\",20", "END OF REPORT\",25");
+
+        match FlatFile::read_csv(sample_reader(&truncated)) {
+            Err(Error::MissingEndOfReport) => (),
+            r => panic!("Expected missing END OF REPORT, got {:?}", r)
+        }
+        match FlatFile::read_csv(sample_reader(&miscounted)) {
+            Err(Error::RecordCountMismatch { expected: 25, found: 20 }) => (),
+            r => panic!("Expected mismatched count, got {:?}", r)
+        }
+
+        let streamed = FlatFileReader::new(sample_reader(&truncated))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(matches!(streamed, Err(Error::MissingEndOfReport)));
+        let warnings = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler = {
+            let warnings = warnings.clone();
+            WarningHandler::new(move |e| warnings.lock().unwrap().push(e.to_string()))
+        };
+        let streamed = FlatFileReader::new(sample_reader(&truncated))
+            .lenient(true)
+            .with_warnings(Some(handler))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(table_summary(streamed.into_iter()).len(), 3);
+        assert_eq!(warnings.lock().unwrap().len(), 1);
+
+        // The count is checked before the last table is handed out
+        let streamed = FlatFileReader::new(sample_reader(&miscounted))
+            .collect::<Vec<_>>();
+        assert_eq!(streamed.iter().filter(|t| t.is_ok()).count(), 4);
+        assert!(matches!(streamed.last(), Some(Err(Error::RecordCountMismatch { .. }))));
+
+        let (flatfile, warning) = FlatFile::read_csv_lenient(sample_reader(&truncated)).unwrap();
+        assert!(matches!(warning, Some(Error::MissingEndOfReport)));
+        assert_eq!(flatfile.iter().count(), 3);
+    }
+
+    #[test]
+    fn data_before_information() {
+        let sample = "C,NEMP.WORLD,TEST,AEMO,PUBLIC,2021/12/25,10:40:12,0000000354978420,TEST,0000000354978416
+D,TEST,ORPHAN,1,0001,1
+I,TEST,ORPHAN,1,ID,VALUE
+D,TEST,ORPHAN,1,0002,2
+C,\"END OF REPORT\",5
+";
+        let streamed = FlatFileReader::new(sample_reader(sample))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(matches!(streamed, Err(Error::DataBeforeInformation { line: Some(2) })));
+    }
+
+    #[test]
+    fn infer_unrecognized_schema() {
+        let flatfile = read_sample(include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV"));
+        let table = flatfile.iter().next().unwrap();
+        assert!(matches!(table.to_arrow(), Err(Error::UnrecognizedPackage { .. })));
+        assert_eq!(table.inferred_name(), "DISPATCH_CASE_SOLUTION");
+
+        let schema = table.infer_schema();
+        let datatype = |name: &str| schema.field_with_name(name).unwrap().data_type().clone();
+        assert_eq!(datatype("SETTLEMENTDATE"), DataType::Timestamp(TimeUnit::Second, None));
+        assert_eq!(datatype("RUNNO"), DataType::Int64);
+        assert_eq!(datatype("TOTALOBJECTIVE"), DataType::Float64);
+        assert_eq!(datatype("CASESUBTYPE"), DataType::Utf8);
+        assert!(schema.fields().iter().all(|f| f.is_nullable()));
+
+        let batch = table.to_arrow_with_schema(Arc::new(schema.clone())).unwrap();
+        assert_eq!(batch.num_columns(), 21);
+        assert_eq!(batch.num_rows(), 1);
+
+        assert_eq!(infer_value_datatype("0001"), DataType::Utf8);
+        assert_eq!(infer_value_datatype("-0"), DataType::Int64);
+        assert_eq!(infer_value_datatype("-0.5"), DataType::Float64);
+        let timestamp = DataType::Timestamp(TimeUnit::Second, None);
+        assert_eq!(widen_datatype(&DataType::Int64, &DataType::Float64), DataType::Float64);
+        assert_eq!(widen_datatype(&timestamp, &DataType::Int64), DataType::Utf8);
+        let widened = widen_schema(
+            &Schema::new(vec![Field::new("A", DataType::Int64, true), Field::new("B", timestamp.clone(), true)]),
+            &Schema::new(vec![Field::new("A", DataType::Float64, true), Field::new("C", DataType::Utf8, true)]),
+        );
+        assert_eq!(widened.field(0).data_type(), &DataType::Float64);
+        assert_eq!(widened.field(1).data_type(), &timestamp);
+        assert_eq!(widened.fields().len(), 2);
+    }
+
+    #[test]
+    fn filtered_reader() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let filter = Filter {
+            columns: Some(vec!["SETTLEMENTDATE".to_string(), "REGIONID".to_string(), "RRP".to_string()]),
+            predicates: vec![
+                Predicate::from_str("REGIONID=NSW1,VIC1").unwrap(),
+                Predicate::from_str("RRP>=85").unwrap(),
+            ],
+        };
+        let table = FlatFileReader::new(sample_reader(sample))
+            .with_filter(&filter)
+            .map(Result::unwrap)
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        assert_eq!(table.information_record.column_headers, ["SETTLEMENTDATE", "REGIONID", "RRP"]);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.data_records[0].get(1).unwrap(), Some("NSW1"));
+        assert_eq!(table.data_records[0].line(), Some(9));
+
+        let (_, batch) = table.to_arrow().unwrap();
+        let names = batch.schema().fields().iter().map(|f| f.name().clone()).collect::<Vec<_>>();
+        assert_eq!(names, ["SETTLEMENTDATE", "REGIONID", "RRP"]);
+        assert_eq!(batch.num_rows(), 1);
+
+        // Tables with none of the columns are skipped
+        let filter = Filter { columns: Some(vec!["RRP".to_string()]), predicates: Vec::new() };
+        let tables = FlatFileReader::new(sample_reader(sample))
+            .with_filter(&filter)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(table_summary(tables.into_iter()), vec![("PRICE".to_string(), 5)]);
+    }
+
+    #[test]
+    fn table_lineage() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let table = FlatFileReader::new(sample_reader(sample))
+            .with_source("PUBLIC_DISPATCHIS_202112251045_0000000354978420.zip/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV")
+            .map(Result::unwrap)
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        let metadata = table.lineage().metadata();
+        assert!(metadata.contains(&("nem_mms.report_id".to_string(), "DISPATCHIS".to_string())));
+        assert!(metadata.contains(&("nem_mms.publish_datetime".to_string(), "2021/12/25 10:40:12".to_string())));
+        assert!(metadata.contains(&("nem_mms.specific_payload_information".to_string(), "0000000354978420,DISPATCHIS,0000000354978416".to_string())));
+
+        let (_, batch) = table.to_arrow().unwrap();
+        let batch = table.with_lineage_columns(&batch).unwrap();
+        let columns = batch.num_columns();
+        assert_eq!(columns, Package::DispatchPrice.schema().fields().len() + 3);
+        let source = batch.column(columns - 3).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(source.value(4), "PUBLIC_DISPATCHIS_202112251045_0000000354978420.zip/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let published = batch.column(columns - 2).as_any().downcast_ref::<TimestampSecondArray>().unwrap();
+        assert_eq!(published.value(0), NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 40, 12).timestamp());
+        let version = batch.column(columns - 1).as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(version.value(0), 4);
+
+        let flatfile = read_sample(sample);
+        assert_eq!(flatfile.iter().next().unwrap().lineage().header.as_ref(), flatfile.header());
+    }
+
+    #[test]
+    fn market_time_timestamps() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let table = read_sample(sample).iter()
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        let (_, naive) = table.to_arrow().unwrap();
+        let settlementdate = NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 45, 0);
+
+        let batch = TimestampMode::MarketTime.apply(naive.clone()).unwrap();
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Timestamp(TimeUnit::Second, Some("+10:00".to_string())));
+        let values = batch.column(0).as_any().downcast_ref::<TimestampSecondArray>().unwrap();
+        assert_eq!(values.value(0), settlementdate.timestamp() - 36000);
+        assert_eq!(format_array(batch.column(0)).unwrap()[0], "2021/12/25 10:45:00");
+
+        let batch = TimestampMode::Utc.apply(naive.clone()).unwrap();
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Timestamp(TimeUnit::Second, Some("UTC".to_string())));
+        let values = batch.column(0).as_any().downcast_ref::<TimestampSecondArray>().unwrap();
+        assert_eq!(values.value(0), settlementdate.timestamp() - 36000);
+
+        let batch = TimestampMode::Naive.apply(naive).unwrap();
+        let values = batch.column(0).as_any().downcast_ref::<TimestampSecondArray>().unwrap();
+        assert_eq!(values.value(0), settlementdate.timestamp());
+
+        // Finer units are shifted by the same amount of time
+        let millis = TimestampMillisecondArray::from_opt_vec(vec![Some(settlementdate.timestamp() * 1_000), None], None);
+        let schema = Schema::new(vec![Field::new("SETTLEMENTDATE", DataType::Timestamp(TimeUnit::Millisecond, None), true)]);
+        let naive = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(millis)]).unwrap();
+        let batch = TimestampMode::Utc.apply(naive).unwrap();
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".to_string())));
+        let values = batch.column(0).as_any().downcast_ref::<TimestampMillisecondArray>().unwrap();
+        assert_eq!(values.value(0), (settlementdate.timestamp() - 36000) * 1_000);
+        assert!(values.is_null(1));
+    }
+
+    #[test]
+    fn decimal_values() {
+        assert_eq!(parse_decimal("85.33", 15, 5), Some(8533000));
+        assert_eq!(parse_decimal("-1000", 15, 5), Some(-100000000));
+        assert_eq!(parse_decimal(".5", 3, 1), Some(5));
+        assert_eq!(parse_decimal("1.50000000", 15, 5), Some(150000));
+        assert_eq!(parse_decimal("1.2E-3", 15, 5), Some(120));
+        assert_eq!(parse_decimal("1.234567", 15, 5), None);
+        assert_eq!(parse_decimal("1000", 4, 1), None);
+        assert_eq!(parse_decimal("1.5.0", 15, 5), None);
+        assert_eq!(parse_decimal("-", 15, 5), None);
+        assert_eq!(parse_decimal("1.5e-2147483648", 15, 5), None);
+        assert_eq!(parse_decimal("0e-2147483648", 15, 5), Some(0));
+        assert_eq!(parse_decimal("0e2147483647", 15, 5), Some(0));
+        assert_eq!(parse_decimal("1e2147483647", 15, 5), None);
+        assert_eq!(format_decimal(8533000, 5), "85.33");
+        assert_eq!(format_decimal(-100000000, 5), "-1000");
+        assert_eq!(format_decimal(-5, 2), "-0.05");
+        assert_eq!(format_decimal(0, 5), "0");
+    }
+
+    #[test]
+    fn integer_and_temporal_values() {
+        assert_eq!(parse_integer::<i32>("20211225130"), None);
+        assert_eq!(parse_integer::<i64>("20211225130"), Some(20211225130));
+        assert_eq!(parse_integer::<i8>("1.0"), Some(1));
+        assert_eq!(parse_integer::<i8>("1.5"), None);
+        assert_eq!(parse_integer::<i16>("1."), None);
+        assert_eq!(parse_integer::<u8>("-1"), None);
+        assert_eq!(parse_date("2021/12/25 00:00:00"), Some(18986));
+        assert_eq!(parse_date("2021/12/25"), Some(18986));
+        assert_eq!(parse_date("2021/12/25 10:45:00"), None);
+        assert_eq!(parse_time("10:45:30"), Some(38730));
+
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let table = read_sample(sample).iter()
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("DISPATCHINTERVAL", DataType::Int64, false),
+            Field::new("RUNNO", DataType::UInt8, false),
+            Field::new("SETTLEMENTDATE", DataType::Timestamp(TimeUnit::Millisecond, None), false),
+            Field::new("REGIONID", DataType::LargeUtf8, false),
+            Field::new("RRP", DataType::Float32, false),
+        ]));
+        let batch = table.to_arrow_with_schema(schema).unwrap();
+        let formatted = batch.columns().iter().map(|c| format_array(c).unwrap()[0].clone()).collect::<Vec<_>>();
+        assert_eq!(formatted, ["20211225130", "1", "2021/12/25 10:45:00", "NSW1", "85.33"]);
+
+        let narrow = Arc::new(Schema::new(vec![Field::new("DISPATCHINTERVAL", DataType::Int32, false)]));
+        assert!(matches!(table.to_arrow_with_schema(narrow), Err(Error::DatatypeMismatch { .. })));
+        let date = Arc::new(Schema::new(vec![Field::new("SETTLEMENTDATE", DataType::Date32, false)]));
+        assert!(matches!(table.to_arrow_with_schema(date), Err(Error::DatatypeMismatch { .. })));
+    }
+
+    #[test]
+    fn decimal_prices() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let table = read_sample(sample).iter()
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        let (package, batch) = table.to_arrow_decimal().unwrap();
+        let idx = batch.schema().index_of("RRP").unwrap();
+        assert_eq!(batch.schema().field(idx).data_type(), &DataType::Decimal(15, 5));
+        assert_eq!(batch.schema().field(0), package.schema().field(0));
+        let values = batch.column(idx).as_any().downcast_ref::<DecimalArray>().unwrap();
+        assert_eq!(values.value(0), 8533000);
+        assert_eq!(format_array(batch.column(idx)).unwrap()[..2], ["85.33", "82.1"]);
+    }
+
+    #[test]
+    fn reject_bad_cells() {
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV")
+            .replacen(",85.33,0,85.33,", ",8x.33,0,85.33,", 1);
+        let flatfile = read_sample(&sample);
+        let table = flatfile.iter()
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        assert!(matches!(table.to_arrow(), Err(Error::DatatypeMismatch { .. })));
+        let schema = Package::DispatchPrice.schema();
+
+        let (kept, rejects) = table.reject_bad_records(schema, RejectMode::Skip);
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0][0].line, Some(9));
+        assert_eq!(rejects[0][0].column, "RRP");
+        assert_eq!(rejects[0][0].value, "8x.33");
+        assert_eq!(kept.len(), table.len() - 1);
+        assert_eq!(kept.to_arrow().unwrap().1.num_rows(), table.len() - 1);
+
+        let (kept, rejects) = table.reject_bad_records(schema, RejectMode::Null);
+        assert_eq!(rejects.len(), 1);
+        let (_, batch) = kept.to_arrow().unwrap();
+        assert_eq!(batch.num_rows(), table.len());
+        assert!(batch.column(schema.index_of("RRP").unwrap()).is_null(0));
+        assert_eq!(kept.data_records[0].line(), Some(9));
+
+        // A record that can't be read at all is kept with its table
+        let sample = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV")
+            .replacen("D,DISPATCH,PRICE,4,", "D,DISPATCH,PRICE,x,", 1);
+        assert!(FlatFileReader::new(sample_reader(&sample)).any(|t| t.is_err()));
+        let tables = FlatFileReader::new(sample_reader(&sample))
+            .keep_bad_records(true)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let table = tables.iter()
+            .find(|t| t.information_record.report_subtype == "PRICE")
+            .unwrap();
+        assert_eq!(table.len(), 4);
+        let rejects = table.reject_unreadable_records();
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].line, Some(9));
+        assert!(rejects[0].value.starts_with("D,DISPATCH,PRICE,x,"));
     }
 }
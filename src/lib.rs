@@ -1,5 +1,9 @@
 pub mod flatfile;
+pub mod filter;
 pub mod error;
 pub mod packages;
 pub mod zip;
+pub mod writer;
+pub mod rejects;
+pub mod parallel;
 pub mod manage;
@@ -1,17 +1,15 @@
-use clap::{Arg, App, SubCommand, crate_version};
+use clap::{Arg, App, ArgMatches, ErrorKind, SubCommand, crate_version, value_t};
 use csv::ReaderBuilder;
-use zip::read::ZipArchive;
 use nem_mms::{
-    flatfile::FlatFile,
-    packages,
-    zip::read_zip,
-    error::Error,
+    flatfile::{parse_datetime, FlatFileTable, ReadOptions, TimestampMode, DEFAULT_BATCH_SIZE},
+    packages::{self, codegen, custom::PackageDefinitions, download::{DownloadOptions, DEFAULT_USER_AGENT, MAX_BACKOFF, MIN_RATE_LIMIT}, listing::NemwebListing, ParquetSink},
+    parallel,
+    rejects::{RejectMode, RejectWriter},
+    error::{Error, WarningHandler},
+    filter::{Filter, Predicate},
     manage::state::DepositoryState,
 };
-use std::{
-    fs::OpenOptions,
-    path::Path,
-};
+use std::{path::Path, time::Duration};
 
 
 fn main() {
@@ -24,7 +22,16 @@ fn main() {
     }
 }
 
+/// Environment variable holding the path of a TOML file of custom packages
+const PACKAGES_ENV_VAR: &str = "NEM_MMS_PACKAGES";
+
 fn _main() -> Result<(), Error> {
+    // Registered before the cli is built so custom packages are valid values
+    // of the PACKAGE arguments
+    if let Some(path) = std::env::var_os(PACKAGES_ENV_VAR) {
+        PackageDefinitions::from_path(path)?.register()?;
+    }
+    let batch_size_default = DEFAULT_BATCH_SIZE.to_string();
     let matches = App::new("nem-mms")
         .version(crate_version!())
         .author("mattswoon")
@@ -34,7 +41,64 @@ fn _main() -> Result<(), Error> {
                     .arg(Arg::with_name("PATH")
                          .required(true)
                          .takes_value(true)
-                         .index(1)))
+                         .index(1))
+                    .arg(Arg::with_name("batch-size")
+                         .long("batch-size")
+                         .short("b")
+                         .help("Maximum number of rows held in memory per table while parsing")
+                         .takes_value(true)
+                         .default_value(&batch_size_default))
+                    .arg(Arg::with_name("jobs")
+                         .long("jobs")
+                         .short("j")
+                         .help("Number of files or zip members to parse at once")
+                         .takes_value(true)
+                         .default_value("1"))
+                    .arg(Arg::with_name("columns")
+                         .long("columns")
+                         .help("Comma separated columns to keep, others are dropped as they're read. Tables with none of them are skipped")
+                         .takes_value(true))
+                    .arg(Arg::with_name("where")
+                         .long("where")
+                         .help("Only keep rows where COLUMN=VALUE[,VALUE...], or a comparison such as COLUMN>=VALUE holds. Tables without COLUMN are kept whole")
+                         .takes_value(true)
+                         .number_of_values(1)
+                         .multiple(true))
+                    .arg(Arg::with_name("lenient")
+                         .long("lenient")
+                         .help("Warn about, rather than fail on, files with a missing or mismatched END OF REPORT record"))
+                    .arg(Arg::with_name("packages")
+                         .long("packages")
+                         .help("Register the custom packages declared in this TOML file, such as a depository's config.toml")
+                         .takes_value(true))
+                    .arg(Arg::with_name("infer-schema")
+                         .long("infer-schema")
+                         .help("Infer schemas for unrecognized reports rather than skipping them"))
+                    .arg(Arg::with_name("lineage")
+                         .long("lineage")
+                         .help("Add _SOURCE_FILE, _PUBLISH_DATETIME and _REPORT_VERSION columns to every row"))
+                    .arg(Arg::with_name("dedup")
+                         .long("dedup")
+                         .help("Only keep the latest row for each primary key, by LASTCHANGED then file publish time. Every key read is held in memory, and --columns must keep the primary key"))
+                    .arg(Arg::with_name("decimals")
+                         .long("decimals")
+                         .help("Write prices and quantities as decimals at their MMS Data Model precision rather than floats"))
+                    .arg(Arg::with_name("timestamps")
+                         .long("timestamps")
+                         .help("Write timestamps in NEM market time (+10:00), as UTC, or naive without a timezone")
+                         .takes_value(true)
+                         .possible_values(&["nem", "utc", "naive"])
+                         .default_value(TimestampMode::default().as_str()))
+                    .arg(Arg::with_name("rejects")
+                         .long("rejects")
+                         .help("Write rows that can't be read or converted to this new csv (or .json) file rather than failing")
+                         .takes_value(true))
+                    .arg(Arg::with_name("reject-mode")
+                         .long("reject-mode")
+                         .help("Skip rejected rows, or null their bad values where the column is nullable")
+                         .takes_value(true)
+                         .possible_values(&["skip", "null"])
+                         .default_value(RejectMode::default().as_str())))
         .subcommand(SubCommand::with_name("fetch")
                     .about("Fetch MMS files from Nemweb")
                     .arg(Arg::with_name("PACKAGE")
@@ -64,7 +128,46 @@ fn _main() -> Result<(), Error> {
                          .help("Month to get historic data for, only used if ARCHIVE=historic")
                          .required_if("ARCHIVE", "historic")
                          .takes_value(true)
-                         .default_value("07")))
+                         .default_value("07"))
+                    .arg(Arg::with_name("from")
+                         .long("from")
+                         .help("Only fetch files with intervals at or after this datetime (YYYY/MM/DD[ HH:MM]), not used for historic")
+                         .takes_value(true))
+                    .arg(Arg::with_name("to")
+                         .long("to")
+                         .help("Only fetch files with intervals at or before this datetime (YYYY/MM/DD[ HH:MM]), not used for historic")
+                         .takes_value(true))
+                    .args(&download_args())
+                    .arg(Arg::with_name("jobs")
+                         .long("jobs")
+                         .short("j")
+                         .help("Number of files to download at once")
+                         .takes_value(true)
+                         .default_value("4"))
+                    .arg(Arg::with_name("sync")
+                         .long("sync")
+                         .help("Only download files missing from DIR, or whose size differs from nemweb's")
+                         .conflicts_with_all(&["list", "parquet"]))
+                    .arg(Arg::with_name("delete")
+                         .long("delete")
+                         .help("With --sync, remove zips in DIR that nemweb no longer lists")
+                         .requires("sync"))
+                    .arg(Arg::with_name("list")
+                         .long("list")
+                         .help("Print the files that would be fetched rather than fetching them")
+                         .conflicts_with("parquet"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("With --list, print the listing as JSON rather than a table")
+                         .requires("list"))
+                    .arg(Arg::with_name("parquet")
+                         .long("parquet")
+                         .help("Convert the fetched zips straight to this parquet file or directory rather than saving them")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-zips")
+                         .long("keep-zips")
+                         .help("With --parquet, also save the fetched zips to DIR")
+                         .requires("parquet")))
         .subcommand(SubCommand::with_name("info")
                     .about("Information about supported MMS packages")
                     .arg(Arg::with_name("PACKAGE")
@@ -72,6 +175,24 @@ fn _main() -> Result<(), Error> {
                          .required(true)
                          .takes_value(true)
                          .possible_values(&packages::Package::available_packages())))
+        .subcommand(SubCommand::with_name("generate")
+                    .about("Generate package schemas from the MMS Data Model's Oracle create script")
+                    .arg(Arg::with_name("DDL")
+                         .help("Path to the MMS Data Model create table script")
+                         .required(true)
+                         .takes_value(true)
+                         .index(1))
+                    .arg(Arg::with_name("reports")
+                         .long("reports")
+                         .short("r")
+                         .help("CSV mapping TABLE to REPORT_TYPE, REPORT_SUBTYPE and VERSION, unmapped tables are skipped")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .short("o")
+                         .help("File to write the generated module to, defaults to stdout")
+                         .takes_value(true)))
         .subcommand(SubCommand::with_name("manage")
                     .about("Manage a directory of MMS data")
                     .subcommand(SubCommand::with_name("init")
@@ -80,12 +201,27 @@ fn _main() -> Result<(), Error> {
                                      .required(true)
                                      .takes_value(true)
                                      .default_value(".")))
+                    .subcommand(SubCommand::with_name("dedup")
+                                .help("Remove rows of a package superseded by a later version of the same primary key")
+                                .arg(Arg::with_name("PACKAGE")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .index(1))
+                                .arg(Arg::with_name("DIRECTORY")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .index(2)
+                                     .default_value(".")))
                     .subcommand(SubCommand::with_name("update")
                                 .help("Fetch, download and extract new data files")
                                 .arg(Arg::with_name("DIRECTORY")
                                      .required(true)
                                      .takes_value(true)
-                                     .default_value("."))))
+                                     .default_value("."))
+                                .arg(Arg::with_name("keep-raw")
+                                     .long("keep-raw")
+                                     .help("Also keep the fetched zips in .raw/"))
+                                .args(&download_args())))
         .get_matches();
 
     match matches.subcommand() {
@@ -93,10 +229,49 @@ fn _main() -> Result<(), Error> {
             let path = sub_m.value_of("PATH")
                 .expect("Expected a path");
             let path = std::path::Path::new(&path);
-            let parsed_flatfiles = parse_flatfiles(&path)?;
+            if let Some(packages) = sub_m.value_of("packages") {
+                PackageDefinitions::from_path(packages)?.register()?;
+            }
+            let warnings = WarningHandler::new(|e| eprintln!("Warning: {}", e));
+            let options = ReadOptions {
+                batch_size: value_t!(sub_m, "batch-size", usize)
+                    .unwrap_or_else(|e| e.exit()),
+                lenient: sub_m.is_present("lenient"),
+                filter: Filter {
+                    columns: sub_m.value_of("columns")
+                        .map(|c| c.split(',').map(|s| s.trim().to_string()).collect()),
+                    predicates: sub_m.values_of("where")
+                        .map(|ps| ps.map(Predicate::from_str).collect::<Result<Vec<_>, _>>())
+                        .transpose()?
+                        .unwrap_or_default(),
+                },
+                warnings: Some(warnings.clone()),
+                keep_bad_records: sub_m.is_present("rejects"),
+            };
             let out = std::path::Path::new(&path)
                 .with_extension("parquet");
-            packages::to_parquet(parsed_flatfiles, out)?;
+            let mut sink = ParquetSink::new(out)
+                .infer_schemas(sub_m.is_present("infer-schema"))
+                .lineage_columns(sub_m.is_present("lineage"))
+                .decimals(sub_m.is_present("decimals"))
+                .deduplicate(sub_m.is_present("dedup"))
+                .timestamps(sub_m.value_of("timestamps")
+                    .and_then(TimestampMode::from_str)
+                    .expect("Not a valid timestamp mode"))
+                .with_warnings(warnings);
+            if let Some(rejects) = sub_m.value_of("rejects") {
+                let reject_mode = sub_m.value_of("reject-mode")
+                    .and_then(RejectMode::from_str)
+                    .expect("Not a valid reject mode");
+                sink = sink.with_rejects(RejectWriter::from_path(rejects)?, reject_mode);
+            }
+            let jobs = value_t!(sub_m, "jobs", usize)
+                .unwrap_or_else(|e| e.exit());
+            parse_flatfiles(&path, &options, jobs, &mut sink)?;
+            if let Some(rejects) = sub_m.value_of("rejects") {
+                eprintln!("Rejected {} rows, see {}", sink.rejected(), rejects);
+            }
+            sink.close()?;
         },
         ("fetch", Some(sub_m)) => {
             let package = sub_m.value_of("PACKAGE")
@@ -107,27 +282,107 @@ fn _main() -> Result<(), Error> {
                 .expect("No directory provided");
             let archive = sub_m.value_of("ARCHIVE")
                 .expect("Couldn't determine archive");
-            match archive {
-                "current" => {
-                    let archive = packages::fetch::Archive::Current;
-                    let scraper = packages::fetch::NemwebScraper { package, archive };
-                    scraper.download_all(dir)?;
-                },
-                "archive" => {
-                    let archive = packages::fetch::Archive::Archive;
-                    let scraper = packages::fetch::NemwebScraper { package, archive };
-                    scraper.download_all(dir)?;
+            if archive == "historic" && sub_m.is_present("sync") {
+                clap::Error::with_description("The argument '--sync' can't be used with the historic archive", ErrorKind::ArgumentConflict)
+                    .exit()
+            }
+            let parse_bound = |name| sub_m.value_of(name)
+                .map(|s| parse_datetime(s).ok_or_else(|| Error::InvalidDateTime(s.to_string())))
+                .transpose();
+            let range = packages::fetch::DateTimeRange {
+                from: parse_bound("from")?,
+                to: parse_bound("to")?,
+            };
+            if sub_m.is_present("list") {
+                let listing = match archive {
+                    "current" | "archive" => {
+                        let archive = packages::fetch::Archive::from_str(archive)
+                            .expect("Checked archive");
+                        let scraper = packages::fetch::NemwebScraper::new(package, archive)
+                            .with_download_options(download_options(sub_m));
+                        let listing = scraper.listing()?;
+                        let files = listing.files_in(&range).cloned().collect();
+                        NemwebListing { files, ..listing }
+                    },
+                    "historic" => {
+                        let year = sub_m.value_of("year").expect("Year required");
+                        let month = sub_m.value_of("month").expect("Month required");
+                        packages::fetch::HistoricDataDownloader::new(package)
+                            .with_download_options(download_options(sub_m))
+                            .with_year(year)?
+                            .with_month(month)?
+                            .listing()?
+                    },
+                    _ => panic!("Invalid ARCHIVE")
+                };
+                if sub_m.is_present("json") {
+                    println!("{}", listing.to_json()?);
+                } else {
+                    println!("{}", listing);
+                }
+                return Ok(())
+            }
+            if let Some(out) = sub_m.value_of("parquet") {
+                let keep_zips = if sub_m.is_present("keep-zips") { Some(dir) } else { None };
+                let options = ReadOptions::default();
+                let mut sink = ParquetSink::new(out)
+                    .with_warnings(WarningHandler::new(|e| eprintln!("Warning: {}", e)));
+                let mut write = |table: FlatFileTable| sink.write_table(&table);
+                let downloads = match archive {
+                    "current" | "archive" => {
+                        let archive = packages::fetch::Archive::from_str(archive)
+                            .expect("Checked archive");
+                        packages::fetch::NemwebScraper::new(package, archive)
+                            .with_range(range)
+                            .with_download_options(download_options(sub_m))
+                            .stream_all(&options, keep_zips, &mut write)?
+                    },
+                    "historic" => {
+                        let year = sub_m.value_of("year").expect("Year required");
+                        let month = sub_m.value_of("month").expect("Month required");
+                        packages::fetch::HistoricDataDownloader::new(package)
+                            .with_download_options(download_options(sub_m))
+                            .with_year(year)?
+                            .with_month(month)?
+                            .stream(&options, keep_zips, &mut write)?
+                    },
+                    _ => panic!("Invalid ARCHIVE")
+                };
+                sink.close()?;
+                eprintln!("{}", downloads);
+                return downloads.into_result()
+            }
+            let downloads = match archive {
+                "current" | "archive" => {
+                    let archive = packages::fetch::Archive::from_str(archive)
+                        .expect("Checked archive");
+                    let scraper = packages::fetch::NemwebScraper::new(package, archive)
+                        .with_range(range)
+                        .with_download_options(download_options(sub_m));
+                    if sub_m.is_present("sync") {
+                        let summary = scraper.sync(dir, sub_m.is_present("delete"))?;
+                        eprintln!("{}", summary);
+                        summary.downloads
+                    } else {
+                        let summary = scraper.download_all(dir)?;
+                        eprintln!("{}", summary);
+                        summary
+                    }
                 },
                 "historic" => {
                     let year = sub_m.value_of("year").expect("Year required");
                     let month = sub_m.value_of("month").expect("Month required");
-                    packages::fetch::HistoricDataDownloader::new(package)
+                    let summary = packages::fetch::HistoricDataDownloader::new(package)
+                        .with_download_options(download_options(sub_m))
                         .with_year(year)?
                         .with_month(month)?
                         .download(dir)?;
+                    eprintln!("{}", summary);
+                    summary
                 },
                 _ => panic!("Invalid ARCHIVE")
-            }
+            };
+            downloads.into_result()?;
         },
         ("info", Some(sub_m)) => {
             let package = sub_m.value_of("PACKAGE")
@@ -136,6 +391,28 @@ fn _main() -> Result<(), Error> {
             let info = packages::PackageInfo::new(package);
             println!("{}", info);
         },
+        ("generate", Some(sub_m)) => {
+            let ddl = sub_m.value_of("DDL")
+                .expect("Expected a DDL script");
+            let ddl = std::fs::read_to_string(ddl)
+                .map_err(Error::Io)?;
+            let mut tables = codegen::parse_ddl(&ddl)?;
+            let reports = sub_m.value_of("reports")
+                .expect("Expected a report mapping");
+            let rdr = ReaderBuilder::new()
+                .from_path(reports)
+                .map_err(Error::Csv)?;
+            let mappings = codegen::read_report_mappings(rdr)?;
+            for table in tables.iter_mut() {
+                table.report = mappings.get(&table.name).cloned();
+            }
+            let generated = codegen::generate(&tables)?;
+            match sub_m.value_of("output") {
+                Some(output) => std::fs::write(output, generated)
+                    .map_err(Error::Io)?,
+                None => print!("{}", generated),
+            }
+        },
         ("manage", Some(sub_m)) => {
             match sub_m.subcommand() {
                 ("init", Some(sub_m)) => {
@@ -145,6 +422,28 @@ fn _main() -> Result<(), Error> {
                     DepositoryState::init(path)
                         .map_err(Error::ManageError)?;
                 },
+                ("dedup", Some(sub_m)) => {
+                    let path = sub_m.value_of("DIRECTORY")
+                        .map(Path::new)
+                        .expect("Expected a directory");
+                    let state = DepositoryState::from_path(path)
+                        .map_err(Error::ManageError)?;
+                    let package = sub_m.value_of("PACKAGE")
+                        .and_then(packages::Package::from_str)
+                        .expect("Not a valid package");
+                    let removed = state.deduplicate(package)?;
+                    eprintln!("Removed {} superseded rows", removed);
+                },
+                ("update", Some(sub_m)) => {
+                    let path = sub_m.value_of("DIRECTORY")
+                        .map(Path::new)
+                        .expect("Expected a directory");
+                    let mut state = DepositoryState::from_path(path)
+                        .map_err(Error::ManageError)?;
+                    let summary = state.update(sub_m.is_present("keep-raw"), &download_options(sub_m))?;
+                    eprintln!("{}", summary);
+                    summary.downloads.into_result()?;
+                },
                 _ => {
                     eprintln!("Not implemented yet, sorry");
                 }
@@ -155,36 +454,82 @@ fn _main() -> Result<(), Error> {
     Ok(())
 }
 
-fn parse_flatfiles<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Vec<FlatFile>, Error> {
-    let parsed_flatfiles = match path.as_ref().extension().map(|s| s.to_str()).flatten() {
-        Some("csv") | Some("CSV") => {
-            let rdr = ReaderBuilder::new()
-                .flexible(true)
-                .has_headers(false)
-                .from_path(path)
-                .map_err(Error::Csv)?;
-            let flatfile = FlatFile::read_csv(rdr)?;
-            vec![flatfile]
-        },
-        Some("zip") | Some("ZIP") => {
-            let file = OpenOptions::new()
-                .read(true)
-                .open(path)
-                .map_err(Error::Io)?;
-            let archive = ZipArchive::new(file)
-                .map_err(Error::Zip)?;
-            read_zip(archive)?
-        },
-        _ if path.as_ref().is_dir() => {
-            let mut flatfiles = Vec::new();
-            for sub_path in path.as_ref().read_dir().map_err(Error::Io)? {
-                let mut to_append = sub_path.map_err(Error::Io)
-                    .and_then(|d| parse_flatfiles(d.path()))?;
-                flatfiles.append(&mut to_append);
-            }
-            flatfiles
+fn parse_flatfiles<P: AsRef<Path>>(path: P, options: &ReadOptions, jobs: usize, sink: &mut ParquetSink) -> Result<(), Error> {
+    let sources = parallel::sources(path)?;
+    parallel::read_sources(sources, options, jobs, |table| sink.write_table(&table))
+}
+
+/// Options for retrying and resuming downloads, shared by `fetch` and
+/// `manage update`
+fn download_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("retries")
+            .long("retries")
+            .help("Times to retry a download after a dropped connection or a 5xx or 429 response")
+            .takes_value(true)
+            .default_value("3"),
+        Arg::with_name("backoff")
+            .long("backoff")
+            .help("Seconds to wait before the first retry, doubled for each one after")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::with_name("no-resume")
+            .long("no-resume")
+            .help("Start partial downloads over rather than resuming them"),
+        Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .help("Most requests per second to each host, 0 for no limit")
+            .takes_value(true)
+            .default_value("2"),
+        Arg::with_name("user-agent")
+            .long("user-agent")
+            .help("User-Agent header sent with every request")
+            .takes_value(true)
+            .default_value(DEFAULT_USER_AGENT),
+        Arg::with_name("base-url")
+            .long("base-url")
+            .help("Fetch from this mirror of nemweb rather than nemweb itself, a file:// url reads a local directory")
+            .takes_value(true),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .help("Proxy for every request, overriding HTTP_PROXY and HTTPS_PROXY")
+            .takes_value(true),
+    ]
+}
+
+fn invalid_value(arg: &str, message: &str) -> ! {
+    clap::Error::with_description(&format!("Invalid value for '--{}': {}", arg, message), ErrorKind::InvalidValue)
+        .exit()
+}
+
+fn download_options(matches: &ArgMatches) -> DownloadOptions {
+    let backoff = value_t!(matches, "backoff", f64)
+        .unwrap_or_else(|e| e.exit());
+    let max_backoff = MAX_BACKOFF.as_secs_f64();
+    if !(0.0..=max_backoff).contains(&backoff) {
+        invalid_value("backoff", &format!("must be between 0 and {} seconds", max_backoff))
+    }
+    let rate_limit = value_t!(matches, "rate-limit", f64)
+        .unwrap_or_else(|e| e.exit());
+    if !rate_limit.is_finite() || (rate_limit > 0.0 && rate_limit < MIN_RATE_LIMIT) {
+        invalid_value("rate-limit", "must be 0, or at least one request an hour")
+    }
+    DownloadOptions {
+        retries: value_t!(matches, "retries", u32)
+            .unwrap_or_else(|e| e.exit()),
+        backoff: Duration::from_secs_f64(backoff),
+        resume: !matches.is_present("no-resume"),
+        // Only fetch downloads several files at once
+        jobs: if matches.is_present("jobs") {
+            value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit())
+        } else {
+            1
         },
-        _ => vec![]
-    };
-    Ok(parsed_flatfiles)
+        rate_limit: Some(rate_limit).filter(|rate| *rate > 0.0),
+        user_agent: matches.value_of("user-agent")
+            .unwrap_or(DEFAULT_USER_AGENT)
+            .to_string(),
+        base_url: matches.value_of("base-url").map(|u| u.to_string()),
+        proxy: matches.value_of("proxy").map(|p| p.to_string()),
+    }
 }
@@ -1,16 +1,38 @@
 use serde::{Serialize, Deserialize};
-use crate::packages::Package;
+use crate::packages::{
+    Package,
+    custom::PackageDefinition,
+};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     packages: Vec<Package>,
+    /// Packages declared in the config, these need to be registered before
+    /// `packages` can refer to them
+    #[serde(default)]
+    custom_packages: Vec<PackageDefinition>,
+    /// Mirror of nemweb to update from, used unless one is given on the
+    /// command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
 }
 
 impl Config {
     pub fn init() -> Self {
         Config {
-            packages: Vec::new()
+            packages: Vec::new(),
+            custom_packages: Vec::new(),
+            base_url: None,
         }
     }
+
+    /// Packages kept up to date in the depository
+    pub fn packages(&self) -> &[Package] {
+        &self.packages
+    }
+
+    pub fn base_url(&self) -> Option<&str> {
+        self.base_url.as_deref()
+    }
 }
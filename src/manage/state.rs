@@ -3,19 +3,27 @@ use std::{
         Path,
         PathBuf,
     },
+    collections::HashSet,
     fs::{
         create_dir,
         read_to_string,
         write,
+        OpenOptions,
     },
+    io::{ErrorKind, Write},
     fmt::{Display, Formatter, self},
 };
 use chrono::NaiveDate;
 use crate::{
     packages::{
         Package,
-        fetch::{Month, Year},
+        custom::PackageDefinitions,
+        fetch::{Archive, Month, NemwebScraper, Year, stream_zip, url_filename},
+        download::{DownloadOptions, DownloadSummary, Downloader},
+        dedup::deduplicate_files,
+        ParquetSink,
     },
+    flatfile::ReadOptions,
     manage::config::Config,
 };
 
@@ -25,6 +33,7 @@ pub enum Error {
     Io(std::io::Error),
     TomlRead(toml::de::Error),
     TomlWrite(toml::ser::Error),
+    CustomPackage(String),
 }
 
 impl Display for Error {
@@ -39,6 +48,8 @@ impl Display for Error {
                 write!(f, "{}", e),
             TomlWrite(e) =>
                 write!(f, "{}", e),
+            CustomPackage(e) =>
+                write!(f, "{}", e),
         }
     }
 }
@@ -95,39 +106,84 @@ pub struct NonHistoricFilename {
 }
 
 impl NonHistoricFilename {
+    /// Names the data file of a nemweb zip, such as
+    /// `PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip`, from the
+    /// report date and file id at the end of its name
+    pub fn from_nemweb_zip(package: Package, zip_name: &str) -> Option<Self> {
+        let stem = zip_name.rsplitn(2, '.').last()?;
+        let mut parts = stem.rsplit('_');
+        let file_id = parts.next()?.to_string();
+        let report_date = parts.next()
+            .and_then(|s| s.get(..8))
+            .and_then(|s| NaiveDate::parse_from_str(s, "%Y%m%d").ok())?;
+        Some(NonHistoricFilename { package, file_id, report_date })
+    }
+
     pub fn as_path_buf(&self) -> PathBuf {
         Path::new(self.package.as_str())
             .join(format!("nonhistoric_{}_{}.parquet", self.report_date.format("%Y-%m-%d"), self.file_id))
     }
 }
 
+fn processed_entry(package: &Package, zip_name: &str) -> String {
+    format!("{}/{}", package.as_str(), zip_name)
+}
+
 /// State of a MMS data depository
 ///
 /// Files are kept in
 ///  - `[base]/`: all files
 ///  - `[base]/data/`: parsed parquet tables
 ///  - `[base]/.raw/`: raw downloaded files
+///  - `[base]/.processed`: zips already converted, one `[PACKAGE]/[zip name]`
+///    per line
 ///             
 #[derive(Debug, Clone, PartialEq)]
 pub struct DepositoryState {
     pub base: PathBuf,
     pub files: Vec<Filename>,
+    /// Zips converted by [`DepositoryState::update`] for each package, as
+    /// `[PACKAGE]/[zip name]`, including those with no tables for their
+    /// package and so no data file
+    ///
+    /// Packages can share a page of zips, so a zip converted for one package
+    /// is still converted for the others.
+    pub processed: HashSet<String>,
     pub config: Config,
 }
 
 impl DepositoryState {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let mut files = Vec::new();
-        for entry in path.as_ref().join("data").read_dir().map_err(Error::Io)? {
-            let entry = entry.map_err(Error::Io)?;
-            let fname = Filename::from_path_buf(entry.path())?;
-            files.push(fname);
-        }
         let conifg_contents = read_to_string(path.as_ref().join("config.toml"))
             .map_err(Error::Io)?;
+        // Custom packages are registered first so the config and data
+        // directories can refer to them by name
+        toml::from_str::<PackageDefinitions>(&conifg_contents)
+            .map_err(Error::TomlRead)?
+            .register()
+            .map_err(|e| Error::CustomPackage(e.to_string()))?;
         let config = toml::from_str(&conifg_contents)
             .map_err(Error::TomlRead)?;
-        Ok(DepositoryState { base: path.as_ref().to_path_buf(), files, config })
+        let mut files = Vec::new();
+        // Data files are kept in a directory per package
+        for package_dir in path.as_ref().join("data").read_dir().map_err(Error::Io)? {
+            let package_dir = package_dir.map_err(Error::Io)?.path();
+            if !package_dir.is_dir() {
+                return Err(Error::UnrecognizedFilename(package_dir))
+            }
+            for entry in package_dir.read_dir().map_err(Error::Io)? {
+                let entry = entry.map_err(Error::Io)?;
+                let fname = Filename::from_path_buf(entry.path())?;
+                files.push(fname);
+            }
+        }
+        // Depositories from before zips were recorded have no list
+        let processed = match read_to_string(path.as_ref().join(".processed")) {
+            Ok(contents) => contents.lines().map(|l| l.to_string()).collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        Ok(DepositoryState { base: path.as_ref().to_path_buf(), files, processed, config })
     }
 
     pub fn init<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
@@ -143,14 +199,130 @@ impl DepositoryState {
         Ok(DepositoryState {
             base: path.as_ref().to_path_buf(),
             files: Vec::new(),
+            processed: HashSet::new(),
             config,
         })
     }
+
+    /// Whether a zip has been converted for `package`
+    pub fn is_processed(&self, package: &Package, zip_name: &str) -> bool {
+        self.processed.contains(&processed_entry(package, zip_name))
+    }
+
+    /// Records a zip as converted for `package`, so later updates don't
+    /// fetch it again for that package
+    fn mark_processed(&mut self, package: &Package, zip_name: &str) -> Result<(), Error> {
+        let entry = processed_entry(package, zip_name);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.base.join(".processed"))
+            .map_err(Error::Io)?;
+        writeln!(file, "{}", entry).map_err(Error::Io)?;
+        self.processed.insert(entry);
+        Ok(())
+    }
+
+    /// Fetches the zips of every configured package from nemweb's current
+    /// reports and converts each to its own data file, skipping those
+    /// already in the depository
+    ///
+    /// Zips are only fetched once, even when they have no tables for their
+    /// package and so no data file is written.
+    ///
+    /// Zips are streamed straight into parquet, and only kept in `.raw/`
+    /// with `keep_raw`. The config's base url is used unless `download` has
+    /// one.
+    pub fn update(&mut self, keep_raw: bool, download: &DownloadOptions) -> Result<UpdateSummary, crate::error::Error> {
+        let raw = self.base.join(".raw");
+        let keep_zips = if keep_raw { Some(raw.as_path()) } else { None };
+        let options = ReadOptions::default();
+        let mut download = download.clone();
+        if download.base_url.is_none() {
+            download.base_url = self.config.base_url().map(|u| u.to_string());
+        }
+        let downloader = Downloader::new(download.clone())?;
+        let mut summary = UpdateSummary::default();
+        for package in self.config.packages().to_vec() {
+            let dir = self.base.join("data").join(package.as_str());
+            if !dir.exists() {
+                create_dir(&dir).map_err(crate::error::Error::Io)?;
+            }
+            let scraper = NemwebScraper::new(package.clone(), Archive::Current)
+                .with_download_options(download.clone());
+            for url in scraper.zip_urls_with(&downloader)? {
+                let zip_name = url_filename(&url)?;
+                if self.is_processed(&package, zip_name) {
+                    continue
+                }
+                let fname = match NonHistoricFilename::from_nemweb_zip(package.clone(), zip_name) {
+                    Some(fname) => fname,
+                    None => return Err(crate::error::Error::ManageError(Error::UnrecognizedFilename(url.into()))),
+                };
+                let filename = Filename::NonHistoric(fname.clone());
+                if self.files.contains(&filename) {
+                    continue
+                }
+                let path = self.base.join("data").join(fname.as_path_buf());
+                let mut sink = ParquetSink::new(&path);
+                // Zips can hold other reports' tables, only this package's
+                // belong in its directory
+                let downloads = stream_zip(&url, &downloader, &options, keep_zips, &mut |table| match table.package() {
+                    Ok(p) if p == package => sink.write_table(&table),
+                    _ => Ok(()),
+                })?;
+                let fetched = downloads.failures.is_empty();
+                summary.downloads.merge(downloads);
+                sink.close()?;
+                if path.exists() {
+                    self.files.push(filename);
+                    summary.written += 1;
+                }
+                if fetched {
+                    self.mark_processed(&package, zip_name).map_err(crate::error::Error::ManageError)?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Removes rows of a package superseded by a later version of the same
+    /// primary key in any of its data files, returning how many were removed
+    pub fn deduplicate(&self, package: Package) -> Result<usize, crate::error::Error> {
+        let dir = self.base.join("data").join(package.as_str());
+        if !dir.is_dir() {
+            return Ok(0)
+        }
+        let mut paths = Vec::new();
+        for entry in dir.read_dir().map_err(crate::error::Error::Io)? {
+            let path = entry.map_err(crate::error::Error::Io)?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("parquet") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        deduplicate_files(&paths, package.primary_key())
+    }
+}
+
+/// What a [`DepositoryState::update`] did
+#[derive(Debug, Default)]
+pub struct UpdateSummary {
+    /// Data files written
+    pub written: usize,
+    pub downloads: DownloadSummary,
+}
+
+impl Display for UpdateSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Wrote {} new data files, {}", self.written, self.downloads)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use zip::{write::{FileOptions, ZipWriter}, CompressionMethod};
 
     #[test]
     fn historic_filename() {
@@ -189,4 +361,77 @@ mod tests {
             Filename::from_path_buf(f.as_path_buf()).unwrap()
         );
     }
+
+    #[test]
+    fn depository_files() {
+        let base = std::env::temp_dir().join(format!("nem-mms-depository-{}", std::process::id()));
+        DepositoryState::init(&base).unwrap();
+        let f = HistoricFilename {
+            package: Package::DispatchUnitScada,
+            month: Month::Jan,
+            year: Year::from_str("2022").unwrap(),
+        };
+        let path = base.join("data").join(f.as_path_buf());
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, b"").unwrap();
+
+        let mut state = DepositoryState::from_path(&base).unwrap();
+        assert_eq!(state.files, vec![Filename::Historic(f)]);
+        assert!(state.processed.is_empty());
+
+        let zip_name = "PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip";
+        state.mark_processed(&Package::DispatchUnitScada, zip_name).unwrap();
+        let reread = DepositoryState::from_path(&base).unwrap();
+        assert_eq!(reread.processed, state.processed);
+        assert!(reread.is_processed(&Package::DispatchUnitScada, zip_name));
+        assert!(!reread.is_processed(&Package::DispatchPrice, zip_name));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn update_packages_sharing_a_page() {
+        let root = std::env::temp_dir().join(format!("nem-mms-shared-page-{}", std::process::id()));
+        let mirror = root.join("mirror");
+        let page = mirror.join("Reports").join("Current").join("DispatchIS_Reports");
+        std::fs::create_dir_all(&page).unwrap();
+        let zip_name = "PUBLIC_DISPATCHIS_202112251045_0000000354978420.zip";
+        let csv_name = "PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV";
+        let mut zip = ZipWriter::new(std::fs::File::create(page.join(zip_name)).unwrap());
+        zip.start_file(csv_name, FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+        zip.write_all(include_bytes!("../../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV")).unwrap();
+        zip.finish().unwrap();
+
+        let base = root.join("depository");
+        DepositoryState::init(&base).unwrap();
+        let base_url = reqwest::Url::from_directory_path(&mirror).unwrap().to_string();
+        let config = format!("packages = [\"DISPATCHPRICE\", \"DISPATCH_LOCAL_PRICE\"]\nbase_url = {:?}\n", base_url);
+        write(base.join("config.toml"), config).unwrap();
+
+        // Both packages are listed on DispatchIS_Reports, each gets its own
+        // file from the one zip
+        let download = DownloadOptions { rate_limit: None, ..DownloadOptions::default() };
+        let mut state = DepositoryState::from_path(&base).unwrap();
+        let summary = state.update(false, &download).unwrap();
+        assert_eq!(summary.written, 2);
+        assert!(state.is_processed(&Package::DispatchPrice, zip_name));
+        assert!(state.is_processed(&Package::DispatchLocalPrice, zip_name));
+
+        let mut state = DepositoryState::from_path(&base).unwrap();
+        assert_eq!(state.files.len(), 2);
+        assert_eq!(state.update(false, &download).unwrap().written, 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn nemweb_zip_filename() {
+        let f = NonHistoricFilename::from_nemweb_zip(
+            Package::DispatchUnitScada,
+            "PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip"
+        ).unwrap();
+        assert_eq!(
+            f.as_path_buf(),
+            PathBuf::from("DISPATCH_UNIT_SCADA/nonhistoric_2021-12-25_0000000354978413.parquet")
+        );
+        assert!(NonHistoricFilename::from_nemweb_zip(Package::DispatchUnitScada, "README.zip").is_none());
+    }
 }
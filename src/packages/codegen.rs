@@ -0,0 +1,553 @@
+use arrow::datatypes::{DataType, TimeUnit};
+use serde::Deserialize;
+use strum::IntoEnumIterator;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+};
+use crate::{
+    error::Error,
+    packages::Package,
+};
+
+/// A table of the MMS Data Model
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDefinition {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    pub primary_key: Vec<String>,
+    pub report: Option<ReportMapping>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub datatype: DataType,
+    pub nullable: bool,
+    /// Precision and scale of `NUMBER(p,s)` columns, which are read as
+    /// `Float64` unless decimals are asked for
+    pub decimal: Option<(usize, usize)>,
+}
+
+/// The report type, subtype and version a table is published as in flat files
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReportMapping {
+    #[serde(rename = "TABLE")]
+    pub table: String,
+    #[serde(rename = "REPORT_TYPE")]
+    pub report_type: String,
+    #[serde(rename = "REPORT_SUBTYPE")]
+    pub report_subtype: String,
+    #[serde(rename = "VERSION")]
+    pub version: u32,
+}
+
+/// Arrow type for an Oracle column type of the MMS Data Model DDL
+///
+/// Integers get the narrowest type that holds their precision, numbers with
+/// a scale are `Float64`.
+pub fn oracle_datatype(s: &str) -> Option<DataType> {
+    let s = s.to_uppercase();
+    let (name, args) = match s.find('(') {
+        Some(i) => (&s[..i], s[i + 1..].trim_end_matches(')')),
+        None => (s.as_str(), ""),
+    };
+    let args = args.split(',')
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .filter_map(|a| a.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+    match (name.trim(), args.as_slice()) {
+        ("VARCHAR2", _) | ("VARCHAR", _) | ("CHAR", _) | ("NVARCHAR2", _) | ("CLOB", _) => Some(DataType::Utf8),
+        ("DATE", _) | ("TIMESTAMP", _) => Some(DataType::Timestamp(TimeUnit::Second, None)),
+        ("NUMBER", [p]) | ("NUMBER", [p, 0]) => Some(match *p {
+            0..=2 => DataType::Int8,
+            3..=4 => DataType::Int16,
+            5..=9 => DataType::Int32,
+            10..=18 => DataType::Int64,
+            _ => DataType::Float64,
+        }),
+        ("NUMBER", _) | ("FLOAT", _) => Some(DataType::Float64),
+        _ => None
+    }
+}
+
+/// Precision and scale of an Oracle `NUMBER` that isn't read as an integer
+pub fn oracle_decimal(s: &str) -> Option<(usize, usize)> {
+    let s = s.to_uppercase();
+    let args = s.trim().strip_prefix("NUMBER(")?.strip_suffix(')')?;
+    let mut args = args.split(',').map(|a| a.trim().parse::<usize>());
+    match (args.next(), args.next(), args.next()) {
+        (Some(Ok(p)), Some(Ok(s)), None) if s > 0 && p <= 38 => Some((p, s)),
+        (Some(Ok(p)), scale, None) if p > 18 && p <= 38 && matches!(scale, None | Some(Ok(0))) => Some((p, 0)),
+        _ => None,
+    }
+}
+
+/// Removes `--` line comments and `/* */` block comments
+fn strip_comments(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while !rest.is_empty() {
+        if rest.starts_with("--") {
+            rest = rest.find('\n').map(|i| &rest[i..]).unwrap_or("");
+        } else if rest.starts_with("/*") {
+            rest = rest.find("*/").map(|i| &rest[i + 2..]).unwrap_or("");
+        } else {
+            let c = rest.chars().next().expect("rest is not empty");
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// Splits on commas that aren't inside parentheses
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.into_iter().filter(|p| !p.is_empty()).collect()
+}
+
+fn parenthesised(s: &str) -> Option<&str> {
+    let start = s.find('(')?;
+    let end = s.rfind(')')?;
+    (start < end).then(|| &s[start + 1..end])
+}
+
+fn column_list(s: &str) -> Vec<String> {
+    parenthesised(s)
+        .map(|cols| cols.split(',').map(|c| c.trim().to_uppercase()).collect())
+        .unwrap_or_default()
+}
+
+/// Drops the owner from names like `MMSDM.DISPATCHPRICE`
+fn table_name(s: &str) -> String {
+    s.rsplit('.').next().unwrap_or(s).to_uppercase()
+}
+
+fn parse_column(table: &str, def: &str) -> Result<ColumnDefinition, Error> {
+    let def = def.trim();
+    let name_end = def.find(char::is_whitespace)
+        .ok_or_else(|| Error::InvalidDataModel(format!("No data type for column {} of {}", def, table)))?;
+    let name = def[..name_end].to_uppercase();
+    let rest = def[name_end..].trim_start();
+    // The type runs to the first space, or to the closing parenthesis of
+    // its precision and scale, which may contain spaces
+    let word_end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    let type_end = if rest[word_end..].trim_start().starts_with('(') {
+        rest.find(')').map(|close| close + 1).unwrap_or(rest.len())
+    } else {
+        word_end
+    };
+    let datatype_str: String = rest[..type_end].split_whitespace().collect();
+    let datatype = oracle_datatype(&datatype_str)
+        .ok_or_else(|| Error::InvalidDataModel(format!("Unrecognized data type {} for {}.{}", datatype_str, table, name)))?;
+    let nullable = !rest[type_end..].to_lowercase().contains("not null");
+    let decimal = oracle_decimal(&datatype_str);
+    Ok(ColumnDefinition { name, datatype, nullable, decimal })
+}
+
+/// Reads the table definitions of the MMS Data Model's Oracle create script
+///
+/// Only `create table` and primary key constraints (inline or added with
+/// `alter table`) are used, every other statement is ignored.
+pub fn parse_ddl(sql: &str) -> Result<Vec<TableDefinition>, Error> {
+    let sql = strip_comments(sql);
+    let mut tables: Vec<TableDefinition> = Vec::new();
+    for statement in sql.split(';').map(str::trim) {
+        let lower = statement.to_lowercase();
+        let words = lower.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["create", "table", ..] => {
+                let name = statement.split_whitespace()
+                    .nth(2)
+                    .map(|n| table_name(n.split('(').next().unwrap_or(n)))
+                    .ok_or_else(|| Error::InvalidDataModel(statement.to_string()))?;
+                let body = parenthesised(statement)
+                    .ok_or_else(|| Error::InvalidDataModel(format!("No columns for table {}", name)))?;
+                let mut columns = Vec::new();
+                let mut primary_key = Vec::new();
+                for def in split_top_level(body) {
+                    let def_lower = def.to_lowercase();
+                    if def_lower.starts_with("constraint") || def_lower.starts_with("primary key") {
+                        if def_lower.contains("primary key") {
+                            primary_key = column_list(def);
+                        }
+                    } else {
+                        columns.push(parse_column(&name, def)?);
+                    }
+                }
+                tables.push(TableDefinition { name, columns, primary_key, report: None });
+            },
+            ["alter", "table", _, ..] if lower.contains("primary key") => {
+                let name = statement.split_whitespace()
+                    .nth(2)
+                    .map(table_name)
+                    .ok_or_else(|| Error::InvalidDataModel(statement.to_string()))?;
+                let key_start = lower.find("primary key").expect("Checked above");
+                let table = tables.iter_mut()
+                    .find(|t| t.name == name)
+                    .ok_or_else(|| Error::InvalidDataModel(format!("Primary key for unknown table {}", name)))?;
+                table.primary_key = column_list(&statement[key_start..]);
+            },
+            _ => (),
+        }
+    }
+    Ok(tables)
+}
+
+/// Reads a csv with `TABLE`, `REPORT_TYPE`, `REPORT_SUBTYPE` and `VERSION`
+/// columns, keyed by table name
+pub fn read_report_mappings<R: std::io::Read>(mut rdr: csv::Reader<R>) -> Result<HashMap<String, ReportMapping>, Error> {
+    let mut mappings = HashMap::new();
+    for mapping in rdr.deserialize() {
+        let mapping: ReportMapping = mapping.map_err(Error::Csv)?;
+        mappings.insert(mapping.table.to_uppercase(), mapping);
+    }
+    Ok(mappings)
+}
+
+/// `BIDOFFERPERIOD` -> `Bidofferperiod`
+fn variant_name(table: &str) -> String {
+    table.split('_')
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            let lower = w.to_lowercase();
+            let mut chars = lower.chars();
+            chars.next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn datatype_source(datatype: &DataType) -> String {
+    match datatype {
+        DataType::Timestamp(TimeUnit::Second, None) => "DataType::Timestamp(TimeUnit::Second, None)".to_string(),
+        dt => format!("DataType::{:?}", dt),
+    }
+}
+
+/// Tables that can be generated: those with a report mapping, since
+/// nothing else can be read from flat files, and that aren't already built
+/// in packages
+fn generated_tables(tables: &[TableDefinition]) -> Vec<(&TableDefinition, &ReportMapping)> {
+    let builtin = Package::iter()
+        .filter_map(|p| p.mms_table())
+        .collect::<Vec<_>>();
+    let mut generated = tables.iter()
+        .filter(|t| !builtin.contains(&t.name.as_str()))
+        .filter_map(|t| t.report.as_ref().map(|r| (t, r)))
+        .collect::<Vec<_>>();
+    generated.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+    generated
+}
+
+/// Renders `src/packages/generated.rs`, the `GeneratedPackage` enum with the
+/// schema, decimal schema, primary key and report mapping of each table
+///
+/// The built in packages are left out so their names and hand tuned
+/// schemas stay as they are, along with tables that have no report mapping.
+/// Fails if a table's name doesn't make a valid variant, or makes the same
+/// one as another table's.
+pub fn generate(tables: &[TableDefinition]) -> Result<String, Error> {
+    let generated = generated_tables(tables);
+    check_variant_names(&generated)?;
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = write_module(&mut out, &generated);
+    Ok(out)
+}
+
+/// Checks every table gets a distinct variant that's a valid identifier,
+/// `FOO_BAR` and `FOOBAR` would both be `Foobar`
+fn check_variant_names(tables: &[(&TableDefinition, &ReportMapping)]) -> Result<(), Error> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for (t, _) in tables {
+        let variant = variant_name(&t.name);
+        let valid = variant.chars().next().map_or(false, |c| c.is_ascii_alphabetic())
+            && variant.chars().all(|c| c.is_ascii_alphanumeric())
+            && variant != "Self";
+        if !valid {
+            return Err(Error::InvalidDataModel(format!("Table {} doesn't make a valid variant name ({})", t.name, variant)))
+        }
+        if let Some(other) = seen.insert(variant.clone(), &t.name) {
+            return Err(Error::InvalidDataModel(format!("Tables {} and {} would both be generated as {}", other, t.name, variant)))
+        }
+    }
+    Ok(())
+}
+
+/// Writes a `match *self` over every table, with the arm for each from `arm`
+fn write_match<F>(out: &mut String, tables: &[(&TableDefinition, &ReportMapping)], arm: F) -> std::fmt::Result
+where
+    F: Fn(&TableDefinition, &ReportMapping) -> String
+{
+    writeln!(out, "        match *self {{")?;
+    for (t, r) in tables {
+        writeln!(out, "            GeneratedPackage::{} => {},", variant_name(&t.name), arm(t, r))?;
+    }
+    writeln!(out, "        }}")
+}
+
+fn write_schema(out: &mut String, name: &str, columns: &[ColumnDefinition], decimals: bool) -> std::fmt::Result {
+    writeln!(out, "    static ref {}: Schema = Schema::new(", name)?;
+    writeln!(out, "        vec![")?;
+    for c in columns {
+        let datatype = match c.decimal {
+            Some((precision, scale)) if decimals => DataType::Decimal(precision, scale),
+            _ => c.datatype.clone(),
+        };
+        writeln!(out, "            Field::new({:?}, {}, {}),", c.name, datatype_source(&datatype), c.nullable)?;
+    }
+    writeln!(out, "        ]")?;
+    writeln!(out, "    );")
+}
+
+fn write_module(out: &mut String, tables: &[(&TableDefinition, &ReportMapping)]) -> std::fmt::Result {
+    let timestamps = tables.iter()
+        .flat_map(|(t, _)| t.columns.iter())
+        .any(|c| matches!(c.datatype, DataType::Timestamp(_, _)));
+    writeln!(out, "// Generated by `nem-mms generate` from the MMS Data Model, do not edit by hand")?;
+    writeln!(out)?;
+    if tables.is_empty() {
+        writeln!(out, "use arrow::datatypes::Schema;")?;
+    } else {
+        let time_unit = if timestamps { ", TimeUnit" } else { "" };
+        writeln!(out, "use arrow::datatypes::{{DataType, Field, Schema{}}};", time_unit)?;
+        writeln!(out, "use lazy_static::lazy_static;")?;
+    }
+    writeln!(out)?;
+    writeln!(out, "/// Tables of the MMS Data Model that aren't built in packages")?;
+    writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]")?;
+    writeln!(out, "pub enum GeneratedPackage {{")?;
+    for (t, _) in tables {
+        writeln!(out, "    {},", variant_name(&t.name))?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "impl GeneratedPackage {{")?;
+
+    writeln!(out, "    pub const ALL: &[GeneratedPackage] = &[")?;
+    for (t, _) in tables {
+        writeln!(out, "        GeneratedPackage::{},", variant_name(&t.name))?;
+    }
+    writeln!(out, "    ];")?;
+    writeln!(out)?;
+
+    writeln!(out, "    pub fn all() -> Vec<GeneratedPackage> {{")?;
+    writeln!(out, "        GeneratedPackage::ALL.to_vec()")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    pub fn from_name(name: &str) -> Option<Self> {{")?;
+    writeln!(out, "        GeneratedPackage::ALL.iter()")?;
+    writeln!(out, "            .copied()")?;
+    writeln!(out, "            .find(|p| p.name() == name)")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    pub fn from_report_type(report_type: &str, report_subtype: &str) -> Option<Self> {{")?;
+    writeln!(out, "        GeneratedPackage::ALL.iter()")?;
+    writeln!(out, "            .copied()")?;
+    writeln!(out, "            .find(|p| p.report_type() == (report_type, report_subtype))")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    /// Package name, the table's name in the MMS Data Model")?;
+    writeln!(out, "    pub fn name(&self) -> &'static str {{")?;
+    write_match(out, tables, |t, _| format!("{:?}", t.name))?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    /// Report type and subtype of the table's information records")?;
+    writeln!(out, "    pub fn report_type(&self) -> (&'static str, &'static str) {{")?;
+    write_match(out, tables, |_, r| format!("({:?}, {:?})", r.report_type, r.report_subtype))?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    /// Report version the schema was generated for")?;
+    writeln!(out, "    pub fn version(&self) -> u32 {{")?;
+    write_match(out, tables, |_, r| r.version.to_string())?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    pub fn primary_key(&self) -> &'static [&'static str] {{")?;
+    write_match(out, tables, |t, _| {
+        let key = t.primary_key.iter().map(|k| format!("{:?}", k)).collect::<Vec<_>>();
+        format!("&[{}]", key.join(", "))
+    })?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    pub fn schema(&self) -> &'static Schema {{")?;
+    write_match(out, tables, |t, _| format!("&{}", t.name))?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    /// Schema with `NUMBER(p,s)` columns as `Decimal(p, s)` rather than `Float64`")?;
+    writeln!(out, "    pub fn decimal_schema(&self) -> &'static Schema {{")?;
+    write_match(out, tables, |t, _| format!("&{}_DECIMAL", t.name))?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+
+    if tables.is_empty() {
+        return Ok(())
+    }
+    writeln!(out)?;
+    writeln!(out, "lazy_static! {{")?;
+    for (i, (t, _)) in tables.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        write_schema(out, &t.name, &t.columns, false)?;
+        writeln!(out)?;
+        write_schema(out, &format!("{}_DECIMAL", t.name), &t.columns, true)?;
+    }
+    writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DDL: &str = r#"
+/*==============================================================*/
+/* Table: DISPATCHPRICE                                         */
+/*==============================================================*/
+create table DISPATCHPRICE (
+   SETTLEMENTDATE       DATE                  not null,
+   RUNNO                NUMBER(3,0)           not null,
+   REGIONID             VARCHAR2(10)          not null,
+   DISPATCHINTERVAL     VARCHAR2(22)          not null,
+   INTERVENTION         NUMBER(2,0)           not null,
+   RRP                  NUMBER(15, 5),
+   LASTCHANGED          DATE
+);
+
+comment on table DISPATCHPRICE is
+'DISPATCHPRICE records 5 minute dispatch prices for energy and FCAS, including whether an intervention has occurred, or price override (e.g. for Administered Price Cap).';
+
+alter table DISPATCHPRICE
+   add constraint DISPATCHPRICE_PK primary key (SETTLEMENTDATE, RUNNO, REGIONID, DISPATCHINTERVAL, INTERVENTION);
+
+-- Table with an inline key
+create table DISPATCH_UNIT_SCADA (
+   SETTLEMENTDATE       DATE                  not null,
+   DUID                 VARCHAR2(20)          not null,
+   SCADAVALUE           NUMBER(16,6),
+   constraint DISPATCH_UNIT_SCADA_PK primary key (SETTLEMENTDATE, DUID)
+);
+
+create table BIDOFFERPERIOD (
+   DUID                 VARCHAR2(20)          not null,
+   BIDTYPE              VARCHAR2(10)          not null,
+   TRADINGDATE          DATE                  not null,
+   OFFERDATETIME        DATE                  not null,
+   PERIODID             NUMBER(3,0)           not null,
+   MAXAVAIL             NUMBER(12,6),
+   LASTCHANGED          DATE,
+   constraint BIDOFFERPERIOD_PK primary key (DUID, BIDTYPE, TRADINGDATE, OFFERDATETIME, PERIODID)
+);
+
+-- Without a report mapping
+create table BIDTYPES (
+   BIDTYPE              VARCHAR2(10)          not null,
+   DESCRIPTION          VARCHAR2(64),
+   constraint BIDTYPES_PK primary key (BIDTYPE)
+);
+"#;
+
+    const REPORTS: &str = "TABLE,REPORT_TYPE,REPORT_SUBTYPE,VERSION
+DISPATCHPRICE,DISPATCH,PRICE,5
+BIDOFFERPERIOD,BID,BIDOFFERPERIOD,1
+";
+
+    /// The sample's generated module, compiled as part of the tests
+    #[allow(dead_code)]
+    mod compiled {
+        include!("../../tests/data/generated_sample.rs");
+    }
+
+    #[test]
+    fn oracle_datatypes() {
+        assert_eq!(oracle_datatype("VARCHAR2(10)"), Some(DataType::Utf8));
+        assert_eq!(oracle_datatype("date"), Some(DataType::Timestamp(TimeUnit::Second, None)));
+        assert_eq!(oracle_datatype("NUMBER(1,0)"), Some(DataType::Int8));
+        assert_eq!(oracle_datatype("NUMBER(3,0)"), Some(DataType::Int16));
+        assert_eq!(oracle_datatype("NUMBER(10,0)"), Some(DataType::Int64));
+        assert_eq!(oracle_datatype("NUMBER(15,5)"), Some(DataType::Float64));
+        assert_eq!(oracle_datatype("BLOB"), None);
+        assert_eq!(oracle_decimal("NUMBER(15,5)"), Some((15, 5)));
+        assert_eq!(oracle_decimal("NUMBER(22,0)"), Some((22, 0)));
+        assert_eq!(oracle_decimal("NUMBER(3,0)"), None);
+        assert_eq!(oracle_decimal("VARCHAR2(10)"), None);
+    }
+
+    #[test]
+    fn ddl_tables() {
+        let tables = parse_ddl(DDL).unwrap();
+        assert_eq!(tables.len(), 4);
+        let price = &tables[0];
+        assert_eq!(price.name, "DISPATCHPRICE");
+        assert_eq!(price.columns.len(), 7);
+        assert_eq!(price.columns[1], ColumnDefinition { name: "RUNNO".to_string(), datatype: DataType::Int16, nullable: false, decimal: None });
+        assert_eq!(price.columns[5], ColumnDefinition { name: "RRP".to_string(), datatype: DataType::Float64, nullable: true, decimal: Some((15, 5)) });
+        assert_eq!(price.primary_key, vec!["SETTLEMENTDATE", "RUNNO", "REGIONID", "DISPATCHINTERVAL", "INTERVENTION"]);
+        let scada = &tables[1];
+        assert_eq!(scada.columns.len(), 3);
+        assert_eq!(scada.primary_key, vec!["SETTLEMENTDATE", "DUID"]);
+    }
+
+    #[test]
+    fn generated_module() {
+        let mut tables = parse_ddl(DDL).unwrap();
+        let mappings = read_report_mappings(csv::Reader::from_reader(REPORTS.as_bytes())).unwrap();
+        for table in tables.iter_mut() {
+            table.report = mappings.get(&table.name).cloned();
+        }
+        // DISPATCHPRICE is built in and BIDTYPES has no report mapping, so
+        // only BIDOFFERPERIOD is generated
+        assert_eq!(generate(&tables).unwrap(), include_str!("../../tests/data/generated_sample.rs"));
+
+        use compiled::GeneratedPackage;
+        assert_eq!(GeneratedPackage::all(), vec![GeneratedPackage::Bidofferperiod]);
+        let package = GeneratedPackage::from_name("BIDOFFERPERIOD").unwrap();
+        assert_eq!(GeneratedPackage::from_report_type("BID", "BIDOFFERPERIOD"), Some(package));
+        assert_eq!(package.report_type(), ("BID", "BIDOFFERPERIOD"));
+        assert_eq!(package.version(), 1);
+        assert_eq!(package.primary_key(), &["DUID", "BIDTYPE", "TRADINGDATE", "OFFERDATETIME", "PERIODID"]);
+        assert_eq!(package.schema().field(4).data_type(), &DataType::Int16);
+        assert_eq!(package.schema().field(5).data_type(), &DataType::Float64);
+        assert_eq!(package.decimal_schema().field(5).data_type(), &DataType::Decimal(12, 6));
+
+        let empty = generate(&[]).unwrap();
+        assert!(empty.contains("pub enum GeneratedPackage {\n}"));
+        assert!(!empty.contains("lazy_static"));
+
+        // Names that collide or don't start with a letter can't be generated
+        let bids = tables.iter().find(|t| t.name == "BIDOFFERPERIOD").unwrap();
+        let renamed = |name: &str| {
+            let mut table = bids.clone();
+            table.name = name.to_string();
+            table
+        };
+        assert!(matches!(generate(&[bids.clone(), renamed("BID_OFFER_PERIOD")]), Err(Error::InvalidDataModel(_))));
+        assert!(matches!(generate(&[renamed("5MIN_BIDS")]), Err(Error::InvalidDataModel(_))));
+    }
+}
@@ -0,0 +1,322 @@
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use std::{
+    path::Path,
+    fs::read_to_string,
+    sync::RwLock,
+};
+use crate::{
+    error::Error,
+    packages::{Package, codegen::oracle_datatype, registry::REGISTRY},
+};
+
+lazy_static! {
+    static ref CUSTOM_PACKAGES: RwLock<Vec<&'static RegisteredPackage>> = RwLock::new(Vec::new());
+}
+
+/// A package declared in TOML rather than built in
+///
+/// ```toml
+/// [[custom_packages]]
+/// name = "BIDOFFERPERIOD"
+/// report_type = "BID"
+/// report_subtype = "BIDOFFERPERIOD"
+/// version = 1
+/// primary_key = ["DUID", "BIDTYPE", "TRADINGDATE", "PERIODID"]
+/// mms_table = "BIDOFFERPERIOD"
+/// columns = [
+///     { name = "DUID", datatype = "Utf8", nullable = false },
+///     { name = "MAXAVAIL", datatype = "NUMBER(12,6)" },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageDefinition {
+    pub name: String,
+    pub report_type: String,
+    pub report_subtype: String,
+    pub version: u32,
+    #[serde(default)]
+    pub primary_key: Vec<String>,
+    /// Table in the MMS Data Model, needed to fetch MMSDM historic files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mms_table: Option<String>,
+    pub columns: Vec<ColumnDeclaration>,
+}
+
+/// A column of a [`PackageDefinition`], the data type is either an arrow
+/// type as printed by `nem-mms info` or an Oracle type from the MMS Data Model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnDeclaration {
+    pub name: String,
+    pub datatype: String,
+    #[serde(default = "nullable_default")]
+    pub nullable: bool,
+}
+
+fn nullable_default() -> bool {
+    true
+}
+
+/// The `custom_packages` section of a depository config or a standalone
+/// package file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageDefinitions {
+    #[serde(default)]
+    pub custom_packages: Vec<PackageDefinition>,
+}
+
+impl PackageDefinitions {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = read_to_string(path).map_err(Error::Io)?;
+        toml::from_str(&contents).map_err(Error::Toml)
+    }
+
+    /// Registers every definition, see [`register`]
+    pub fn register(&self) -> Result<Vec<Package>, Error> {
+        self.custom_packages.iter()
+            .cloned()
+            .map(register)
+            .collect()
+    }
+}
+
+/// Data type of a column declaration, `Timestamp` is short for
+/// `Timestamp(Second, None)`
+///
+/// Oracle `NUMBER(p,s)` columns are read as `Float64`, declare them as
+/// `Decimal(p, s)` to keep them exact.
+pub fn arrow_datatype(s: &str) -> Option<DataType> {
+    if let Some(args) = s.trim().strip_prefix("Decimal(").and_then(|s| s.strip_suffix(')')) {
+        let mut args = args.split(',').map(|a| a.trim().parse::<usize>());
+        return match (args.next(), args.next(), args.next()) {
+            (Some(Ok(precision)), Some(Ok(scale)), None) if precision <= 38 && scale <= precision => Some(DataType::Decimal(precision, scale)),
+            _ => None,
+        }
+    }
+    match s.trim() {
+        "Utf8" => Some(DataType::Utf8),
+        "LargeUtf8" => Some(DataType::LargeUtf8),
+        "Boolean" => Some(DataType::Boolean),
+        "Int8" => Some(DataType::Int8),
+        "Int16" => Some(DataType::Int16),
+        "Int32" => Some(DataType::Int32),
+        "Int64" => Some(DataType::Int64),
+        "UInt8" => Some(DataType::UInt8),
+        "UInt16" => Some(DataType::UInt16),
+        "UInt32" => Some(DataType::UInt32),
+        "UInt64" => Some(DataType::UInt64),
+        "Float32" => Some(DataType::Float32),
+        "Float64" => Some(DataType::Float64),
+        "Date32" => Some(DataType::Date32),
+        "Date64" => Some(DataType::Date64),
+        "Time32(Second)" => Some(DataType::Time32(TimeUnit::Second)),
+        "Time32(Millisecond)" => Some(DataType::Time32(TimeUnit::Millisecond)),
+        "Time64(Microsecond)" => Some(DataType::Time64(TimeUnit::Microsecond)),
+        "Time64(Nanosecond)" => Some(DataType::Time64(TimeUnit::Nanosecond)),
+        "Timestamp" | "Timestamp(Second, None)" => Some(DataType::Timestamp(TimeUnit::Second, None)),
+        "Timestamp(Millisecond, None)" => Some(DataType::Timestamp(TimeUnit::Millisecond, None)),
+        "Timestamp(Microsecond, None)" => Some(DataType::Timestamp(TimeUnit::Microsecond, None)),
+        "Timestamp(Nanosecond, None)" => Some(DataType::Timestamp(TimeUnit::Nanosecond, None)),
+        s => oracle_datatype(s),
+    }
+}
+
+impl PackageDefinition {
+    pub fn schema(&self) -> Result<Schema, Error> {
+        let fields = self.columns.iter()
+            .map(|c| arrow_datatype(&c.datatype)
+                .map(|dt| Field::new(&c.name, dt, c.nullable))
+                .ok_or_else(|| Error::InvalidPackageDefinition(
+                    format!("Unrecognized data type {} for {}.{}", c.datatype, self.name, c.name)
+                )))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for key in self.primary_key.iter() {
+            if !self.columns.iter().any(|c| c.name == *key) {
+                return Err(Error::InvalidPackageDefinition(
+                    format!("Primary key {} is not a column of {}", key, self.name)
+                ))
+            }
+        }
+        Ok(Schema::new(fields))
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredPackage {
+    definition: PackageDefinition,
+    schema: Schema,
+    primary_key: Vec<&'static str>,
+}
+
+/// Handle to a registered [`PackageDefinition`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CustomPackage(usize);
+
+impl CustomPackage {
+    fn registered(&self) -> &'static RegisteredPackage {
+        CUSTOM_PACKAGES.read()
+            .expect("Custom package lock poisoned")[self.0]
+    }
+
+    pub fn all() -> Vec<CustomPackage> {
+        let count = CUSTOM_PACKAGES.read()
+            .expect("Custom package lock poisoned")
+            .len();
+        (0..count).map(CustomPackage).collect()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        CUSTOM_PACKAGES.read()
+            .expect("Custom package lock poisoned")
+            .iter()
+            .position(|p| p.definition.name == name)
+            .map(CustomPackage)
+    }
+
+    pub fn from_report_type(report_type: &str, report_subtype: &str) -> Option<Self> {
+        CUSTOM_PACKAGES.read()
+            .expect("Custom package lock poisoned")
+            .iter()
+            .position(|p| p.definition.report_type == report_type && p.definition.report_subtype == report_subtype)
+            .map(CustomPackage)
+    }
+
+    pub fn name(&self) -> &'static str {
+        &self.registered().definition.name
+    }
+
+    pub fn report_type(&self) -> (&'static str, &'static str) {
+        let definition = &self.registered().definition;
+        (&definition.report_type, &definition.report_subtype)
+    }
+
+    pub fn schema(&self) -> &'static Schema {
+        &self.registered().schema
+    }
+
+    pub fn primary_key(&self) -> &'static [&'static str] {
+        &self.registered().primary_key
+    }
+
+    pub fn mms_table(&self) -> Option<&'static str> {
+        self.registered().definition.mms_table.as_deref()
+    }
+}
+
+/// Makes a declared package available everywhere a built in one is
+///
+/// Registering the same definition again is a no-op, but a definition can't
+/// reuse the name or report type of a different package.
+pub fn register(definition: PackageDefinition) -> Result<Package, Error> {
+    let clashes = |package: &str| Error::InvalidPackageDefinition(
+        format!("{} clashes with the {} package", definition.name, package)
+    );
+    // Built in and generated packages never change, custom ones are checked
+    // below while holding the lock so the same package can't be added twice
+    let custom = |p: &Package| matches!(p, Package::Custom(_));
+    let clash = Package::from_str(&definition.name)
+        .filter(|p| !custom(p))
+        .or_else(|| Package::from_report_type(&definition.report_type, &definition.report_subtype).filter(|p| !custom(p)));
+    if let Some(package) = clash {
+        return Err(clashes(package.as_str()))
+    }
+    let schema = definition.schema()?;
+    let mut packages = CUSTOM_PACKAGES.write()
+        .expect("Custom package lock poisoned");
+    if let Some(idx) = packages.iter().position(|p| p.definition.name == definition.name) {
+        return if packages[idx].definition == definition {
+            Ok(Package::Custom(CustomPackage(idx)))
+        } else {
+            Err(Error::InvalidPackageDefinition(format!("{} is already defined", definition.name)))
+        }
+    }
+    let same_report = packages.iter()
+        .position(|p| p.definition.report_type == definition.report_type && p.definition.report_subtype == definition.report_subtype);
+    if let Some(idx) = same_report {
+        return Err(clashes(&packages[idx].definition.name))
+    }
+    let primary_key = definition.primary_key.iter()
+        .map(|k| &*Box::leak(k.clone().into_boxed_str()))
+        .collect();
+    // Definitions live for the rest of the program, like the built in schemas
+    let registered: &'static RegisteredPackage = Box::leak(Box::new(RegisteredPackage { definition, schema, primary_key }));
+    packages.push(registered);
+    let package = Package::Custom(CustomPackage(packages.len() - 1));
+    drop(packages);
+    REGISTRY.register(package, registered.definition.version, &registered.schema);
+    Ok(package)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        /// Held by tests that register packages, since they share the global
+        /// registry
+        static ref REGISTERING: Mutex<()> = Mutex::new(());
+    }
+
+    const DEFINITIONS: &str = r#"
+[[custom_packages]]
+name = "TEST_BIDOFFERPERIOD"
+report_type = "TEST_BID"
+report_subtype = "BIDOFFERPERIOD"
+version = 1
+primary_key = ["DUID", "PERIODID"]
+columns = [
+    { name = "DUID", datatype = "Utf8", nullable = false },
+    { name = "PERIODID", datatype = "NUMBER(3,0)", nullable = false },
+    { name = "MAXAVAIL", datatype = "Float64" },
+    { name = "PRICE", datatype = "Decimal(15, 5)" },
+]
+"#;
+
+    #[test]
+    fn register_definitions() {
+        let _registering = REGISTERING.lock().unwrap_or_else(|e| e.into_inner());
+        let definitions: PackageDefinitions = toml::from_str(DEFINITIONS).unwrap();
+        let packages = definitions.register().unwrap();
+        assert_eq!(packages.len(), 1);
+        let package = packages[0];
+        assert_eq!(Package::from_str("TEST_BIDOFFERPERIOD"), Some(package));
+        assert_eq!(Package::from_report_type("TEST_BID", "BIDOFFERPERIOD"), Some(package));
+        assert_eq!(package.as_str(), "TEST_BIDOFFERPERIOD");
+        assert_eq!(package.schema().field(1), &Field::new("PERIODID", DataType::Int16, false));
+        assert!(package.schema().field(2).is_nullable());
+        assert_eq!(package.schema().field(3).data_type(), &DataType::Decimal(15, 5));
+        assert_eq!(REGISTRY.lookup("TEST_BID", "BIDOFFERPERIOD", 1).unwrap().package, package);
+        assert!(Package::available_packages().contains(&"TEST_BIDOFFERPERIOD"));
+
+        // Registering again is fine, redefining isn't
+        assert_eq!(definitions.register().unwrap(), packages);
+        let mut redefined = definitions.custom_packages[0].clone();
+        redefined.version = 2;
+        assert!(matches!(register(redefined), Err(Error::InvalidPackageDefinition(_))));
+        let mut renamed = definitions.custom_packages[0].clone();
+        renamed.name = "TEST_BIDOFFERPERIOD_COPY".to_string();
+        assert!(matches!(register(renamed), Err(Error::InvalidPackageDefinition(_))));
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let _registering = REGISTERING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut definition = PackageDefinition {
+            name: "DISPATCHPRICE".to_string(),
+            report_type: "TEST_DISPATCH".to_string(),
+            report_subtype: "PRICE".to_string(),
+            version: 1,
+            primary_key: vec!["REGIONID".to_string()],
+            mms_table: None,
+            columns: vec![ColumnDeclaration { name: "RRP".to_string(), datatype: "Float64".to_string(), nullable: true }],
+        };
+        assert!(matches!(register(definition.clone()), Err(Error::InvalidPackageDefinition(_))));
+        definition.name = "TEST_PRICE".to_string();
+        assert!(matches!(definition.schema(), Err(Error::InvalidPackageDefinition(_))));
+        definition.primary_key = Vec::new();
+        definition.columns[0].datatype = "Complex128".to_string();
+        assert!(matches!(definition.schema(), Err(Error::InvalidPackageDefinition(_))));
+    }
+}
@@ -0,0 +1,350 @@
+use arrow::{
+    array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray},
+    compute::{cast, filter_record_batch},
+    datatypes::{DataType, TimeUnit},
+    record_batch::RecordBatch,
+    util::display::array_value_to_string,
+};
+use chrono::NaiveDateTime;
+use parquet::{
+    arrow::{arrow_writer::ArrowWriter, ArrowReader, ParquetFileArrowReader},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+    },
+};
+use std::{
+    collections::HashMap,
+    fs::{rename, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use crate::{
+    error::Error,
+    flatfile::parse_datetime,
+};
+
+/// Rows read from a parquet file at a time
+const READ_BATCH_SIZE: usize = 8192;
+
+/// Column whose latest value wins when primary keys collide
+pub const LASTCHANGED: &str = "LASTCHANGED";
+
+/// Which of two rows with the same primary key is kept, the greatest wins
+///
+/// Rows are ordered by `LASTCHANGED`, then by the publish time of their file
+/// and finally by the order they were pushed, so a re-issued file replaces
+/// the rows of an earlier one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Precedence {
+    last_changed: Option<i64>,
+    published: Option<NaiveDateTime>,
+    sequence: usize,
+}
+
+/// A value of a primary key column
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum KeyValue {
+    Null,
+    Integer(i64),
+    /// Bits of a float, keys are compared exactly
+    Float(u64),
+    String(String),
+}
+
+/// Reads integer, temporal and float key columns as numbers rather than
+/// formatting every value
+fn key_column(column: &ArrayRef) -> Result<ArrayRef, Error> {
+    use DataType::*;
+    match column.data_type() {
+        Int8 | Int16 | Int32 | UInt8 | UInt16 | UInt32 | Date32 | Date64 | Timestamp(_, _) =>
+            cast(column, &Int64).map_err(Error::Arrow),
+        _ => Ok(column.clone()),
+    }
+}
+
+/// Reads `LASTCHANGED` as a number to compare, timestamps at any unit are
+/// compared in nanoseconds
+fn last_changed_column(column: &ArrayRef) -> Result<ArrayRef, Error> {
+    use DataType::*;
+    let column = match column.data_type() {
+        Timestamp(TimeUnit::Nanosecond, _) => column.clone(),
+        Timestamp(_, _) => cast(column, &Timestamp(TimeUnit::Nanosecond, None)).map_err(Error::Arrow)?,
+        Date32 | Date64 | Int32 | Int64 => column.clone(),
+        dt => return Err(Error::InvalidPackageDefinition(
+            format!("{} must be a timestamp or date to deduplicate by it, not {:?}", LASTCHANGED, dt)
+        )),
+    };
+    cast(&column, &DataType::Int64).map_err(Error::Arrow)
+}
+
+fn key_value(column: &ArrayRef, row: usize) -> Result<KeyValue, Error> {
+    if column.is_null(row) {
+        return Ok(KeyValue::Null)
+    }
+    let any = column.as_any();
+    let value = match column.data_type() {
+        DataType::Int64 => KeyValue::Integer(any.downcast_ref::<Int64Array>().expect("Checked data type").value(row)),
+        DataType::Float64 => KeyValue::Float(any.downcast_ref::<Float64Array>().expect("Checked data type").value(row).to_bits()),
+        DataType::Utf8 => KeyValue::String(any.downcast_ref::<StringArray>().expect("Checked data type").value(row).to_string()),
+        _ => KeyValue::String(array_value_to_string(column, row).map_err(Error::Arrow)?),
+    };
+    Ok(value)
+}
+
+/// Finds the latest row for each primary key of the batches pushed to it
+///
+/// Only the key of each row is held, not the rows themselves. Once every
+/// batch has been pushed, `finish` gives a [`DedupFilter`] that drops the
+/// superseded rows when the same batches are passed through it again.
+#[derive(Debug)]
+pub struct Deduplicator {
+    primary_key: Vec<String>,
+    latest: HashMap<Vec<KeyValue>, (Precedence, usize)>,
+    rows: usize,
+}
+
+impl Deduplicator {
+    pub fn new(primary_key: &[&str]) -> Self {
+        Deduplicator {
+            primary_key: primary_key.iter().map(|k| k.to_string()).collect(),
+            latest: HashMap::new(),
+            rows: 0,
+        }
+    }
+
+    /// Adds the rows of a batch, `published` is the publish time of the file
+    /// it's from
+    pub fn push(&mut self, batch: &RecordBatch, published: Option<NaiveDateTime>) -> Result<(), Error> {
+        let schema = batch.schema();
+        let key_columns = self.primary_key.iter()
+            .map(|k| schema.index_of(k)
+                .map_err(|_| Error::MissingColumnHeader(k.clone()))
+                .and_then(|idx| key_column(batch.column(idx))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let last_changed = schema.index_of(LASTCHANGED).ok()
+            .map(|idx| last_changed_column(batch.column(idx)))
+            .transpose()?;
+        let last_changed = last_changed.as_ref()
+            .map(|c| c.as_any().downcast_ref::<Int64Array>().expect("Cast to Int64"));
+        for row in 0..batch.num_rows() {
+            let key = key_columns.iter()
+                .map(|c| key_value(c, row))
+                .collect::<Result<Vec<_>, _>>()?;
+            let sequence = self.rows + row;
+            let precedence = Precedence {
+                last_changed: last_changed.filter(|c| c.is_valid(row)).map(|c| c.value(row)),
+                published,
+                sequence,
+            };
+            match self.latest.get(&key) {
+                Some((existing, _)) if *existing > precedence => (),
+                _ => {
+                    self.latest.insert(key, (precedence, sequence));
+                },
+            }
+        }
+        self.rows += batch.num_rows();
+        Ok(())
+    }
+
+    /// Number of rows that will be kept
+    pub fn len(&self) -> usize {
+        self.latest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_empty()
+    }
+
+    pub fn finish(self) -> DedupFilter {
+        let mut keep = vec![false; self.rows];
+        for (_, row) in self.latest.values() {
+            keep[*row] = true;
+        }
+        DedupFilter { keep, offset: 0 }
+    }
+}
+
+/// Drops the rows a [`Deduplicator`] found to be superseded, from the same
+/// rows pushed to it in the same order (though not necessarily in the same
+/// batches)
+#[derive(Debug)]
+pub struct DedupFilter {
+    keep: Vec<bool>,
+    offset: usize,
+}
+
+impl DedupFilter {
+    /// Number of rows kept out of the next `rows`
+    pub fn kept(&self, rows: usize) -> usize {
+        self.keep.iter()
+            .skip(self.offset)
+            .take(rows)
+            .filter(|k| **k)
+            .count()
+    }
+
+    pub fn skip(&mut self, rows: usize) {
+        self.offset += rows;
+    }
+
+    pub fn filter(&mut self, batch: &RecordBatch) -> Result<RecordBatch, Error> {
+        let keep = self.keep.get(self.offset..self.offset + batch.num_rows())
+            .ok_or(Error::IndexError(self.offset + batch.num_rows()))?;
+        self.offset += batch.num_rows();
+        filter_record_batch(batch, &BooleanArray::from(keep.to_vec()))
+            .map_err(Error::Arrow)
+    }
+}
+
+fn file_reader<P: AsRef<Path>>(path: P) -> Result<SerializedFileReader<File>, Error> {
+    SerializedFileReader::new(File::open(path).map_err(Error::Io)?)
+        .map_err(Error::Parquet)
+}
+
+/// Deduplicates rows across the parquet files of a package, rewriting the
+/// files that had rows superseded by another
+///
+/// Files are read twice, once to find the latest row of each key and again
+/// to rewrite them, so only the keys are held in memory. Files written with
+/// lineage metadata are ranked by their publish time, others by the order
+/// given. Returns the number of rows removed.
+pub fn deduplicate_files<P: AsRef<Path>>(paths: &[P], primary_key: &[&str]) -> Result<usize, Error> {
+    let mut dedup = Deduplicator::new(primary_key);
+    let mut files = Vec::new();
+    for path in paths {
+        let reader = file_reader(path)?;
+        let metadata = reader.metadata().file_metadata().key_value_metadata().clone();
+        let published = metadata.iter()
+            .flatten()
+            .find(|kv| kv.key == "nem_mms.publish_datetime")
+            .and_then(|kv| kv.value.as_deref())
+            .and_then(parse_datetime);
+        let rows = reader.metadata().file_metadata().num_rows() as usize;
+        let mut arrow_reader = ParquetFileArrowReader::new(Arc::new(reader));
+        for batch in arrow_reader.get_record_reader(READ_BATCH_SIZE).map_err(Error::Parquet)? {
+            dedup.push(&batch.map_err(Error::Arrow)?, published)?;
+        }
+        files.push((path.as_ref().to_path_buf(), metadata, rows));
+    }
+
+    let mut filter = dedup.finish();
+    let mut removed = 0;
+    for (path, metadata, rows) in files {
+        let kept = filter.kept(rows);
+        if kept == rows {
+            filter.skip(rows);
+            continue
+        }
+        removed += rows - kept;
+        let mut arrow_reader = ParquetFileArrowReader::new(Arc::new(file_reader(&path)?));
+        let schema = Arc::new(arrow_reader.get_schema().map_err(Error::Parquet)?);
+        // Written alongside and renamed over the original, so an interrupted
+        // run leaves the file as it was
+        let tmp = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let props = WriterProperties::builder()
+            .set_key_value_metadata(metadata)
+            .build();
+        let mut writer = ArrowWriter::try_new(File::create(&tmp).map_err(Error::Io)?, schema, Some(props))
+            .map_err(Error::Parquet)?;
+        for batch in arrow_reader.get_record_reader(READ_BATCH_SIZE).map_err(Error::Parquet)? {
+            let batch = filter.filter(&batch.map_err(Error::Arrow)?)?;
+            writer.write(&batch).map_err(Error::Parquet)?;
+        }
+        writer.close().map_err(Error::Parquet)?;
+        rename(&tmp, &path).map_err(Error::Io)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::{ArrayRef, Float64Array, StringArray, TimestampMillisecondArray, TimestampSecondArray},
+        datatypes::{Field, Schema},
+    };
+    use chrono::NaiveDate;
+    use std::sync::Arc;
+
+    fn batch(regions: Vec<&str>, prices: Vec<f64>, last_changed: Vec<Option<i64>>) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("REGIONID", DataType::Utf8, false),
+            Field::new("RRP", DataType::Float64, true),
+            Field::new(LASTCHANGED, DataType::Timestamp(TimeUnit::Second, None), true),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(regions)),
+            Arc::new(Float64Array::from(prices)),
+            Arc::new(TimestampSecondArray::from_opt_vec(last_changed, None)),
+        ];
+        RecordBatch::try_new(Arc::new(schema), columns).unwrap()
+    }
+
+    fn prices(batches: &[RecordBatch]) -> Vec<f64> {
+        batches.iter()
+            .flat_map(|b| {
+                let prices = b.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+                (0..b.num_rows()).map(|i| prices.value(i)).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn latest_rows_kept() {
+        let published = NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 40, 0);
+        let mut dedup = Deduplicator::new(&["REGIONID"]);
+        let first = batch(vec!["NSW1", "QLD1", "SA1"], vec![1.0, 2.0, 3.0], vec![Some(10), Some(10), None]);
+        dedup.push(&first, Some(published)).unwrap();
+        // NSW1 changed later, QLD1 is older and SA1 is from a later file
+        let republished = published + chrono::Duration::minutes(5);
+        let second = batch(vec!["NSW1", "QLD1", "SA1"], vec![4.0, 5.0, 6.0], vec![Some(20), Some(5), None]);
+        dedup.push(&second, Some(republished)).unwrap();
+        assert_eq!(dedup.len(), 3);
+        let mut filter = dedup.finish();
+        assert_eq!(filter.kept(6), 3);
+        let kept = vec![filter.filter(&first).unwrap(), filter.filter(&second).unwrap()];
+        assert_eq!(prices(&kept), [2.0, 4.0, 6.0]);
+
+        // Repeated rows keep the last one pushed
+        let mut dedup = Deduplicator::new(&["REGIONID"]);
+        let first = batch(vec!["NSW1"], vec![1.0], vec![Some(10)]);
+        let second = batch(vec!["NSW1"], vec![2.0], vec![Some(10)]);
+        dedup.push(&first, None).unwrap();
+        dedup.push(&second, None).unwrap();
+        let mut filter = dedup.finish();
+        let kept = vec![filter.filter(&first).unwrap(), filter.filter(&second).unwrap()];
+        assert_eq!(prices(&kept), [2.0]);
+        assert!(filter.filter(&first).is_err());
+
+        let mut dedup = Deduplicator::new(&["DUID"]);
+        assert!(matches!(dedup.push(&batch(vec![], vec![], vec![]), None), Err(Error::MissingColumnHeader(_))));
+    }
+
+    #[test]
+    fn millisecond_last_changed() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("REGIONID", DataType::Utf8, false),
+            Field::new("RRP", DataType::Float64, true),
+            Field::new(LASTCHANGED, DataType::Timestamp(TimeUnit::Millisecond, None), true),
+        ]));
+        let batch = |price: f64, last_changed: i64| {
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(StringArray::from(vec!["NSW1"])),
+                Arc::new(Float64Array::from(vec![price])),
+                Arc::new(TimestampMillisecondArray::from_opt_vec(vec![Some(last_changed)], None)),
+            ];
+            RecordBatch::try_new(schema.clone(), columns).unwrap()
+        };
+        // Within the same second, the earlier push changed later
+        let first = batch(1.0, 10_500);
+        let second = batch(2.0, 10_100);
+        let mut dedup = Deduplicator::new(&["REGIONID"]);
+        dedup.push(&first, None).unwrap();
+        dedup.push(&second, None).unwrap();
+        let mut filter = dedup.finish();
+        let kept = vec![filter.filter(&first).unwrap(), filter.filter(&second).unwrap()];
+        assert_eq!(prices(&kept), [1.0]);
+    }
+}
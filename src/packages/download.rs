@@ -0,0 +1,447 @@
+use reqwest::{StatusCode, Url};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, self},
+    fs::{metadata, remove_file, rename, OpenOptions},
+    io::{copy, ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+use crate::{
+    error::Error,
+    packages::transport::{FileTransport, HttpTransport, Response, Transport},
+};
+
+/// Longest wait between retries, however many there have been
+/// Longest wait between retries
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Lowest rate limit, one request an hour, slower limits are raised to it
+pub const MIN_RATE_LIMIT: f64 = 1.0 / 3600.0;
+
+pub const DEFAULT_USER_AGENT: &str = concat!("nem-mms/", env!("CARGO_PKG_VERSION"));
+
+pub const DEFAULT_BASE_URL: &str = "https://www.nemweb.com.au";
+
+/// How files are downloaded: how many at once, how quickly, and how
+/// failures are retried and partial downloads resumed
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadOptions {
+    /// Retries after the first attempt for dropped connections and 5xx or
+    /// 429 responses, other failures aren't retried
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+    /// Continue a partial download with a Range request rather than starting
+    /// it over
+    pub resume: bool,
+    /// Files downloaded at once
+    pub jobs: usize,
+    /// Most requests per second made to each host, unlimited if `None`
+    pub rate_limit: Option<f64>,
+    pub user_agent: String,
+    /// Where nemweb's pages are fetched from, nemweb itself if `None`. A
+    /// `file://` url reads a local mirror directory.
+    pub base_url: Option<String>,
+    /// Proxy for every request, rather than one from the environment
+    pub proxy: Option<String>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            resume: true,
+            jobs: 4,
+            rate_limit: Some(2.0),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            base_url: None,
+            proxy: None,
+        }
+    }
+}
+
+impl DownloadOptions {
+    pub fn base_url(&self) -> &str {
+        self.base_url.as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+    }
+
+    fn wait(&self, attempt: u32) {
+        let backoff = self.backoff.checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF);
+        sleep(backoff)
+    }
+
+    /// Runs `f` until it succeeds, fails with an error that isn't transient
+    /// or runs out of retries, passing each failure that's retried to
+    /// `retried`
+    fn retry<T, F, R>(&self, mut f: F, mut retried: R) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+        R: FnMut(Error),
+    {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    retried(e);
+                    self.wait(attempt);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+fn reqwest_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || e.is_decode()
+}
+
+fn is_transient(e: &Error) -> bool {
+    let retry_status = |status: &StatusCode| status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS;
+    match e {
+        Error::Reqwest(e) => reqwest_transient(e),
+        // Reading a response body fails with an io error
+        Error::Io(e) => e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+            .map(reqwest_transient)
+            .unwrap_or(false)
+            || matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::TimedOut | ErrorKind::UnexpectedEof),
+        Error::FailedToDownload { status, .. } | Error::FailedToFetch { status, .. } => retry_status(status),
+        _ => false,
+    }
+}
+
+/// Path a download is written to until it's complete
+fn partial_path(path: &Path) -> PathBuf {
+    let mut fname = path.file_name().map(|s| s.to_os_string()).unwrap_or_default();
+    fname.push(".part");
+    path.with_file_name(fname)
+}
+
+/// Makes the requests of [`DownloadOptions`] through a [`Transport`]
+///
+/// Clones share one transport and rate limit, so a downloader can be handed
+/// to as many threads as needed without any of them exceeding the limit.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    options: DownloadOptions,
+    transport: Arc<dyn Transport>,
+    /// Earliest time the next request can be made to each host
+    next_request: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Failures that were retried and the urls they were for, until they're
+    /// taken into a [`DownloadSummary`]
+    retried: Arc<Mutex<Vec<(String, Error)>>>,
+}
+
+impl Downloader {
+    /// Reads a local mirror if the base url is a `file://` one, and makes
+    /// http requests otherwise
+    pub fn new(options: DownloadOptions) -> Result<Self, Error> {
+        let transport: Arc<dyn Transport> = if options.base_url().starts_with("file:") {
+            Arc::new(FileTransport)
+        } else {
+            Arc::new(HttpTransport::new(&options.user_agent, options.proxy.as_deref())?)
+        };
+        Ok(Downloader::with_transport(options, transport))
+    }
+
+    pub fn with_transport(options: DownloadOptions, transport: Arc<dyn Transport>) -> Self {
+        Downloader {
+            options,
+            transport,
+            next_request: Arc::new(Mutex::new(HashMap::new())),
+            retried: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn options(&self) -> &DownloadOptions {
+        &self.options
+    }
+
+    /// Waits until a request can be made to the url's host without going
+    /// over the rate limit, urls without a host like local files aren't
+    /// limited
+    fn wait_turn(&self, url: &str) {
+        let interval = match self.options.rate_limit {
+            Some(rate) if rate > 0.0 => Duration::from_secs_f64(1.0 / rate.max(MIN_RATE_LIMIT)),
+            _ => return,
+        };
+        let host = match Url::parse(url).ok().and_then(|u| u.host_str().map(|h| h.to_string())) {
+            Some(host) => host,
+            None => return,
+        };
+        let turn = {
+            let mut next_request = self.next_request.lock()
+                .expect("Rate limit lock poisoned");
+            let now = Instant::now();
+            let turn = next_request.get(&host).map(|next| (*next).max(now)).unwrap_or(now);
+            next_request.insert(host, turn + interval);
+            turn
+        };
+        let now = Instant::now();
+        if turn > now {
+            sleep(turn - now)
+        }
+    }
+
+    fn get(&self, url: &str, offset: u64) -> Result<Response, Error> {
+        self.wait_turn(url);
+        self.transport.get(url, offset)
+    }
+
+    /// Retries `f` as set by the options, keeping the failures that were
+    /// retried for [`Downloader::take_retried`]
+    fn retry<T, F>(&self, url: &str, f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>
+    {
+        self.options.retry(f, |e| {
+            self.retried.lock()
+                .expect("Retry log lock poisoned")
+                .push((url.to_string(), e))
+        })
+    }
+
+    /// Failures that were retried since this was last called, by this
+    /// downloader or any of its clones
+    pub fn take_retried(&self) -> Vec<(String, Error)> {
+        let mut retried = self.retried.lock()
+            .expect("Retry log lock poisoned");
+        std::mem::take(&mut *retried)
+    }
+
+    /// Downloads a file into memory
+    pub fn fetch_bytes(&self, url: &str) -> Result<Vec<u8>, Error> {
+        self.retry(url, || {
+            let mut response = self.get(url, 0)?;
+            if response.status.is_success() {
+                let mut bytes = Vec::new();
+                response.body.read_to_end(&mut bytes)
+                    .map_err(Error::Io)?;
+                Ok(bytes)
+            } else {
+                Err(Error::FailedToFetch { url: url.to_string(), status: response.status })
+            }
+        })
+    }
+
+    /// Downloads a file to `path`, which must not exist unless `replace` is
+    /// set, returning its size
+    ///
+    /// The file is written to `[path].part` and only renamed to `path` once
+    /// it's complete, so a dropped connection never leaves a truncated file
+    /// behind. A `.part` file left by an earlier run is resumed from where it
+    /// stopped, if the server supports Range requests and resuming is turned
+    /// on.
+    pub fn download_file<P: AsRef<Path>>(&self, url: &str, path: P, replace: bool) -> Result<u64, Error> {
+        let path = path.as_ref();
+        if !replace && path.exists() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.to_string_lossy())
+            )))
+        }
+        let partial = partial_path(path);
+        if !self.options.resume && partial.exists() {
+            remove_file(&partial).map_err(Error::Io)?;
+        }
+        let bytes = self.retry(url, || {
+            let offset = match self.options.resume {
+                true => metadata(&partial).map(|m| m.len()).unwrap_or(0),
+                false => 0,
+            };
+            let mut response = self.get(url, offset)?;
+            if response.status == StatusCode::RANGE_NOT_SATISFIABLE {
+                // The partial file doesn't match the remote one, start over
+                remove_file(&partial).map_err(Error::Io)?;
+                response = self.get(url, 0)?;
+            }
+            let mut file = match response.status {
+                StatusCode::PARTIAL_CONTENT => OpenOptions::new()
+                    .append(true)
+                    .open(&partial)
+                    .map_err(Error::Io)?,
+                status if status.is_success() => OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&partial)
+                    .map_err(Error::Io)?,
+                status => return Err(Error::FailedToDownload {
+                    url: url.to_string(),
+                    path: path.to_path_buf(),
+                    status,
+                }),
+            };
+            copy(&mut response.body, &mut file)
+                .map_err(Error::Io)?;
+            metadata(&partial).map(|m| m.len()).map_err(Error::Io)
+        })?;
+        rename(&partial, path).map_err(Error::Io)?;
+        Ok(bytes)
+    }
+
+    /// Downloads each url to its path, `jobs` at a time
+    ///
+    /// A line is printed as each file finishes, and failures, including
+    /// those that were retried, are gathered into the summary rather than
+    /// stopping the other downloads.
+    pub fn download_files(&self, files: Vec<(String, PathBuf)>, replace: bool) -> DownloadSummary {
+        let (tx, rx) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(files.into_iter()));
+        let workers = (0..self.options.jobs.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let tx = tx.clone();
+                let downloader = self.clone();
+                thread::spawn(move || loop {
+                    let next = queue.lock()
+                        .expect("Download queue lock poisoned")
+                        .next();
+                    let (url, path) = match next {
+                        Some(file) => file,
+                        None => break,
+                    };
+                    let result = downloader.download_file(&url, &path, replace);
+                    if tx.send((url, result)).is_err() {
+                        break
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+
+        let mut summary = DownloadSummary::default();
+        for (url, result) in rx {
+            match result {
+                Ok(bytes) => {
+                    println!("Fetched {} ({} bytes)", url, bytes);
+                    summary.downloaded += 1;
+                    summary.bytes += bytes;
+                },
+                Err(e) => {
+                    println!("Failed to fetch {}", url);
+                    summary.failures.push((url, e));
+                },
+            }
+        }
+        for worker in workers {
+            if let Err(panic) = worker.join() {
+                std::panic::resume_unwind(panic)
+            }
+        }
+        summary.retried = self.take_retried();
+        summary
+    }
+}
+
+/// What a batch of downloads did
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    pub bytes: u64,
+    pub failures: Vec<(String, Error)>,
+    /// Failures that were retried, whether or not a retry succeeded
+    pub retried: Vec<(String, Error)>,
+}
+
+impl DownloadSummary {
+    pub fn merge(&mut self, other: DownloadSummary) {
+        self.downloaded += other.downloaded;
+        self.bytes += other.bytes;
+        self.failures.extend(other.failures);
+        self.retried.extend(other.retried);
+    }
+
+    /// Fails if any download did, so scheduled runs notice
+    pub fn into_result(self) -> Result<(), Error> {
+        match self.failures.len() {
+            0 => Ok(()),
+            failed => Err(Error::DownloadsFailed(failed)),
+        }
+    }
+}
+
+impl Display for DownloadSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} downloaded ({} bytes), {} retries, {} failed", self.downloaded, self.bytes, self.retried.len(), self.failures.len())?;
+        for (url, e) in self.retried.iter() {
+            write!(f, "\n    {}: {}, retried", url, e)?;
+        }
+        for (url, e) in self.failures.iter() {
+            write!(f, "\n    {}: {}", url, e)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::transport::MemoryTransport;
+
+    #[test]
+    fn test_partial_download() {
+        assert_eq!(
+            partial_path(Path::new("downloads/PUBLIC_DVD_DISPATCH_UNIT_SCADA_202111010000.zip")),
+            PathBuf::from("downloads/PUBLIC_DVD_DISPATCH_UNIT_SCADA_202111010000.zip.part")
+        );
+        let status = |status| Error::FailedToFetch { url: String::new(), status };
+        assert!(is_transient(&status(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_transient(&status(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_transient(&status(StatusCode::NOT_FOUND)));
+
+        let options = DownloadOptions { retries: 2, backoff: Duration::from_millis(1), ..DownloadOptions::default() };
+        let mut attempts = 0;
+        let mut retried = 0;
+        let result: Result<(), Error> = options.retry(|| {
+            attempts += 1;
+            Err(status(StatusCode::BAD_GATEWAY))
+        }, |_| retried += 1);
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+        assert_eq!(retried, 2);
+    }
+
+    #[test]
+    fn resume_partial_download() {
+        let dir = std::env::temp_dir().join(format!("nem-mms-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("PUBLIC_DVD_DISPATCH_UNIT_SCADA_202111010000.zip");
+        std::fs::write(partial_path(&path), b"abc").unwrap();
+
+        let url = "https://www.nemweb.com.au/PUBLIC_DVD_DISPATCH_UNIT_SCADA_202111010000.zip";
+        let transport = Arc::new(MemoryTransport::new());
+        transport.insert(url, "abcdef");
+        transport.fail(url, StatusCode::SERVICE_UNAVAILABLE);
+        let options = DownloadOptions { backoff: Duration::from_millis(1), rate_limit: None, ..DownloadOptions::default() };
+        let downloader = Downloader::with_transport(options, transport.clone());
+        assert_eq!(downloader.download_file(url, &path, false).unwrap(), 6);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+        assert!(!partial_path(&path).exists());
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(downloader.take_retried().len(), 1);
+        assert!(downloader.take_retried().is_empty());
+        assert!(downloader.download_file(url, &path, false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_per_host() {
+        let options = DownloadOptions { rate_limit: Some(20.0), ..DownloadOptions::default() };
+        let downloader = Downloader::new(options).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            downloader.wait_turn("https://www.nemweb.com.au/Reports/Current/");
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
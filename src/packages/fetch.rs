@@ -1,13 +1,25 @@
-use scraper::{Html, Selector};
-use reqwest::blocking::get;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use reqwest::StatusCode;
+use scraper::Html;
 use std::{
-    io::{Write, stdout},
-    path::Path,
-    fs::OpenOptions,
+    collections::HashSet,
+    io::Cursor,
+    path::{Path, PathBuf},
+    fs::{metadata, remove_file, File},
+    sync::Arc,
+    fmt::{Display, Formatter, self},
 };
+use zip::read::ZipArchive;
 use crate::{
-    packages::Package,
+    packages::{
+        Package,
+        download::{DownloadOptions, DownloadSummary, Downloader},
+        listing::{percent_decode, NemwebListing, RemoteFile},
+        transport::Transport,
+    },
     error::Error,
+    flatfile::{FlatFileTable, ReadOptions},
+    zip::read_zip_tables,
 };
 
 fn package_url_part(package: &Package) -> Option<&'_ str> {
@@ -19,6 +31,7 @@ fn package_url_part(package: &Package) -> Option<&'_ str> {
         RooftopPvActual => Some("ROOFTOP_PV/ACTUAL"),
         RooftopPvForecast => Some("ROOFTOP_PV/FORECAST"),
         DispatchPrice => Some("DispatchIS_Reports"),
+        Generated(_) | Custom(_) => None,
     }
 }
 
@@ -107,6 +120,9 @@ pub struct HistoricDataDownloader {
     pub package: Package,
     pub year: Year,
     pub month: Month,
+    pub download: DownloadOptions,
+    /// Used instead of the one the download options would pick
+    pub transport: Option<Arc<dyn Transport>>,
 }
 
 impl HistoricDataDownloader {
@@ -114,10 +130,24 @@ impl HistoricDataDownloader {
         HistoricDataDownloader {
             package,
             year: Year::default(),
-            month: Month::default()
+            month: Month::default(),
+            download: DownloadOptions::default(),
+            transport: None,
         }
     }
 
+    pub fn with_download_options(self, download: DownloadOptions) -> Self {
+        HistoricDataDownloader { download, ..self }
+    }
+
+    pub fn with_transport(self, transport: Arc<dyn Transport>) -> Self {
+        HistoricDataDownloader { transport: Some(transport), ..self }
+    }
+
+    fn downloader(&self) -> Result<Downloader, Error> {
+        downloader(&self.download, &self.transport)
+    }
+
     pub fn with_year(self, year: &str) -> Result<Self, Error> {
         let year = Year::from_str(year)
             .ok_or(Error::InvalidYear(year.to_string()))?;
@@ -130,46 +160,88 @@ impl HistoricDataDownloader {
         Ok(HistoricDataDownloader { month, ..self })
     }
 
-    pub fn url(&self) -> Option<String> {
-        use Package::*;
-        let filename = match &self.package {
-            DispatchUnitScada => Some(format!("PUBLIC_DVD_DISPATCH_UNIT_SCADA_{}{}010000.zip", &self.year.as_str(), &self.month.as_str())),
-            DispatchNegativeResidue => None,
-            DispatchLocalPrice => None,
-            RooftopPvActual => Some(format!("PUBLIC_DVD_ROOFTOP_PV_ACTUAL_{}{}010000.zip", &self.year.as_str(), &self.month.as_str())),
-            RooftopPvForecast => Some(format!("PUBLIC_DVD_ROOFTOP_PV_FORECAST_{}{}010000.zip", &self.year.as_str(), &self.month.as_str())),
-            DispatchPrice => None,
-            
-        }?;
-        let url = format!("Data_Archive/Wholesale_Electricity/MMSDM/{}/MMSDM_{}_{}/MMSDM_Historical_Data_SQLLoader/DATA/{}", &self.year.as_str(), &self.year.as_str(), &self.month.as_str(), filename);
-        Some(url)
-    }
-
-    pub fn download<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
-        let url = self.url()
-            .map(|u| format!("{}/{}", BASE_URL, u))
-            .ok_or(Error::UnsupportedFetchReport(self.package.clone()))?;
-        print!("Fetching {} ... ", &url);
-        stdout().flush().map_err(Error::Io)?;
-        let fname = url.split('/')
-            .last()
-            .ok_or(Error::ZipUrlNoFilename(url.to_string()))?;
-        let path = path.as_ref().join(fname);
-        download_file(&url, path)
-            .map(|b| print!(" success ({} bytes)\n", b))
-            .or_else(|e| {
-                print!(" failed\n");
-                match e {
-                    Error::FailedToDownload { url, path, status } => {
-                        eprintln!("Failed to download {} to {:#?}. Got status {}", url, path.as_os_str(), status);
-                        Ok(())
-                    },
-                    _ => Err(e)
-            }})?;
-        Ok(())
+    /// The month's directory of the MMSDM archive
+    pub fn url(&self) -> String {
+        format!(
+            "Data_Archive/Wholesale_Electricity/MMSDM/{}/MMSDM_{}_{}",
+            self.year.as_str(), self.year.as_str(), self.month.as_str()
+        )
+    }
+
+    /// Every file of the package's table in the month's DATA listing, in
+    /// part order for tables split across several files
+    ///
+    /// The first of [`MMSDM_DATA_DIRS`] that exists is listed, as the month
+    /// may have been published under an older layout.
+    pub fn listing(&self) -> Result<NemwebListing, Error> {
+        self.listing_with(&self.downloader()?)
+    }
+
+    fn listing_with(&self, downloader: &Downloader) -> Result<NemwebListing, Error> {
+        let table = self.package.mms_table()
+            .ok_or(Error::UnsupportedFetchReport(self.package))?;
+        let base_url = self.download.base_url();
+        let mut not_found = None;
+        for dir in MMSDM_DATA_DIRS {
+            let url = format!("{}/{}/{}", base_url, self.url(), dir);
+            let document = match downloader.fetch_bytes(&url) {
+                Ok(document) => document,
+                Err(Error::FailedToFetch { url, status }) if status == StatusCode::NOT_FOUND => {
+                    not_found = Some(Error::FailedToFetch { url, status });
+                    continue
+                },
+                Err(e) => return Err(e),
+            };
+            let document = Html::parse_document(&String::from_utf8_lossy(&document));
+            let mut listing = NemwebListing::from_html(&url, base_url, &document)?;
+            listing.files.retain(|f| f.report.as_deref() == Some(table));
+            listing.files.sort_by_key(|f| f.part);
+            return Ok(listing)
+        }
+        Err(not_found.expect("No MMSDM DATA directories to try"))
+    }
+
+    /// The package's files in the listing, or an error if it has none
+    fn files(&self, downloader: &Downloader) -> Result<Vec<RemoteFile>, Error> {
+        let listing = self.listing_with(downloader)?;
+        if listing.files.is_empty() {
+            let table = self.package.mms_table().unwrap_or_default().to_string();
+            return Err(Error::NoHistoricData { table, url: listing.url })
+        }
+        Ok(listing.files)
+    }
+
+    pub fn download<P: AsRef<Path>>(&self, path: P) -> Result<DownloadSummary, Error> {
+        let downloader = self.downloader()?;
+        let files = self.files(&downloader)?
+            .into_iter()
+            .map(|f| (f.url, path.as_ref().join(f.name)))
+            .collect();
+        Ok(downloader.download_files(files, false))
+    }
+
+    /// Streams the tables of the month's zips into `f`, unless `keep_zips`
+    /// names a directory to save them in, each zip is only kept on disk
+    /// while it's read
+    pub fn stream<F>(&self, options: &ReadOptions, keep_zips: Option<&Path>, f: &mut F) -> Result<DownloadSummary, Error>
+    where
+        F: FnMut(FlatFileTable) -> Result<(), Error>
+    {
+        let downloader = self.downloader()?;
+        let mut summary = DownloadSummary::default();
+        for file in self.files(&downloader)? {
+            summary.merge(stream_zip_file(&file.url, &downloader, options, keep_zips, f)?);
+        }
+        Ok(summary)
     }
 }
 
+/// Directories under an MMSDM month holding its table files, newest layout
+/// first
+pub const MMSDM_DATA_DIRS: &[&str] = &[
+    "MMSDM_Historical_Data_SQLLoader/DATA",
+    "MMSDM_Historical_Data_SQLLoader/PREVIOUS/DATA",
+];
 
 #[derive(Debug)]
 pub enum Archive  {
@@ -194,17 +266,111 @@ impl Archive {
     }
 }
 
-const BASE_URL: &'static str = "https://www.nemweb.com.au";
+/// Datetime of a part of a nemweb file name, either `YYYYMMDD` for the daily
+/// files of the Archive or `YYYYMMDDHHMM[SS]` for the interval files of
+/// Current, and whether it's a whole day
+pub fn filename_part_datetime(part: &str) -> Option<(NaiveDateTime, bool)> {
+    if !part.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    match part.len() {
+        8 => NaiveDate::parse_from_str(part, "%Y%m%d").ok()
+            .map(|d| (d.and_hms(0, 0, 0), true)),
+        12 => NaiveDateTime::parse_from_str(part, "%Y%m%d%H%M").ok()
+            .map(|t| (t, false)),
+        14 => NaiveDateTime::parse_from_str(part, "%Y%m%d%H%M%S").ok()
+            .map(|t| (t, false)),
+        _ => None,
+    }
+}
+
+/// First and last datetimes a nemweb file could hold, from the datetime in
+/// its name
+///
+/// Daily files run to 04:00 the next day, since many reports follow the
+/// market day.
+pub fn filename_datetimes(fname: &str) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let stem = fname.split('.').next()?;
+    stem.split('_')
+        .find_map(filename_part_datetime)
+        .map(|(dt, daily)| match daily {
+            true => (dt, dt + Duration::days(1) + Duration::hours(4)),
+            false => (dt, dt),
+        })
+}
+
+/// Window of datetimes to fetch files for, either end may be open and both
+/// are inclusive
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DateTimeRange {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+impl DateTimeRange {
+    /// Whether the file could hold datetimes in the window, files without a
+    /// datetime in their name are always kept
+    pub fn contains_file(&self, fname: &str) -> bool {
+        match filename_datetimes(fname) {
+            Some((first, last)) => self.from.map(|from| last >= from).unwrap_or(true)
+                && self.to.map(|to| first <= to).unwrap_or(true),
+            None => true,
+        }
+    }
+}
+
+/// What a [`NemwebScraper::sync`] did
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub downloads: DownloadSummary,
+    pub skipped: usize,
+    pub deleted: usize,
+}
+
+impl Display for SyncSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} up to date, {} removed, {}", self.skipped, self.deleted, self.downloads)
+    }
+}
 
 #[derive(Debug)]
 pub struct NemwebScraper {
     pub package: Package,
-    pub archive: Archive
+    pub archive: Archive,
+    pub range: DateTimeRange,
+    pub download: DownloadOptions,
+    /// Used instead of the one the download options would pick
+    pub transport: Option<Arc<dyn Transport>>,
 }
 
 impl NemwebScraper {
     pub fn new(package: Package, archive: Archive) -> Self {
-        NemwebScraper { package, archive }
+        NemwebScraper {
+            package,
+            archive,
+            range: DateTimeRange::default(),
+            download: DownloadOptions::default(),
+            transport: None,
+        }
+    }
+
+    pub fn with_download_options(self, download: DownloadOptions) -> Self {
+        NemwebScraper { download, ..self }
+    }
+
+    pub fn with_transport(self, transport: Arc<dyn Transport>) -> Self {
+        NemwebScraper { transport: Some(transport), ..self }
+    }
+
+    /// A downloader for the scraper's requests, share it between them so
+    /// they share the rate limit
+    pub fn downloader(&self) -> Result<Downloader, Error> {
+        downloader(&self.download, &self.transport)
+    }
+
+    /// Only fetch the files covering this window
+    pub fn with_range(self, range: DateTimeRange) -> Self {
+        NemwebScraper { range, ..self }
     }
 
     pub fn url(&self) -> Option<String> {
@@ -212,78 +378,206 @@ impl NemwebScraper {
             .map(|p| format!("Reports/{}/{}", self.archive.url_part(), p))
     }
 
-    fn fetch_html_document(&self) -> Result<Html, Error> {
-        let url = self.url()
-            .map(|u| format!("{}/{}", BASE_URL, u))
-            .ok_or(Error::UnsupportedFetchReport(self.package.clone()))?;
-        let document = get(url)
-            .map_err(Error::Reqwest)?
-            .text()
-            .map_err(Error::Reqwest)?;
+    fn listing_url(&self) -> Result<String, Error> {
+        self.url()
+            .map(|u| format!("{}/{}", self.download.base_url(), u))
+            .ok_or(Error::UnsupportedFetchReport(self.package.clone()))
+    }
+
+    /// Every zip on the package's page, whatever the scraper's range
+    pub fn listing(&self) -> Result<NemwebListing, Error> {
+        self.listing_with(&self.downloader()?)
+    }
+
+    fn listing_with(&self, downloader: &Downloader) -> Result<NemwebListing, Error> {
+        let document = self.fetch_html_document(downloader)?;
+        NemwebListing::from_html(&self.listing_url()?, self.download.base_url(), &document)
+    }
+
+    fn fetch_html_document(&self, downloader: &Downloader) -> Result<Html, Error> {
+        let url = self.listing_url()?;
+        let document = downloader.fetch_bytes(&url)?;
+        let document = String::from_utf8_lossy(&document);
         let document = Html::parse_document(&document);
         Ok(document)
     }
 
-    fn find_all_urls<'a>(&'a self, document: &'a Html) -> Result<Vec<&'a str>, Error> {
-        let selector = Selector::parse("a")
-            .map_err(|_| Error::ScraperError)?;
-        let zip_links: Vec<&str> = document.select(&selector)
-            .filter_map(|eref| eref.value()
-                 .attr("href")
-                 .and_then(|h| if h.ends_with(".zip") { Some(h) } else { None }))
-            .collect();
-        Ok(zip_links)
-    }
-
-    pub fn download_all<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
-        let document = self.fetch_html_document()?;
-        let zip_urls = self.find_all_urls(&document)?;
-        for url in zip_urls {
-            print!("Fetching {} ... ", &url);
-            stdout().flush().map_err(Error::Io)?;
-            let fname = url.split('/').last()
-                .ok_or(Error::ZipUrlNoFilename(url.to_string()))?;
-            let target_path = path.as_ref().join(fname);
-            let full_url = format!("{}{}", BASE_URL, url);
-            download_file(&full_url, target_path)
-                .map(|b| print!(" success ({} bytes)\n", b))
-                .or_else(|e| {
-                    print!(" failed\n");
-                    match e {
-                        Error::FailedToDownload { url, path, status } => {
-                            eprintln!("Failed to download {} to {:#?}. Got status {}", url, path.as_os_str(), status);
-                            Ok(())
-                        },
-                        _ => Err(e)
-                }})?;
+    /// Full urls of every zip listed on the package's page within the
+    /// scraper's range
+    pub fn zip_urls(&self) -> Result<Vec<String>, Error> {
+        self.zip_urls_with(&self.downloader()?)
+    }
+
+    pub fn zip_urls_with(&self, downloader: &Downloader) -> Result<Vec<String>, Error> {
+        let listing = self.listing_with(downloader)?;
+        Ok(listing.files_in(&self.range).map(|f| f.url.clone()).collect())
+    }
+
+    /// Streams the tables of every zip on the package's page into `f`, see
+    /// [`stream_zip`]
+    pub fn stream_all<F>(&self, options: &ReadOptions, keep_zips: Option<&Path>, f: &mut F) -> Result<DownloadSummary, Error>
+    where
+        F: FnMut(FlatFileTable) -> Result<(), Error>
+    {
+        let downloader = self.downloader()?;
+        let mut summary = DownloadSummary::default();
+        for url in self.zip_urls_with(&downloader)? {
+            summary.merge(stream_zip(&url, &downloader, options, keep_zips, f)?);
+        }
+        Ok(summary)
+    }
+
+    /// Mirrors the package's page into `path`, downloading only the zips
+    /// that are missing or whose size doesn't match the listing
+    ///
+    /// With `delete`, local zips that are no longer listed are removed. Zips
+    /// outside of the scraper's range are left alone either way.
+    pub fn sync<P: AsRef<Path>>(&self, path: P, delete: bool) -> Result<SyncSummary, Error> {
+        let downloader = self.downloader()?;
+        let listing = self.listing_with(&downloader)?;
+        let mut summary = SyncSummary::default();
+        let listed = listing.files.iter()
+            .map(|f| f.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut missing = Vec::new();
+        for file in listing.files_in(&self.range) {
+            let target_path = path.as_ref().join(&file.name);
+            let local_size = metadata(&target_path).ok().map(|m| m.len());
+            match (local_size, file.size) {
+                (Some(local), Some(remote)) if local == remote => summary.skipped += 1,
+                // Without a size in the listing a file is assumed complete
+                (Some(_), None) => summary.skipped += 1,
+                _ => missing.push((file.url.clone(), target_path)),
+            }
+        }
+        summary.downloads = downloader.download_files(missing, true);
+        if delete {
+            for entry in path.as_ref().read_dir().map_err(Error::Io)? {
+                let local = entry.map_err(Error::Io)?.path();
+                let fname = match local.file_name().and_then(|s| s.to_str()) {
+                    Some(fname) => fname,
+                    None => continue,
+                };
+                if fname.ends_with(".zip") && self.range.contains_file(fname) && !listed.contains(fname) {
+                    println!("Removing {}", local.to_string_lossy());
+                    remove_file(&local).map_err(Error::Io)?;
+                    summary.deleted += 1;
+                }
+            }
         }
-        Ok(())
+        Ok(summary)
+    }
+
+    /// Downloads every zip on the package's page into `path`, several at a
+    /// time as set by the download options
+    pub fn download_all<P: AsRef<Path>>(&self, path: P) -> Result<DownloadSummary, Error> {
+        let downloader = self.downloader()?;
+        let files = self.zip_urls_with(&downloader)?
+            .into_iter()
+            .map(|url| {
+                let target_path = path.as_ref().join(url_filename(&url)?);
+                Ok((url, target_path))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(downloader.download_files(files, false))
     }
 }
 
-fn download_file<P: AsRef<Path>>(url: &str, path: P) -> Result<u64, Error> {
-    let mut response = get(url)
-        .map_err(Error::Reqwest)?;
-    if response.status().is_success() {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&path)
-            .map_err(Error::Io)?;
-        response.copy_to(&mut file)
-            .map_err(Error::Reqwest)
-    } else {
-        Err(Error::FailedToDownload { 
-            url: url.to_string(), 
-            path: path.as_ref().to_path_buf(),
-            status: response.status(),
-        })
+fn downloader(options: &DownloadOptions, transport: &Option<Arc<dyn Transport>>) -> Result<Downloader, Error> {
+    match transport {
+        Some(transport) => Ok(Downloader::with_transport(options.clone(), transport.clone())),
+        None => Downloader::new(options.clone()),
+    }
+}
+
+/// Last part of a url, the name its file is saved as
+pub fn url_filename(url: &str) -> Result<&str, Error> {
+    url.split('/')
+        .last()
+        .filter(|s| !s.is_empty())
+        .ok_or(Error::ZipUrlNoFilename(url.to_string()))
+}
+
+/// Downloads a zip into memory and streams the tables of its flat files into
+/// `f`, saving the zip in `keep_zips` as well if it's given
+///
+/// Zips that can't be fetched are counted as failed in the summary and
+/// skipped, like they are when downloading.
+pub fn stream_zip<F>(url: &str, downloader: &Downloader, options: &ReadOptions, keep_zips: Option<&Path>, f: &mut F) -> Result<DownloadSummary, Error>
+where
+    F: FnMut(FlatFileTable) -> Result<(), Error>
+{
+    let fname = percent_decode(url_filename(url)?);
+    let fetched = downloader.fetch_bytes(url);
+    let mut summary = DownloadSummary { retried: downloader.take_retried(), ..DownloadSummary::default() };
+    let bytes = match fetched {
+        Ok(bytes) => bytes,
+        Err(e @ Error::FailedToFetch { .. }) => {
+            summary.failures.push((url.to_string(), e));
+            return Ok(summary)
+        },
+        Err(e) => return Err(e),
+    };
+    summary.downloaded += 1;
+    summary.bytes += bytes.len() as u64;
+    if let Some(dir) = keep_zips {
+        std::fs::write(dir.join(&fname), &bytes).map_err(Error::Io)?;
+    }
+    let archive = ZipArchive::new(Cursor::new(bytes)).map_err(Error::Zip)?;
+    read_zip_tables(archive, &fname, options, f)?;
+    Ok(summary)
+}
+
+/// Removes a file when dropped
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
     }
 }
 
+/// Like [`stream_zip`], but downloads the zip to a file rather than into
+/// memory, for MMSDM historic zips that can run to gigabytes
+///
+/// Unless it's saved in `keep_zips` the zip goes to the temporary directory
+/// and is removed once its tables are read. A download that's cut off is
+/// left there as a partial file, and resumed by the next attempt.
+pub fn stream_zip_file<F>(url: &str, downloader: &Downloader, options: &ReadOptions, keep_zips: Option<&Path>, f: &mut F) -> Result<DownloadSummary, Error>
+where
+    F: FnMut(FlatFileTable) -> Result<(), Error>
+{
+    let fname = percent_decode(url_filename(url)?);
+    let (path, _temp) = match keep_zips {
+        Some(dir) => (dir.join(&fname), None),
+        None => {
+            let path = std::env::temp_dir().join(format!("nem-mms-{}", fname));
+            (path.clone(), Some(TempFile(path)))
+        },
+    };
+    let downloaded = downloader.download_file(url, &path, true);
+    let mut summary = DownloadSummary { retried: downloader.take_retried(), ..DownloadSummary::default() };
+    let bytes = match downloaded {
+        Ok(bytes) => bytes,
+        Err(e @ Error::FailedToDownload { .. }) => {
+            summary.failures.push((url.to_string(), e));
+            return Ok(summary)
+        },
+        Err(e) => return Err(e),
+    };
+    summary.downloaded += 1;
+    summary.bytes += bytes;
+    let file = File::open(&path).map_err(Error::Io)?;
+    let archive = ZipArchive::new(file).map_err(Error::Zip)?;
+    read_zip_tables(archive, &fname, options, f)?;
+    Ok(summary)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+    use crate::packages::{download::DEFAULT_BASE_URL, transport::MemoryTransport};
 
     #[test]
     fn test_find_all_urls() {
@@ -309,15 +603,132 @@ mod test {
 </html>
         "#;
         let document = Html::parse_document(html);
-        let nemweb_scraper = NemwebScraper { package: Package::DispatchUnitScada, archive: Archive::Current };
-        let zip_urls = nemweb_scraper.find_all_urls(&document).unwrap();
+        let listing = NemwebListing::from_html("https://www.nemweb.com.au/Reports/Current/Dispatch_SCADA", DEFAULT_BASE_URL, &document).unwrap();
+        let zip_urls = listing.files.iter().map(|f| f.url.as_str()).collect::<Vec<_>>();
         dbg!(&zip_urls);
         let expected = vec![
-            "/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip",
-            "/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251050_0000000354978611.zip",
-            "/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251055_0000000354978803.zip",
-            "/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251100_0000000354979009.zip"
+            "https://www.nemweb.com.au/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip",
+            "https://www.nemweb.com.au/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251050_0000000354978611.zip",
+            "https://www.nemweb.com.au/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251055_0000000354978803.zip",
+            "https://www.nemweb.com.au/Reports/Current/Dispatch_SCADA/PUBLIC_DISPATCHSCADA_202112251100_0000000354979009.zip"
+        ];
+        assert_eq!(zip_urls, expected);
+        let sizes = listing.files.iter().map(|f| f.size).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![Some(3157), Some(3128), Some(3145), Some(3144)]);
+        assert_eq!(listing.files[0].published, Some(NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 40, 0)));
+
+        let range = DateTimeRange {
+            from: Some(NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 50, 0)),
+            to: Some(NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 55, 0)),
+        };
+        let in_range = listing.files_in(&range).map(|f| f.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(in_range, expected[1..3].to_vec());
+    }
+
+    #[test]
+    fn test_sync_with_memory_transport() {
+        let base_url = "https://mirror.example.com/nemweb";
+        let page = format!("{}/Reports/Current/Dispatch_SCADA", base_url);
+        let names = [
+            "PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip",
+            "PUBLIC_DISPATCHSCADA_202112251050_0000000354978611.zip",
         ];
-        assert_eq!(zip_urls, expected)
+        let transport = Arc::new(MemoryTransport::new());
+        let mut html = String::from("<html><body><pre>\n");
+        for (i, name) in names.iter().enumerate() {
+            let body = vec![i as u8; 10 + i];
+            html.push_str(&format!(
+                "Saturday, December 25, 2021 10:4{} AM {} <A HREF=\"/Reports/Current/Dispatch_SCADA/{}\">{}</A><br>\n",
+                i, body.len(), name, name
+            ));
+            transport.insert(format!("{}/{}", page, name), body);
+        }
+        html.push_str("</pre></body></html>\n");
+        transport.insert(page.clone(), html);
+        transport.fail(format!("{}/{}", page, names[1]), StatusCode::BAD_GATEWAY);
+
+        let dir = std::env::temp_dir().join(format!("nem-mms-sync-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("PUBLIC_DISPATCHSCADA_202112251040_0000000354978200.zip"), b"stale").unwrap();
+        let download = DownloadOptions {
+            base_url: Some(base_url.to_string()),
+            backoff: std::time::Duration::from_millis(1),
+            rate_limit: None,
+            ..DownloadOptions::default()
+        };
+        let scraper = NemwebScraper::new(Package::DispatchUnitScada, Archive::Current)
+            .with_download_options(download)
+            .with_transport(transport.clone());
+
+        let summary = scraper.sync(&dir, true).unwrap();
+        assert!(summary.downloads.failures.is_empty());
+        assert_eq!((summary.downloads.downloaded, summary.skipped, summary.deleted), (2, 0, 1));
+        assert_eq!(read(dir.join(names[1])).unwrap(), vec![1; 11]);
+        let summary = scraper.sync(&dir, true).unwrap();
+        assert_eq!((summary.downloads.downloaded, summary.skipped, summary.deleted), (0, 2, 0));
+        // Both listings, both zips and the retried one
+        assert_eq!(transport.requests().len(), 5);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_historic_listing() {
+        let transport = Arc::new(MemoryTransport::new());
+        let historic = |package| HistoricDataDownloader::new(package)
+            .with_year("2021").unwrap()
+            .with_month("11").unwrap()
+            .with_download_options(DownloadOptions { rate_limit: None, ..DownloadOptions::default() })
+            .with_transport(transport.clone());
+        let month = historic(Package::DispatchUnitScada);
+        // Only the older layout exists, so the first directory is a 404
+        let dir = format!("/{}/{}", month.url(), MMSDM_DATA_DIRS[1]);
+        let mut html = String::from("<html><body><pre>\n");
+        let tables = ["DISPATCH_UNIT_SCADA#FILE02", "DISPATCH_UNIT_SCADA#FILE01", "DISPATCHPRICE#FILE01", "NEGATIVE_RESIDUE#FILE01"];
+        for table in tables.iter() {
+            let name = format!("PUBLIC_ARCHIVE#{}#202111010000.zip", table);
+            html.push_str(&format!(
+                "Monday, December 6, 2021 9:05 AM 1024 <A HREF=\"{}/{}\">{}</A><br>\n",
+                dir, name.replace('#', "%23"), name
+            ));
+        }
+        html.push_str("</pre></body></html>\n");
+        transport.insert(format!("{}{}", DEFAULT_BASE_URL, dir), html);
+
+        let listing = month.listing().unwrap();
+        let names = listing.files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            "PUBLIC_ARCHIVE#DISPATCH_UNIT_SCADA#FILE01#202111010000.zip",
+            "PUBLIC_ARCHIVE#DISPATCH_UNIT_SCADA#FILE02#202111010000.zip",
+        ]);
+        assert!(listing.files[0].url.ends_with("PUBLIC_ARCHIVE%23DISPATCH_UNIT_SCADA%23FILE01%23202111010000.zip"));
+        assert_eq!(transport.requests().len(), 2);
+
+        // Packages are found by their MMS table name rather than their own
+        let listing = historic(Package::DispatchNegativeResidue).listing().unwrap();
+        assert_eq!(listing.files.len(), 1);
+        assert_eq!(listing.files[0].report.as_deref(), Some("NEGATIVE_RESIDUE"));
+
+        match historic(Package::DispatchLocalPrice).download(std::env::temp_dir()) {
+            Err(Error::NoHistoricData { table, .. }) => assert_eq!(table, "DISPATCH_LOCAL_PRICE"),
+            result => panic!("Expected no historic data, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_filename_datetimes() {
+        let day = NaiveDate::from_ymd(2021, 11, 17);
+        assert_eq!(
+            filename_datetimes("PUBLIC_DISPATCHSCADA_20211117.zip"),
+            Some((day.and_hms(0, 0, 0), NaiveDate::from_ymd(2021, 11, 18).and_hms(4, 0, 0)))
+        );
+        assert_eq!(
+            filename_datetimes("PUBLIC_ROOFTOP_PV_ACTUAL_MEASUREMENT_20211117103000_0000000354979056.zip"),
+            Some((day.and_hms(10, 30, 0), day.and_hms(10, 30, 0)))
+        );
+        assert_eq!(filename_datetimes("README.zip"), None);
+
+        let range = DateTimeRange { from: Some(NaiveDate::from_ymd(2021, 11, 18).and_hms(3, 0, 0)), to: None };
+        assert!(range.contains_file("PUBLIC_DISPATCHSCADA_20211117.zip"));
+        assert!(!range.contains_file("PUBLIC_DISPATCHSCADA_20211116.zip"));
     }
 }
@@ -0,0 +1,63 @@
+// Generated by `nem-mms generate` from the MMS Data Model, do not edit by hand
+
+use arrow::datatypes::Schema;
+
+/// Tables of the MMS Data Model that aren't built in packages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeneratedPackage {
+}
+
+impl GeneratedPackage {
+    pub const ALL: &[GeneratedPackage] = &[
+    ];
+
+    pub fn all() -> Vec<GeneratedPackage> {
+        GeneratedPackage::ALL.to_vec()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        GeneratedPackage::ALL.iter()
+            .copied()
+            .find(|p| p.name() == name)
+    }
+
+    pub fn from_report_type(report_type: &str, report_subtype: &str) -> Option<Self> {
+        GeneratedPackage::ALL.iter()
+            .copied()
+            .find(|p| p.report_type() == (report_type, report_subtype))
+    }
+
+    /// Package name, the table's name in the MMS Data Model
+    pub fn name(&self) -> &'static str {
+        match *self {
+        }
+    }
+
+    /// Report type and subtype of the table's information records
+    pub fn report_type(&self) -> (&'static str, &'static str) {
+        match *self {
+        }
+    }
+
+    /// Report version the schema was generated for
+    pub fn version(&self) -> u32 {
+        match *self {
+        }
+    }
+
+    pub fn primary_key(&self) -> &'static [&'static str] {
+        match *self {
+        }
+    }
+
+    pub fn schema(&self) -> &'static Schema {
+        match *self {
+        }
+    }
+
+    /// Schema with `NUMBER(p,s)` columns as `Decimal(p, s)` rather than `Float64`
+    pub fn decimal_schema(&self) -> &'static Schema {
+        match *self {
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use chrono::NaiveDateTime;
+use colored::Colorize;
+use prettytable::{
+    Table,
+    row,
+    cell,
+    format::{
+        FormatBuilder,
+        LinePosition,
+        LineSeparator
+    },
+};
+use scraper::{Html, Selector};
+use serde::{Serialize, Serializer};
+use std::fmt::{Display, Formatter, self};
+use crate::{
+    error::Error,
+    flatfile::format_datetime,
+    packages::fetch::{filename_part_datetime, DateTimeRange},
+};
+
+/// A zip in a nemweb directory listing
+///
+/// Nemweb names files `PUBLIC_[REPORT]_[DATETIME]_[SEQUENCE].zip`, or
+/// `PUBLIC_[REPORT]_[DATE].zip` for the daily files of the Archive, and the
+/// parts that can be found are parsed out of the name. MMSDM historic files
+/// are named after their table instead, see [`mmsdm_name_parts`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RemoteFile {
+    pub name: String,
+    pub url: String,
+    /// Size in bytes shown in the listing
+    pub size: Option<u64>,
+    /// When the listing says the file was last written, in NEM market time
+    #[serde(serialize_with = "serialize_datetime")]
+    pub published: Option<NaiveDateTime>,
+    /// Report name, such as `DISPATCHSCADA`
+    pub report: Option<String>,
+    /// Interval datetime in the name, midnight for daily files
+    #[serde(serialize_with = "serialize_datetime")]
+    pub interval: Option<NaiveDateTime>,
+    /// Sequence number at the end of the name of interval files
+    pub sequence: Option<u64>,
+    /// Which part of an MMSDM table split across several files this is
+    pub part: Option<u32>,
+}
+
+fn serialize_datetime<S: Serializer>(dt: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+    match dt {
+        Some(dt) => serializer.serialize_str(&format_datetime(dt)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Table, part number and month of an MMSDM historic file name
+///
+/// These were named `PUBLIC_DVD_[TABLE]_[YYYYMM]010000`, with tables too big
+/// for one file split into `PUBLIC_DVD_[TABLE]_FILE01_[YYYYMM]010000` and so
+/// on. Newer months use `PUBLIC_ARCHIVE#[TABLE]#FILE01#[YYYYMM]010000` for
+/// every table, however many parts it has.
+pub fn mmsdm_name_parts(stem: &str) -> Option<(String, Option<u32>, NaiveDateTime)> {
+    let (rest, separator) = if let Some(rest) = stem.strip_prefix("PUBLIC_ARCHIVE#") {
+        (rest, '#')
+    } else if let Some(rest) = stem.strip_prefix("PUBLIC_DVD_") {
+        (rest, '_')
+    } else {
+        return None
+    };
+    let mut parts = rest.split(separator).collect::<Vec<_>>();
+    let (month, _) = filename_part_datetime(parts.pop()?)?;
+    let part = parts.last()
+        .and_then(|p| p.strip_prefix("FILE"))
+        .and_then(|n| n.parse().ok());
+    if part.is_some() {
+        parts.pop();
+    }
+    Some((parts.join("_"), part, month)).filter(|(table, _, _)| !table.is_empty())
+}
+
+/// Decodes the `%XX` escapes of a url path segment, such as the `%23` for
+/// each `#` in newer MMSDM file names
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Full url of a link on a nemweb page, relative links are resolved against
+/// `base_url`
+fn resolve_url(base_url: &str, href: &str) -> String {
+    if href.contains("://") {
+        href.to_string()
+    } else {
+        format!("{}/{}", base_url.trim_end_matches('/'), href.trim_start_matches('/'))
+    }
+}
+
+impl RemoteFile {
+    pub fn new(name: &str, url: &str) -> Self {
+        let stem = name.split('.').next().unwrap_or(name);
+        if let Some((table, part, month)) = mmsdm_name_parts(stem) {
+            return RemoteFile {
+                name: name.to_string(),
+                url: url.to_string(),
+                size: None,
+                published: None,
+                report: Some(table),
+                interval: Some(month),
+                sequence: None,
+                part,
+            }
+        }
+        let parts = stem.split('_').collect::<Vec<_>>();
+        let interval_idx = parts.iter().position(|p| filename_part_datetime(p).is_some());
+        let (report, interval, sequence) = match interval_idx {
+            Some(idx) => {
+                let report = parts[..idx].iter()
+                    .skip_while(|p| **p == "PUBLIC")
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join("_");
+                let interval = filename_part_datetime(parts[idx]).map(|(dt, _)| dt);
+                let sequence = parts[idx + 1..].last().and_then(|s| s.parse().ok());
+                (Some(report).filter(|r| !r.is_empty()), interval, sequence)
+            },
+            None => (None, None, None),
+        };
+        RemoteFile {
+            name: name.to_string(),
+            url: url.to_string(),
+            size: None,
+            published: None,
+            report,
+            interval,
+            sequence,
+            part: None,
+        }
+    }
+}
+
+/// The zips listed on a nemweb directory page
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NemwebListing {
+    pub url: String,
+    pub files: Vec<RemoteFile>,
+}
+
+impl NemwebListing {
+    /// Parses a nemweb directory page found at `url`, links are resolved
+    /// against `base_url`
+    ///
+    /// Each link is preceded by a line like
+    /// `Saturday, December 25, 2021 10:40 AM         3157`, with the file's
+    /// publish time and size.
+    pub fn from_html(url: &str, base_url: &str, document: &Html) -> Result<Self, Error> {
+        let selector = Selector::parse("a")
+            .map_err(|_| Error::ScraperError)?;
+        let files = document.select(&selector)
+            .filter_map(|eref| {
+                let href = eref.value()
+                    .attr("href")
+                    .filter(|h| h.ends_with(".zip"))?;
+                let name = percent_decode(href.rsplit('/').next().unwrap_or(href));
+                let mut file = RemoteFile::new(&name, &resolve_url(base_url, href));
+                let details = eref.prev_sibling()
+                    .and_then(|n| n.value().as_text().map(|t| t.trim().to_string()))
+                    .unwrap_or_default();
+                let mut details = details.rsplitn(2, char::is_whitespace);
+                file.size = details.next().and_then(|s| s.parse().ok());
+                file.published = details.next()
+                    .and_then(|p| NaiveDateTime::parse_from_str(p.trim(), "%A, %B %d, %Y %I:%M %p").ok());
+                Some(file)
+            })
+            .collect();
+        Ok(NemwebListing { url: url.to_string(), files })
+    }
+
+    /// Files that could hold datetimes in the range
+    pub fn files_in<'a>(&'a self, range: &'a DateTimeRange) -> impl Iterator<Item = &'a RemoteFile> + 'a {
+        self.files.iter().filter(move |f| range.contains_file(&f.name))
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Io(e.into()))
+    }
+}
+
+impl Display for NemwebListing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let show = |dt: &Option<NaiveDateTime>| dt.as_ref().map(format_datetime).unwrap_or_default();
+        write!(f, "{}\n", self.url.bold())?;
+        let mut table = Table::new();
+        table.set_format(FormatBuilder::new()
+                         .borders(' ')
+                         .column_separator(' ')
+                         .separator(LinePosition::Title, LineSeparator::new('-', '-', '-', '-'))
+                         .padding(0, 1)
+                         .build());
+        for file in self.files.iter() {
+            table.add_row(row![
+                cell!(file.name),
+                cell!(file.size.map(|s| s.to_string()).unwrap_or_default()),
+                cell!(show(&file.published)),
+                cell!(file.report.as_deref().unwrap_or_default()),
+                cell!(show(&file.interval)),
+                cell!(file.sequence.map(|s| s.to_string()).unwrap_or_default()),
+            ]);
+        }
+        table.set_titles(row!["Name", "Size", "Published", "Report", "Interval", "Sequence"]);
+        write!(f, "{}", table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn parse_file_names() {
+        let day = NaiveDate::from_ymd(2021, 12, 25);
+        let file = RemoteFile::new("PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip", "");
+        assert_eq!(file.report.as_deref(), Some("DISPATCHSCADA"));
+        assert_eq!(file.interval, Some(day.and_hms(10, 45, 0)));
+        assert_eq!(file.sequence, Some(354978413));
+
+        let file = RemoteFile::new("PUBLIC_ROOFTOP_PV_ACTUAL_MEASUREMENT_20211225110000_0000000354979056.zip", "");
+        assert_eq!(file.report.as_deref(), Some("ROOFTOP_PV_ACTUAL_MEASUREMENT"));
+        assert_eq!(file.interval, Some(day.and_hms(11, 0, 0)));
+
+        let file = RemoteFile::new("PUBLIC_DISPATCHSCADA_20211225.zip", "");
+        assert_eq!(file.interval, Some(day.and_hms(0, 0, 0)));
+        assert_eq!(file.sequence, None);
+
+        let file = RemoteFile::new("README.zip", "");
+        assert_eq!((file.report, file.interval, file.sequence), (None, None, None));
+    }
+
+    #[test]
+    fn parse_mmsdm_file_names() {
+        let month = NaiveDate::from_ymd(2021, 11, 1).and_hms(0, 0, 0);
+        assert_eq!(
+            mmsdm_name_parts("PUBLIC_DVD_DISPATCH_UNIT_SCADA_202111010000"),
+            Some(("DISPATCH_UNIT_SCADA".to_string(), None, month))
+        );
+        assert_eq!(
+            mmsdm_name_parts("PUBLIC_DVD_DISPATCHLOAD_FILE02_202111010000"),
+            Some(("DISPATCHLOAD".to_string(), Some(2), month))
+        );
+        assert_eq!(
+            mmsdm_name_parts("PUBLIC_ARCHIVE#DISPATCH_UNIT_SCADA#FILE01#202111010000"),
+            Some(("DISPATCH_UNIT_SCADA".to_string(), Some(1), month))
+        );
+        assert_eq!(mmsdm_name_parts("PUBLIC_DISPATCHSCADA_202112251045_0000000354978413"), None);
+
+        let name = percent_decode("PUBLIC_ARCHIVE%23DISPATCH_UNIT_SCADA%23FILE01%23202111010000.zip");
+        assert_eq!(name, "PUBLIC_ARCHIVE#DISPATCH_UNIT_SCADA#FILE01#202111010000.zip");
+        let file = RemoteFile::new(&name, "");
+        assert_eq!((file.report.as_deref(), file.part, file.interval), (Some("DISPATCH_UNIT_SCADA"), Some(1), Some(month)));
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
@@ -1,25 +1,56 @@
 pub mod schema;
 pub mod fetch;
+pub mod download;
+pub mod listing;
+pub mod transport;
+pub mod registry;
+pub mod codegen;
+pub mod custom;
+pub mod generated;
+pub mod dedup;
 
 use crate::{
-    error::Error,
+    error::{Error, WarningHandler},
     flatfile::{
+        format_array,
+        widen_schema,
         FlatFile,
+        FlatFileTable,
         InformationRecord,
+        Lineage,
+        TimestampMode,
+        DEFAULT_BATCH_SIZE,
     },
-    packages::fetch::{
-        HistoricDataDownloader,
-        NemwebScraper,
-        Archive,
+    rejects::{RejectMode, RejectWriter},
+    packages::{
+        custom::CustomPackage,
+        generated::GeneratedPackage,
+        dedup::Deduplicator,
+        registry::REGISTRY,
+        fetch::{
+            NemwebScraper,
+            Archive,
+        },
     },
 };
 use arrow::{
+    array::{ArrayRef, StringArray},
+    compute::cast,
+    datatypes::{DataType, Field, Schema, SchemaRef},
     record_batch::RecordBatch,
 };
 use colored::Colorize;
 use parquet::{
-    file::properties::WriterProperties,
-    arrow::arrow_writer::ArrowWriter,
+    file::{
+        metadata::KeyValue,
+        properties::WriterProperties,
+        reader::SerializedFileReader,
+    },
+    arrow::{
+        arrow_writer::ArrowWriter,
+        ArrowReader,
+        ParquetFileArrowReader,
+    },
 };
 use prettytable::{
     Table, 
@@ -32,19 +63,21 @@ use prettytable::{
     },
 };
 use strum::IntoEnumIterator;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use strum_macros::EnumIter;
 use std::{
-    fs::OpenOptions,
+    borrow::Cow,
+    fs::{File, OpenOptions, remove_file, rename},
+    io::BufWriter,
     ffi::{OsStr, OsString},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{Display, self},
 };
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, EnumIter, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, EnumIter)]
 pub enum Package {
     DispatchUnitScada,
     DispatchNegativeResidue,
@@ -52,11 +85,28 @@ pub enum Package {
     DispatchPrice,
     RooftopPvActual,
     RooftopPvForecast,
+    /// A package declared in TOML, see [`custom`]
+    #[strum(disabled)]
+    Custom(CustomPackage),
+    /// A table of the MMS Data Model generated by `nem-mms generate`, see
+    /// [`codegen`]
+    #[strum(disabled)]
+    Generated(GeneratedPackage),
 }
 
 impl Package {
-    pub fn available_packages() -> Vec<&'static str> {
+    /// Built in packages, then generated ones, then any registered custom
+    /// ones
+    pub fn all() -> Vec<Package> {
         Package::iter()
+            .chain(GeneratedPackage::all().into_iter().map(Package::Generated))
+            .chain(CustomPackage::all().into_iter().map(Package::Custom))
+            .collect()
+    }
+
+    pub fn available_packages() -> Vec<&'static str> {
+        Package::all()
+            .into_iter()
             .map(|p| p.as_str())
             .collect()
     }
@@ -70,7 +120,8 @@ impl Package {
              "ROOFTOP_PV_ACTUAL"         => Some(RooftopPvActual),
              "ROOFTOP_PV_FORECAST"       => Some(RooftopPvForecast),
              "DISPATCHPRICE"             => Some(DispatchPrice),
-             _ => None
+             _ => GeneratedPackage::from_name(s).map(Generated)
+                 .or_else(|| CustomPackage::from_name(s).map(Custom))
         }
     }
 
@@ -83,22 +134,64 @@ impl Package {
             RooftopPvActual => "ROOFTOP_PV_ACTUAL",
             RooftopPvForecast => "ROOFTOP_PV_FORECAST",
             DispatchPrice => "DISPATCHPRICE",
+            Generated(p) => p.name(),
+            Custom(p) => p.name(),
         }
     }
 
     pub fn from_information_record(record: &InformationRecord) -> Option<Self> {
+        Package::from_report_type(&record.report_type, &record.report_subtype)
+    }
+
+    pub fn from_report_type(report_type: &str, report_subtype: &str) -> Option<Self> {
         use Package::*;
-        match (record.report_type.as_str(), record.report_subtype.as_str()) {
-            ("DISPATCH", "UNIT_SCADA") => Some(DispatchUnitScada),
+        match (report_type, report_subtype) {
+            ("DISPATCH", "UNIT_SCADA")       => Some(DispatchUnitScada),
             ("DISPATCH", "NEGATIVE_RESIDUE") => Some(DispatchNegativeResidue),
-            ("DISPATCH", "LOCAL_PRICE") => Some(DispatchLocalPrice),
-            ("ROOFTOP", "ACTUAL") => Some(RooftopPvActual),
-            ("ROOFTOP", "FORECAST") => Some(RooftopPvForecast),
-            ("DISPATCH", "PRICE") => Some(DispatchPrice),
-            _ => None
+            ("DISPATCH", "LOCAL_PRICE")      => Some(DispatchLocalPrice),
+            ("ROOFTOP", "ACTUAL")            => Some(RooftopPvActual),
+            ("ROOFTOP", "FORECAST")          => Some(RooftopPvForecast),
+            ("DISPATCH", "PRICE")            => Some(DispatchPrice),
+            _ => GeneratedPackage::from_report_type(report_type, report_subtype).map(Generated)
+                .or_else(|| CustomPackage::from_report_type(report_type, report_subtype).map(Custom))
+        }
+    }
+
+    /// Report type and subtype of the package's information records
+    pub fn report_type(&self) -> (&'static str, &'static str) {
+        use Package::*;
+        match self {
+            DispatchUnitScada => ("DISPATCH", "UNIT_SCADA"),
+            DispatchNegativeResidue => ("DISPATCH", "NEGATIVE_RESIDUE"),
+            DispatchLocalPrice => ("DISPATCH", "LOCAL_PRICE"),
+            RooftopPvActual => ("ROOFTOP", "ACTUAL"),
+            RooftopPvForecast => ("ROOFTOP", "FORECAST"),
+            DispatchPrice => ("DISPATCH", "PRICE"),
+            Generated(p) => p.report_type(),
+            Custom(p) => p.report_type(),
+        }
+    }
+
+    /// Name of the package's table in the MMS Data Model, which names its
+    /// MMSDM historic files
+    ///
+    /// Custom packages only have one if their definition declares it.
+    pub fn mms_table(&self) -> Option<&'static str> {
+        use Package::*;
+        match self {
+            DispatchUnitScada => Some("DISPATCH_UNIT_SCADA"),
+            DispatchNegativeResidue => Some("NEGATIVE_RESIDUE"),
+            DispatchLocalPrice => Some("DISPATCH_LOCAL_PRICE"),
+            RooftopPvActual => Some("ROOFTOP_PV_ACTUAL"),
+            RooftopPvForecast => Some("ROOFTOP_PV_FORECAST"),
+            DispatchPrice => Some("DISPATCHPRICE"),
+            Generated(p) => Some(p.name()),
+            Custom(p) => p.mms_table(),
         }
     }
 
+    /// Schema of the latest version of the package, older versions are
+    /// mapped onto this by the [`registry::REGISTRY`]
     pub fn schema(&self) -> &'static arrow::datatypes::Schema {
         use Package::*;
         match self {
@@ -108,19 +201,48 @@ impl Package {
             RooftopPvActual => &schema::ROOFTOP_PV_ACTUAL,
             RooftopPvForecast => &schema::ROOFTOP_PV_FORECAST,
             DispatchPrice => &schema::DISPATCHPRICE,
+            Generated(p) => p.schema(),
+            Custom(p) => p.schema(),
+        }
+    }
+
+    /// Columns that identify a row, rows with the same key are versions of
+    /// the same record
+    pub fn primary_key(&self) -> &'static [&'static str] {
+        use Package::*;
+        match self {
+            DispatchUnitScada => schema::DISPATCH_UNIT_SCADA_PRIMARY_KEY,
+            DispatchNegativeResidue => schema::DISPATCH_NEGATIVE_RESIDUE_PRIMARY_KEY,
+            DispatchLocalPrice => schema::DISPATCH_LOCAL_PRICE_PRIMARY_KEY,
+            RooftopPvActual => schema::ROOFTOP_PV_ACTUAL_PRIMARY_KEY,
+            RooftopPvForecast => schema::ROOFTOP_PV_FORECAST_PRIMARY_KEY,
+            DispatchPrice => schema::DISPATCHPRICE_PRIMARY_KEY,
+            Generated(p) => p.primary_key(),
+            Custom(p) => p.primary_key(),
+        }
+    }
+
+    /// Schema with monetary and energy columns as decimals at their MMS Data
+    /// Model precision and scale, rather than `Float64`
+    ///
+    /// Custom packages declare their decimal columns directly, so this is the
+    /// same as their [`Package::schema`].
+    pub fn decimal_schema(&self) -> &'static arrow::datatypes::Schema {
+        use Package::*;
+        match self {
+            DispatchUnitScada => &schema::DISPATCH_UNIT_SCADA_DECIMAL,
+            DispatchNegativeResidue => &schema::DISPATCH_NEGATIVE_RESIDUE_DECIMAL,
+            DispatchLocalPrice => &schema::DISPATCH_LOCAL_PRICE_DECIMAL,
+            RooftopPvActual => &schema::ROOFTOP_PV_ACTUAL_DECIMAL,
+            RooftopPvForecast => &schema::ROOFTOP_PV_FORECAST_DECIMAL,
+            DispatchPrice => &schema::DISPATCHPRICE_DECIMAL,
+            Generated(p) => p.decimal_schema(),
+            Custom(p) => p.schema(),
         }
     }
 
     pub fn to_parquet<P: AsRef<Path>>(&self, batches: Vec<RecordBatch>, path: P) -> Result<(), Error> {
-        let schema = Arc::new(self.schema().clone());
-        let file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path)
-            .map_err(Error::Io)?;
-        let props = WriterProperties::builder().build();
-        let mut writer = ArrowWriter::try_new(file, schema, Some(props))
-            .map_err(Error::Parquet)?;
+        let mut writer = parquet_writer(Arc::new(self.schema().clone()), path, &[])?;
         for batch in batches {
             writer.write(&batch).map_err(Error::Parquet)?;
         }
@@ -129,9 +251,65 @@ impl Package {
     }
 }
 
+/// Packages are (de)serialised by name, so custom packages are written the
+/// same way as built in ones
+impl Serialize for Package {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Package {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Package::from_str(&s)
+            // Configs used to be written with the variant names
+            .or_else(|| Package::iter().find(|p| format!("{:?}", p) == s))
+            .ok_or_else(|| serde::de::Error::custom(format!("Unrecognized package {}", s)))
+    }
+}
+
+/// Converts a column that was written with an inferred type to a wider one,
+/// `Utf8` columns get the flat file text of each value back
+fn widen_column(column: &ArrayRef, datatype: &DataType) -> Result<ArrayRef, Error> {
+    match datatype {
+        dt if dt == column.data_type() => Ok(column.clone()),
+        DataType::Utf8 => {
+            let values = format_array(column)?;
+            let values = values.iter()
+                .map(|v| (!v.is_empty()).then(|| v.as_str()))
+                .collect::<StringArray>();
+            Ok(Arc::new(values))
+        },
+        dt => cast(column, dt).map_err(Error::Arrow),
+    }
+}
+
+/// Opens a new parquet file, with the lineage of the tables written to it so
+/// far as key-value metadata (see [`Lineage::sources_metadata`])
+fn parquet_writer<P: AsRef<Path>>(schema: SchemaRef, path: P, sources: &[Lineage]) -> Result<ArrowWriter<File>, Error> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(Error::Io)?;
+    let metadata = Lineage::sources_metadata(sources)
+        .into_iter()
+        .map(|(key, value)| KeyValue { key, value: Some(value) })
+        .collect::<Vec<_>>();
+    let props = WriterProperties::builder()
+        .set_key_value_metadata((!metadata.is_empty()).then(|| metadata))
+        .build();
+    ArrowWriter::try_new(file, schema, Some(props))
+        .map_err(Error::Parquet)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PackageInfo {
     name: String,
+    report_type: (&'static str, &'static str),
+    versions: Vec<u32>,
+    primary_key: &'static [&'static str],
     schema: &'static arrow::datatypes::Schema,
     supports_fetch_current: bool,
     supports_fetch_archive: bool,
@@ -141,11 +319,17 @@ pub struct PackageInfo {
 impl PackageInfo {
     pub fn new(package: Package) -> Self {
         let name = package.as_str().to_string();
+        let report_type = package.report_type();
+        let versions = registry::REGISTRY.versions(package)
+            .into_iter()
+            .map(|v| v.version)
+            .collect();
+        let primary_key = package.primary_key();
         let schema = package.schema();
-        let supports_fetch_historic = HistoricDataDownloader::new(package).url().is_some();
+        let supports_fetch_historic = package.mms_table().is_some();
         let supports_fetch_current = NemwebScraper::new(package, Archive::Current).url().is_some();
         let supports_fetch_archive = NemwebScraper::new(package, Archive::Archive).url().is_some();
-        PackageInfo { name, schema, supports_fetch_current, supports_fetch_archive, supports_fetch_historic }
+        PackageInfo { name, report_type, versions, primary_key, schema, supports_fetch_current, supports_fetch_archive, supports_fetch_historic }
     }
 }
 
@@ -153,6 +337,9 @@ impl Display for PackageInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let indent = "    ";
         write!(f, "Pacakge name: {}\n", self.name)?;
+        write!(f, "Report type: {} {}\n", self.report_type.0, self.report_type.1)?;
+        write!(f, "Report versions: {}\n", self.versions.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "))?;
+        write!(f, "Primary key: {}\n", self.primary_key.join(", "))?;
         write!(f, "Supported fetch operations:\n")?;
         if self.supports_fetch_current {
             write!(f, "{}{}", indent, "✓ Current\n".green())?;
@@ -193,46 +380,595 @@ impl Display for PackageInfo {
     }
 }
 
-pub fn to_parquet<P: AsRef<Path>>(flatfiles: Vec<FlatFile>, path: P) -> Result<(), Error> {
-    let mut reports: HashMap<Package, Vec<RecordBatch>> = HashMap::new();
-    for flatfile in flatfiles {
-        for res in flatfile.iter().map(|t| t.to_arrow()) {
-            match res {
-                Err(e) => match e {
-                    Error::UnrecognizedPackage { report_type, report_subtype } => 
-                        // TODO: change this to a debug log, it's very noisy
-                        println!("Unrecognized package ... skipping\n\tReport type: {}\n\tReport subtype: {}",
-                                  report_type,
-                                  report_subtype),
-                    _ => return Err(e)
-                },
-                Ok((package, rb)) => {
-                    if let Some(v) = reports.get_mut(&package) {
-                        (*v).push(rb);
-                    } else {
-                        reports.insert(package, vec![rb]);
+/// Writes record batches to parquet as they're produced, one file per package
+///
+/// Batches are written to `[stem]_[PACKAGE].parquet` alongside `path`, or to
+/// `[PACKAGE].parquet` inside `path` if it's a directory. When only a single
+/// package was written its file is moved to `path` itself on `close`.
+///
+/// Tables of unrecognized reports are skipped unless schema inference is
+/// turned on, in which case they're written as `[REPORT TYPE]_[SUBTYPE]` with
+/// an inferred schema. When a later table has values the inferred types
+/// can't hold, those columns are widened (see
+/// [`crate::flatfile::widen_schema`]) and what's been written so far is
+/// rewritten with them.
+///
+/// Each file's metadata holds the lineage of the tables written to it, only
+/// the source files when there's more than one. Since the metadata is fixed
+/// when a file is opened, a file with several sources is rewritten on close.
+/// The lineage of every row can be kept by turning on lineage columns.
+///
+/// Timestamps are written in NEM market time unless another
+/// [`TimestampMode`] is given.
+///
+/// Files are written as `.partial` alongside their output and only moved
+/// into place once the sink closes successfully, so a run that fails part
+/// way (say on a truncated flat file) leaves nothing behind.
+pub struct ParquetSink {
+    path: PathBuf,
+    infer_schemas: bool,
+    lineage_columns: bool,
+    decimals: bool,
+    timestamps: TimestampMode,
+    rejects: Option<RejectWriter<BufWriter<File>>>,
+    reject_mode: RejectMode,
+    rejected: usize,
+    deduplicate: bool,
+    deduplicators: HashMap<String, Deduplicator>,
+    inferred: HashMap<String, SchemaRef>,
+    warnings: Option<WarningHandler>,
+    unregistered: HashSet<(String, String, u32)>,
+    writers: HashMap<String, Output>,
+    // Declared after the writers so their files are closed before removal
+    partials: PartialFiles,
+}
+
+/// A file being written by a [`ParquetSink`], to `partial` until it's moved
+/// to `path` on close
+struct Output {
+    path: PathBuf,
+    partial: PathBuf,
+    sources: Vec<Lineage>,
+    /// How many of the sources the file's metadata was written with
+    written_sources: usize,
+    writer: ArrowWriter<File>,
+    rewrites: usize,
+}
+
+fn already_exists(path: &Path) -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.to_string_lossy())
+    ))
+}
+
+fn partial_path(path: &Path, rewrites: usize) -> PathBuf {
+    match rewrites {
+        0 => path.with_extension("parquet.partial"),
+        n => path.with_extension(format!("parquet.{}.partial", n)),
+    }
+}
+
+/// Files written by a [`ParquetSink`] that haven't been moved into place,
+/// removed when dropped
+#[derive(Debug, Default)]
+struct PartialFiles(Vec<PathBuf>);
+
+impl Drop for PartialFiles {
+    fn drop(&mut self) {
+        for partial in self.0.iter() {
+            let _ = remove_file(partial);
+        }
+    }
+}
+
+impl ParquetSink {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        ParquetSink {
+            path: path.as_ref().to_path_buf(),
+            infer_schemas: false,
+            lineage_columns: false,
+            decimals: false,
+            timestamps: TimestampMode::default(),
+            rejects: None,
+            reject_mode: RejectMode::default(),
+            rejected: 0,
+            deduplicate: false,
+            deduplicators: HashMap::new(),
+            inferred: HashMap::new(),
+            warnings: None,
+            unregistered: HashSet::new(),
+            writers: HashMap::new(),
+            partials: PartialFiles::default(),
+        }
+    }
+
+    pub fn infer_schemas(self, infer_schemas: bool) -> Self {
+        ParquetSink { infer_schemas, ..self }
+    }
+
+    /// Append the [`crate::flatfile::LINEAGE_COLUMNS`] to every batch
+    pub fn lineage_columns(self, lineage_columns: bool) -> Self {
+        ParquetSink { lineage_columns, ..self }
+    }
+
+    /// Convert tables with their package's [`Package::decimal_schema`]
+    pub fn decimals(self, decimals: bool) -> Self {
+        ParquetSink { decimals, ..self }
+    }
+
+    pub fn timestamps(self, timestamps: TimestampMode) -> Self {
+        ParquetSink { timestamps, ..self }
+    }
+
+    /// Only keep the latest row for each primary key of a package, see
+    /// [`Deduplicator`]. Rows are written as they come and the superseded
+    /// ones filtered out on `close`, so only the primary key of each row is
+    /// held in memory. Tables projected without their primary key fail.
+    pub fn deduplicate(self, deduplicate: bool) -> Self {
+        ParquetSink { deduplicate, ..self }
+    }
+
+    /// Where to report report versions that aren't registered, and were read
+    /// with another version's schema, see [`registry::SchemaRegistry::lookup`]
+    pub fn with_warnings(self, warnings: WarningHandler) -> Self {
+        ParquetSink { warnings: Some(warnings), ..self }
+    }
+
+    /// Write records that can't be converted here rather than failing,
+    /// along with any [`FlatFileTable::bad_records`]
+    pub fn with_rejects(self, rejects: RejectWriter<BufWriter<File>>, reject_mode: RejectMode) -> Self {
+        ParquetSink { rejects: Some(rejects), reject_mode, ..self }
+    }
+
+    /// Number of records rejected so far, whether they were skipped or had
+    /// their bad values nulled
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    fn output_path(&self, name: &str) -> Result<PathBuf, Error> {
+        if self.path.is_dir() {
+            Ok(self.path.join(name).with_extension("parquet"))
+        } else {
+            let filename = self.path.file_stem()
+                .map(|s| vec![s, &OsStr::new(&format!("_{}", name))].into_iter().collect::<OsString>())
+                .ok_or(Error::InvalidFilename(self.path.clone()))?;
+            Ok(self.path.with_file_name(filename).with_extension("parquet"))
+        }
+    }
+
+    /// Converts a table to arrow and writes it
+    ///
+    /// With a reject writer, records with cells that can't be converted are
+    /// written to it rather than failing the table, as are the table's
+    /// records that couldn't be read at all.
+    pub fn write_table(&mut self, table: &FlatFileTable) -> Result<(), Error> {
+        self.reject_unreadable(table)?;
+        let (table, converted) = match self.convert(table) {
+            Err(e) if self.rejects.is_some() => {
+                let schema = match self.conversion_schema(table) {
+                    Some(schema) => schema,
+                    None => return Err(e),
+                };
+                let (kept, rejects) = table.reject_bad_records(&schema, self.reject_mode);
+                if rejects.is_empty() {
+                    return Err(e)
+                }
+                if let Some(wtr) = self.rejects.as_mut() {
+                    for reject in rejects.iter().flatten() {
+                        wtr.write(reject)?;
                     }
                 }
+                self.rejected += rejects.len();
+                let converted = self.convert(&kept)?;
+                (Cow::Owned(kept), converted)
+            },
+            converted => (Cow::Borrowed(table), converted?),
+        };
+        let (name, rb) = match converted {
+            Some(converted) => converted,
+            None => return Ok(()),
+        };
+        let rb = if self.lineage_columns {
+            table.with_lineage_columns(&rb)?
+        } else {
+            rb
+        };
+        let rb = self.timestamps.apply(rb)?;
+        self.push_batch(&name, table.package().ok(), rb, table.lineage())
+    }
+
+    /// Writes a table's unreadable records as rejects, or fails on the first
+    /// of them without a reject writer
+    fn reject_unreadable(&mut self, table: &FlatFileTable) -> Result<(), Error> {
+        let wtr = match (table.bad_records().first(), self.rejects.as_mut()) {
+            (None, _) => return Ok(()),
+            (Some(bad), None) => return Err(Error::UnreadableRecord { line: bad.line(), error: bad.error.clone() }),
+            (Some(_), Some(wtr)) => wtr,
+        };
+        for reject in table.reject_unreadable_records() {
+            wtr.write(&reject)?;
+        }
+        self.rejected += table.bad_records().len();
+        Ok(())
+    }
+
+    /// Converts a table with its package's schema, or an inferred one, and
+    /// names its output. Tables of unrecognized reports give `None`.
+    fn convert(&mut self, table: &FlatFileTable) -> Result<Option<(String, RecordBatch)>, Error> {
+        let converted = if self.decimals {
+            table.to_arrow_decimal()
+        } else {
+            table.to_arrow()
+        };
+        match converted {
+            Err(Error::UnrecognizedPackage { .. }) if self.infer_schemas => {
+                let name = table.inferred_name();
+                let inferred = table.infer_schema();
+                let schema = match self.inferred.get(&name).cloned() {
+                    Some(schema) => {
+                        let widened = widen_schema(&schema, &inferred);
+                        if widened != *schema {
+                            self.widen_output(&name, &schema, &widened)?;
+                        }
+                        Arc::new(widened)
+                    },
+                    None => Arc::new(inferred),
+                };
+                self.inferred.insert(name.clone(), schema.clone());
+                Ok(Some((name, table.to_arrow_with_schema(schema)?)))
+            },
+            Err(Error::UnrecognizedPackage { report_type, report_subtype }) => {
+                // TODO: change this to a debug log, it's very noisy
+                println!("Unrecognized package ... skipping\n\tReport type: {}\n\tReport subtype: {}",
+                          report_type,
+                          report_subtype);
+                Ok(None)
+            },
+            Err(Error::EmptyProjection { .. }) => Ok(None),
+            Err(e) => Err(e),
+            Ok((package, rb)) => {
+                self.check_registered(table.information_record());
+                Ok(Some((package.as_str().to_string(), rb)))
+            },
+        }
+    }
+
+    /// Warns, once for each, about report versions that were read with
+    /// another version's schema
+    fn check_registered(&mut self, info: &InformationRecord) {
+        if REGISTRY.is_registered(&info.report_type, &info.report_subtype, info.report_version) {
+            return
+        }
+        let key = (info.report_type.clone(), info.report_subtype.clone(), info.report_version);
+        if !self.unregistered.insert(key) {
+            return
+        }
+        let read_as = REGISTRY.lookup(&info.report_type, &info.report_subtype, info.report_version)
+            .map(|registered| registered.version);
+        // Newer versions only add columns, so reading them with the latest
+        // schema is expected and not worth a warning
+        match (read_as, self.warnings.as_ref()) {
+            (Ok(read_as), Some(warnings)) if read_as > info.report_version => {
+                warnings.warn(&Error::UnregisteredReportVersion {
+                    report_type: info.report_type.clone(),
+                    report_subtype: info.report_subtype.clone(),
+                    version: info.report_version,
+                    read_as,
+                })
+            },
+            _ => (),
+        }
+    }
+
+    /// Schema a table is converted with, if it has been worked out
+    fn conversion_schema(&self, table: &FlatFileTable) -> Option<Schema> {
+        match table.package() {
+            Ok(package) if self.decimals => Some(package.decimal_schema().clone()),
+            Ok(package) => Some(package.schema().clone()),
+            Err(_) => self.inferred.get(&table.inferred_name()).map(|s| s.as_ref().clone()),
+        }
+    }
+
+    /// Writes a batch of a package, converting its timestamps if it's still
+    /// in naive market time
+    pub fn write(&mut self, package: Package, batch: &RecordBatch) -> Result<(), Error> {
+        let batch = self.timestamps.apply(batch.clone())?;
+        self.push_batch(package.as_str(), Some(package), batch, &Lineage::default())
+    }
+
+    /// Writes a batch, and indexes its primary keys to be deduplicated on
+    /// `close` if it's of a package with one
+    fn push_batch(&mut self, name: &str, package: Option<Package>, batch: RecordBatch, lineage: &Lineage) -> Result<(), Error> {
+        let primary_key = package.map(|p| p.primary_key()).unwrap_or(&[]);
+        if self.deduplicate && !primary_key.is_empty() {
+            let schema = batch.schema();
+            let missing = primary_key.iter()
+                .filter(|k| schema.index_of(k).is_err())
+                .map(|k| k.to_string())
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(Error::PrimaryKeyNotProjected { package: name.to_string(), columns: missing })
             }
+            let published = lineage.header.as_ref().map(|h| h.publish_datetime());
+            self.deduplicators.entry(name.to_string())
+                .or_insert_with(|| Deduplicator::new(primary_key))
+                .push(&batch, published)?;
         }
-    };
-    if reports.len() <= 1 {
-        for (p, bs) in reports.into_iter() {
-            p.to_parquet(bs, &path)?;
+        self.write_batch(name, &batch, lineage)
+    }
+
+    fn write_batch(&mut self, name: &str, batch: &RecordBatch, lineage: &Lineage) -> Result<(), Error> {
+        if !self.writers.contains_key(name) {
+            let path = self.output_path(name)?;
+            // Checked up front as well as on close, so a run isn't wasted on
+            // output that can't be moved into place
+            if path.exists() {
+                return Err(already_exists(&path))
+            }
+            // A lone output is moved to the sink's path itself
+            if self.writers.is_empty() && !self.path.is_dir() && self.path.exists() {
+                return Err(already_exists(&self.path))
+            }
+            let partial = partial_path(&path, 0);
+            let sources = vec![lineage.clone()];
+            let writer = parquet_writer(batch.schema(), &partial, &sources)?;
+            self.partials.0.push(partial.clone());
+            let output = Output { path, partial, sources, written_sources: 1, writer, rewrites: 0 };
+            self.writers.insert(name.to_string(), output);
+        }
+        let output = self.writers.get_mut(name)
+            .expect("Writer was just inserted");
+        if !output.sources.contains(lineage) {
+            output.sources.push(lineage.clone());
+        }
+        output.writer.write(batch).map_err(Error::Parquet)
+    }
+
+    /// Rewrites the output of an inferred schema with the columns whose type
+    /// differs between `inferred` and `widened` converted to the wider type
+    fn widen_output(&mut self, name: &str, inferred: &Schema, widened: &Schema) -> Result<(), Error> {
+        // The written schema also has any lineage columns and converted
+        // timestamps, so only the widened columns are changed
+        let schema = |written: &Schema| {
+            let fields = written.fields()
+                .iter()
+                .map(|field| match (inferred.field_with_name(field.name()), widened.field_with_name(field.name())) {
+                    (Ok(from), Ok(to)) if from.data_type() != to.data_type() => Field::new(field.name(), to.data_type().clone(), true),
+                    _ => field.clone(),
+                })
+                .collect::<Vec<_>>();
+            Schema::new(fields)
+        };
+        self.rewrite_output(name, schema, |batch, schema| {
+            let columns = schema.fields()
+                .iter()
+                .zip(batch.columns())
+                .map(|(field, column)| widen_column(column, field.data_type()))
+                .collect::<Result<Vec<_>, _>>()?;
+            RecordBatch::try_new(schema.clone(), columns).map_err(Error::Arrow)
+        })
+    }
+
+    /// Rewrites what's been written to an output, `schema` gives the new
+    /// schema from the written one and `f` converts each written batch to it
+    fn rewrite_output<S, F>(&mut self, name: &str, schema: S, mut f: F) -> Result<(), Error>
+    where
+        S: FnOnce(&Schema) -> Schema,
+        F: FnMut(RecordBatch, &SchemaRef) -> Result<RecordBatch, Error>,
+    {
+        let Output { path, partial, sources, mut writer, rewrites, .. } = match self.writers.remove(name) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        writer.close().map_err(Error::Parquet)?;
+        let file = File::open(&partial).map_err(Error::Io)?;
+        let reader = SerializedFileReader::new(file).map_err(Error::Parquet)?;
+        let mut reader = ParquetFileArrowReader::new(Arc::new(reader));
+        let schema = Arc::new(schema(&reader.get_schema().map_err(Error::Parquet)?));
+
+        let rewritten = partial_path(&path, rewrites + 1);
+        self.partials.0.push(rewritten.clone());
+        let mut writer = parquet_writer(schema.clone(), &rewritten, &sources)?;
+        for batch in reader.get_record_reader(DEFAULT_BATCH_SIZE).map_err(Error::Parquet)? {
+            let batch = f(batch.map_err(Error::Arrow)?, &schema)?;
+            writer.write(&batch).map_err(Error::Parquet)?;
         }
-    } else {
-        for (p, bs) in reports.into_iter() {
-            let ppath = if path.as_ref().is_dir() {
-                path.as_ref().join(format!("{}", p.as_str())).with_extension("parquet")
-            } else {
-                let filename = path.as_ref().file_stem()
-                    .map(|s| vec![s, &OsStr::new(&format!("_{}", p.as_str()))].into_iter().collect::<OsString>())
-                    .ok_or(Error::InvalidFilename(path.as_ref().to_path_buf()))?;
-                Path::new(&filename).with_extension("parquet")
-            };
-            p.to_parquet(bs, ppath)?;
+        remove_file(&partial).map_err(Error::Io)?;
+        self.partials.0.retain(|p| *p != partial);
+        let written_sources = sources.len();
+        let output = Output { path, partial: rewritten, sources, written_sources, writer, rewrites: rewrites + 1 };
+        self.writers.insert(name.to_string(), output);
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), Error> {
+        for (name, deduplicator) in std::mem::take(&mut self.deduplicators) {
+            let mut filter = deduplicator.finish();
+            self.rewrite_output(&name, |written| written.clone(), |batch, _| filter.filter(&batch))?;
+        }
+        // Files opened with the metadata of fewer sources than they hold
+        let stale = self.writers.iter()
+            .filter(|(_, output)| output.written_sources < output.sources.len())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in stale {
+            self.rewrite_output(&name, |written| written.clone(), |batch, _| Ok(batch))?;
+        }
+        if let Some(rejects) = self.rejects.take() {
+            rejects.finish()?;
         }
+        let single = self.writers.len() == 1 && !self.path.is_dir();
+        let mut written = Vec::new();
+        for (_, mut output) in std::mem::take(&mut self.writers) {
+            output.writer.close().map_err(Error::Parquet)?;
+            let path = if single { self.path.clone() } else { output.path };
+            written.push((output.partial, path));
+        }
+        // Nothing is moved into place unless everything can be
+        if let Some((_, path)) = written.iter().find(|(_, path)| path.exists()) {
+            return Err(already_exists(path))
+        }
+        for (partial, path) in written {
+            rename(&partial, &path).map_err(Error::Io)?;
+            self.partials.0.retain(|p| *p != partial);
+        }
+        Ok(())
     }
-    Ok(())
 }
 
+/// Writes the tables of flat files to parquet, keeping only the latest row
+/// for each primary key
+pub fn to_parquet<P: AsRef<Path>>(flatfiles: Vec<FlatFile>, path: P) -> Result<(), Error> {
+    let mut sink = ParquetSink::new(path)
+        .deduplicate(true);
+    for flatfile in flatfiles {
+        for table in flatfile.iter() {
+            sink.write_table(&table)?;
+        }
+    };
+    sink.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::Filter, flatfile::FlatFileReader};
+
+    #[test]
+    fn packages_from_report_type() {
+        for package in Package::all() {
+            let (report_type, report_subtype) = package.report_type();
+            assert_eq!(Package::from_report_type(report_type, report_subtype), Some(package));
+        }
+        assert_eq!(Package::from_report_type("DISPATCH", "NOT_A_TABLE"), None);
+    }
+
+    fn write_sample(sample: &str, dir: &Path) -> Result<(), Error> {
+        let rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(sample.as_bytes());
+        let mut sink = ParquetSink::new(dir);
+        FlatFileReader::new(rdr).try_for_each(|table| sink.write_table(&table?))?;
+        sink.close()
+    }
+
+    #[test]
+    fn sink_only_keeps_complete_output() {
+        let dir = std::env::temp_dir().join(format!("nem-mms-sink-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = include_str!("../../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+
+        let miscounted = sample.replace("END OF REPORT\",20", "END OF REPORT\",25");
+        let written = write_sample(&miscounted, &dir);
+        assert!(matches!(written, Err(Error::RecordCountMismatch { .. })));
+        assert_eq!(dir.read_dir().unwrap().count(), 0);
+
+        write_sample(sample, &dir).unwrap();
+        let mut files = dir.read_dir()
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["DISPATCHPRICE.parquet", "DISPATCH_LOCAL_PRICE.parquet"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inferred_columns_widen() {
+        let sample = "C,NEMP.WORLD,TEST,AEMO,PUBLIC,2021/12/25,10:40:12,0000000354978420,TEST,0000000354978416
+I,TEST,WIDENING,1,ID,VALUE
+D,TEST,WIDENING,1,0001,1
+D,TEST,WIDENING,1,2,1.5
+D,TEST,WIDENING,1,3,abc
+C,\"END OF REPORT\",6
+";
+        let dir = std::env::temp_dir().join(format!("nem-mms-widen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(sample.as_bytes());
+        // One row per table, so VALUE is widened from Int64 to Float64 and
+        // then to Utf8
+        let mut sink = ParquetSink::new(&dir).infer_schemas(true);
+        for table in FlatFileReader::new(rdr).with_batch_size(1) {
+            sink.write_table(&table.unwrap()).unwrap();
+        }
+        sink.close().unwrap();
+
+        let path = dir.join("TEST_WIDENING.parquet");
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let batch = ParquetFileArrowReader::new(Arc::new(reader))
+            .get_record_reader(16)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let strings = |idx: usize| format_array(batch.column(idx)).unwrap();
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Utf8);
+        assert_eq!(strings(0), vec!["0001", "2", "3"]);
+        assert_eq!(batch.schema().field(1).data_type(), &DataType::Utf8);
+        assert_eq!(strings(1)[1..], ["1.5", "abc"]);
+        assert_eq!(dir.read_dir().unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sink_deduplicates_on_close() {
+        let dir = std::env::temp_dir().join(format!("nem-mms-dedup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = include_str!("../../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let read = |filter: &Filter| {
+            let rdr = csv::ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(false)
+                .from_reader(sample.as_bytes());
+            FlatFileReader::new(rdr).with_filter(filter)
+        };
+
+        // The same file twice, so every row is superseded by its copy
+        let mut sink = ParquetSink::new(&dir).deduplicate(true);
+        for _ in 0..2 {
+            read(&Filter::default()).try_for_each(|table| sink.write_table(&table?)).unwrap();
+        }
+        sink.close().unwrap();
+        let reader = SerializedFileReader::new(File::open(dir.join("DISPATCHPRICE.parquet")).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let filter = Filter { columns: Some(vec!["REGIONID".to_string(), "RRP".to_string()]), ..Filter::default() };
+        let mut sink = ParquetSink::new(&dir).deduplicate(true);
+        let written = read(&filter).try_for_each(|table| sink.write_table(&table?));
+        assert!(matches!(written, Err(Error::PrimaryKeyNotProjected { .. })));
+    }
+
+    #[test]
+    fn sink_metadata_lists_sources() {
+        let dir = std::env::temp_dir().join(format!("nem-mms-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = include_str!("../../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+        let mut sink = ParquetSink::new(&dir);
+        for source in &["first.CSV", "second.CSV"] {
+            let rdr = csv::ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(false)
+                .from_reader(sample.as_bytes());
+            FlatFileReader::new(rdr)
+                .with_source(source)
+                .try_for_each(|table| sink.write_table(&table?))
+                .unwrap();
+        }
+        sink.close().unwrap();
+
+        let reader = SerializedFileReader::new(File::open(dir.join("DISPATCHPRICE.parquet")).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata().key_value_metadata().clone().unwrap();
+        let value = |key: &str| metadata.iter().find(|kv| kv.key == key).and_then(|kv| kv.value.clone());
+        assert_eq!(value("nem_mms.source_files"), Some("first.CSV\nsecond.CSV".to_string()));
+        assert_eq!(value("nem_mms.publish_datetime"), None);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 10);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
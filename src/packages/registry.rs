@@ -0,0 +1,156 @@
+use arrow::datatypes::{Field, Schema};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+};
+use crate::{
+    error::Error,
+    packages::{Package, generated::GeneratedPackage, schema},
+};
+
+lazy_static! {
+    pub static ref REGISTRY: SchemaRegistry = SchemaRegistry::builtin();
+}
+
+/// The schema of a package as published at a particular report version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisteredVersion {
+    pub package: Package,
+    pub version: u32,
+    pub schema: &'static Schema,
+}
+
+impl RegisteredVersion {
+    pub fn has_column(&self, name: &str) -> bool {
+        self.schema.field_with_name(name).is_ok()
+    }
+
+    /// Columns of the package's current schema that this version doesn't
+    /// have, these are filled with nulls when mapping onto the current schema
+    pub fn missing_columns(&self) -> Vec<&'static Field> {
+        self.package.schema()
+            .fields()
+            .iter()
+            .filter(|f| !self.has_column(f.name()))
+            .collect()
+    }
+}
+
+/// Schemas of every known version of each package, keyed by the report
+/// type, subtype and version of the information record
+///
+/// Packages declared at runtime (see [`crate::packages::custom`]) are added
+/// to the builtin ones as they're registered.
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    versions: RwLock<HashMap<(String, String, u32), RegisteredVersion>>,
+}
+
+impl SchemaRegistry {
+    fn builtin() -> Self {
+        use Package::*;
+        let registry = SchemaRegistry { versions: RwLock::new(HashMap::new()) };
+        registry.register(DispatchUnitScada, 1, &schema::DISPATCH_UNIT_SCADA);
+        registry.register(DispatchNegativeResidue, 1, &schema::DISPATCH_NEGATIVE_RESIDUE);
+        registry.register(DispatchLocalPrice, 1, &schema::DISPATCH_LOCAL_PRICE);
+        registry.register(DispatchPrice, 4, &schema::DISPATCHPRICE_V4);
+        registry.register(DispatchPrice, 5, &schema::DISPATCHPRICE);
+        registry.register(RooftopPvActual, 2, &schema::ROOFTOP_PV_ACTUAL);
+        registry.register(RooftopPvForecast, 1, &schema::ROOFTOP_PV_FORECAST);
+        for package in GeneratedPackage::all() {
+            registry.register(Generated(package), package.version(), package.schema());
+        }
+        registry
+    }
+
+    pub(crate) fn register(&self, package: Package, version: u32, schema: &'static Schema) {
+        let (report_type, report_subtype) = package.report_type();
+        self.versions.write()
+            .expect("Schema registry lock poisoned")
+            .insert(
+                (report_type.to_string(), report_subtype.to_string(), version),
+                RegisteredVersion { package, version, schema }
+            );
+    }
+
+    /// Every registered version of a package, oldest first
+    pub fn versions(&self, package: Package) -> Vec<RegisteredVersion> {
+        let mut versions = self.versions.read()
+            .expect("Schema registry lock poisoned")
+            .values()
+            .filter(|v| v.package == package)
+            .copied()
+            .collect::<Vec<_>>();
+        versions.sort_by_key(|v| v.version);
+        versions
+    }
+
+    /// Finds the schema for a version of a report
+    ///
+    /// Versions that aren't registered are read with the nearest one that
+    /// is: the latest for newer versions, since AEMO only ever appends
+    /// columns, or the oldest one after it for older versions. Columns are
+    /// matched by name, so those an older version lacks are filled with
+    /// nulls. See [`SchemaRegistry::is_registered`] to tell when this
+    /// happened.
+    pub fn lookup(&self, report_type: &str, report_subtype: &str, version: u32) -> Result<RegisteredVersion, Error> {
+        let key = (report_type.to_string(), report_subtype.to_string(), version);
+        let registered = self.versions.read()
+            .expect("Schema registry lock poisoned")
+            .get(&key)
+            .copied();
+        if let Some(registered) = registered {
+            return Ok(registered)
+        }
+        let package = Package::from_report_type(report_type, report_subtype)
+            .ok_or(Error::UnrecognizedPackage {
+                report_type: report_type.to_string(),
+                report_subtype: report_subtype.to_string()
+            })?;
+        let versions = self.versions(package);
+        versions.iter()
+            .find(|v| v.version > version)
+            .or_else(|| versions.last())
+            .copied()
+            .ok_or(Error::UnsupportedReportVersion {
+                report_type: report_type.to_string(),
+                report_subtype: report_subtype.to_string(),
+                version,
+            })
+    }
+
+    pub fn is_registered(&self, report_type: &str, report_subtype: &str, version: u32) -> bool {
+        let key = (report_type.to_string(), report_subtype.to_string(), version);
+        self.versions.read()
+            .expect("Schema registry lock poisoned")
+            .contains_key(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_versions() {
+        let v4 = REGISTRY.lookup("DISPATCH", "PRICE", 4).unwrap();
+        assert_eq!(v4.package, Package::DispatchPrice);
+        assert_eq!(v4.version, 4);
+        let missing = v4.missing_columns()
+            .into_iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(missing, schema::DISPATCHPRICE_V5_COLUMNS.to_vec());
+
+        assert_eq!(REGISTRY.lookup("DISPATCH", "PRICE", 6).unwrap().version, 5);
+        // Older versions are read with the oldest registered one after them
+        assert_eq!(REGISTRY.lookup("DISPATCH", "PRICE", 3).unwrap().version, 4);
+        assert!(!REGISTRY.is_registered("DISPATCH", "PRICE", 3));
+        assert_eq!(REGISTRY.lookup("ROOFTOP", "ACTUAL", 1).unwrap().version, 2);
+        assert!(matches!(
+            REGISTRY.lookup("DISPATCH", "CASE_SOLUTION", 2),
+            Err(Error::UnrecognizedPackage { .. })
+        ));
+    }
+}
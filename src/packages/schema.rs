@@ -126,6 +126,148 @@ lazy_static! {
             Field::new("CUMUL_PRE_AP_LOWERREG_PRICE", DataType::Float64, true),
             Field::new("OCD_STATUS", DataType::Utf8, true),
             Field::new("MII_STATUS", DataType::Utf8, true),
+            Field::new("RAISE1SECRRP", DataType::Float64, true),
+            Field::new("RAISE1SECROP", DataType::Float64, true),
+            Field::new("RAISE1SECAPCFLAG", DataType::Int16, true),
+            Field::new("LOWER1SECRRP", DataType::Float64, true),
+            Field::new("LOWER1SECROP", DataType::Float64, true),
+            Field::new("LOWER1SECAPCFLAG", DataType::Int16, true),
+            Field::new("PRE_AP_RAISE1_PRICE", DataType::Float64, true),
+            Field::new("PRE_AP_LOWER1_PRICE", DataType::Float64, true),
+            Field::new("CUMUL_PRE_AP_RAISE1_PRICE", DataType::Float64, true),
+            Field::new("CUMUL_PRE_AP_LOWER1_PRICE", DataType::Float64, true),
         ]
     );
+
+    /// DISPATCH PRICE before the very fast (1 second) FCAS markets were added
+    pub static ref DISPATCHPRICE_V4: Schema = without_columns(&DISPATCHPRICE, DISPATCHPRICE_V5_COLUMNS);
+
+    pub static ref DISPATCH_UNIT_SCADA_DECIMAL: Schema = with_decimals(&DISPATCH_UNIT_SCADA, DISPATCH_UNIT_SCADA_DECIMALS);
+    pub static ref DISPATCH_NEGATIVE_RESIDUE_DECIMAL: Schema = with_decimals(&DISPATCH_NEGATIVE_RESIDUE, DISPATCH_NEGATIVE_RESIDUE_DECIMALS);
+    pub static ref DISPATCH_LOCAL_PRICE_DECIMAL: Schema = with_decimals(&DISPATCH_LOCAL_PRICE, DISPATCH_LOCAL_PRICE_DECIMALS);
+    pub static ref ROOFTOP_PV_ACTUAL_DECIMAL: Schema = with_decimals(&ROOFTOP_PV_ACTUAL, ROOFTOP_PV_ACTUAL_DECIMALS);
+    pub static ref ROOFTOP_PV_FORECAST_DECIMAL: Schema = with_decimals(&ROOFTOP_PV_FORECAST, ROOFTOP_PV_FORECAST_DECIMALS);
+    pub static ref DISPATCHPRICE_DECIMAL: Schema = with_decimals(&DISPATCHPRICE, DISPATCHPRICE_DECIMALS);
+}
+
+/// Primary keys of the MMS Data Model tables
+pub const DISPATCH_UNIT_SCADA_PRIMARY_KEY: &[&str] = &["SETTLEMENTDATE", "DUID"];
+pub const DISPATCH_NEGATIVE_RESIDUE_PRIMARY_KEY: &[&str] = &["SETTLEMENTDATE", "NRM_DATETIME", "DIRECTIONAL_INTERCONNECTORID"];
+pub const DISPATCH_LOCAL_PRICE_PRIMARY_KEY: &[&str] = &["SETTLEMENTDATE", "DUID"];
+pub const ROOFTOP_PV_ACTUAL_PRIMARY_KEY: &[&str] = &["INTERVAL_DATETIME", "TYPE", "REGIONID"];
+pub const ROOFTOP_PV_FORECAST_PRIMARY_KEY: &[&str] = &["VERSION_DATETIME", "REGIONID", "INTERVAL_DATETIME"];
+pub const DISPATCHPRICE_PRIMARY_KEY: &[&str] = &["SETTLEMENTDATE", "RUNNO", "REGIONID", "DISPATCHINTERVAL", "INTERVENTION"];
+
+/// Columns added to DISPATCH PRICE in version 5
+pub const DISPATCHPRICE_V5_COLUMNS: &[&str] = &[
+    "RAISE1SECRRP",
+    "RAISE1SECROP",
+    "RAISE1SECAPCFLAG",
+    "LOWER1SECRRP",
+    "LOWER1SECROP",
+    "LOWER1SECAPCFLAG",
+    "PRE_AP_RAISE1_PRICE",
+    "PRE_AP_LOWER1_PRICE",
+    "CUMUL_PRE_AP_RAISE1_PRICE",
+    "CUMUL_PRE_AP_LOWER1_PRICE",
+];
+
+/// Monetary and energy columns with their MMS Data Model `NUMBER(precision,
+/// scale)`, for the decimal variants of the schemas
+pub const DISPATCH_UNIT_SCADA_DECIMALS: &[(&str, usize, usize)] = &[
+    ("SCADAVALUE", 16, 6),
+];
+
+pub const DISPATCH_NEGATIVE_RESIDUE_DECIMALS: &[(&str, usize, usize)] = &[
+    ("CUMUL_NEGRESIDUE_AMOUNT", 15, 5),
+    ("CUMUL_NEGRESIDUE_PREV_TI", 15, 5),
+    ("NEGRESIDUE_CURRENT_TI", 15, 5),
+    ("NEGRESIDUE_PD_NEXT_TI", 15, 5),
+];
+
+pub const DISPATCH_LOCAL_PRICE_DECIMALS: &[(&str, usize, usize)] = &[
+    ("LOCAL_PRICE_ADJUSTMENT", 10, 2),
+];
+
+pub const ROOFTOP_PV_ACTUAL_DECIMALS: &[(&str, usize, usize)] = &[
+    ("POWER", 12, 3),
+];
+
+pub const ROOFTOP_PV_FORECAST_DECIMALS: &[(&str, usize, usize)] = &[
+    ("POWERMEAN", 12, 3),
+    ("POWERPOE50", 12, 3),
+    ("POWERPOELOW", 12, 3),
+    ("POWERPOEHIGH", 12, 3),
+];
+
+pub const DISPATCHPRICE_DECIMALS: &[(&str, usize, usize)] = &[
+    ("RRP", 15, 5),
+    ("EEP", 15, 5),
+    ("ROP", 15, 5),
+    ("RAISE6SECRRP", 15, 5),
+    ("RAISE6SECROP", 15, 5),
+    ("RAISE60SECRRP", 15, 5),
+    ("RAISE60SECROP", 15, 5),
+    ("RAISE5MINRRP", 15, 5),
+    ("RAISE5MINROP", 15, 5),
+    ("RAISEREGRRP", 15, 5),
+    ("RAISEREGROP", 15, 5),
+    ("LOWER6SECRRP", 15, 5),
+    ("LOWER6SECROP", 15, 5),
+    ("LOWER60SECRRP", 15, 5),
+    ("LOWER60SECROP", 15, 5),
+    ("LOWER5MINRRP", 15, 5),
+    ("LOWER5MINROP", 15, 5),
+    ("LOWERREGRRP", 15, 5),
+    ("LOWERREGROP", 15, 5),
+    ("PRE_AP_ENERGY_PRICE", 15, 5),
+    ("PRE_AP_RAISE6_PRICE", 15, 5),
+    ("PRE_AP_RAISE60_PRICE", 15, 5),
+    ("PRE_AP_RAISE5MIN_PRICE", 15, 5),
+    ("PRE_AP_RAISEREG_PRICE", 15, 5),
+    ("PRE_AP_LOWER6_PRICE", 15, 5),
+    ("PRE_AP_LOWER60_PRICE", 15, 5),
+    ("PRE_AP_LOWER5MIN_PRICE", 15, 5),
+    ("PRE_AP_LOWERREG_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_ENERGY_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_RAISE6_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_RAISE60_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_RAISE5MIN_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_RAISEREG_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_LOWER6_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_LOWER60_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_LOWER5MIN_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_LOWERREG_PRICE", 15, 5),
+    ("RAISE1SECRRP", 15, 5),
+    ("RAISE1SECROP", 15, 5),
+    ("LOWER1SECRRP", 15, 5),
+    ("LOWER1SECROP", 15, 5),
+    ("PRE_AP_RAISE1_PRICE", 15, 5),
+    ("PRE_AP_LOWER1_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_RAISE1_PRICE", 15, 5),
+    ("CUMUL_PRE_AP_LOWER1_PRICE", 15, 5),
+];
+
+/// Replaces the data type of each of `decimals` with `Decimal(precision,
+/// scale)`
+pub fn with_decimals(schema: &Schema, decimals: &[(&str, usize, usize)]) -> Schema {
+    Schema::new(
+        schema.fields()
+            .iter()
+            .map(|f| match decimals.iter().find(|(name, _, _)| f.name() == *name) {
+                Some((_, precision, scale)) => Field::new(f.name(), DataType::Decimal(*precision, *scale), f.is_nullable()),
+                None => f.clone(),
+            })
+            .collect()
+    )
+}
+
+fn without_columns(schema: &Schema, columns: &[&str]) -> Schema {
+    Schema::new(
+        schema.fields()
+            .iter()
+            .filter(|f| !columns.contains(&f.name().as_str()))
+            .cloned()
+            .collect()
+    )
 }
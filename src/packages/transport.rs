@@ -0,0 +1,282 @@
+use chrono::{DateTime, Local};
+use reqwest::{
+    blocking::Client,
+    header::RANGE,
+    Proxy,
+    StatusCode,
+    Url,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter, self},
+    fs::{metadata, File},
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+    sync::Mutex,
+};
+use crate::error::Error;
+
+/// A response to [`Transport::get`], with the body left to be read
+pub struct Response {
+    pub status: StatusCode,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    pub fn new<R: Read + Send + 'static>(status: StatusCode, body: R) -> Self {
+        Response { status, body: Box::new(body) }
+    }
+
+    pub fn empty(status: StatusCode) -> Self {
+        Response::new(status, std::io::empty())
+    }
+}
+
+impl Debug for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+/// How requests reach nemweb, or whatever is standing in for it
+pub trait Transport: Debug + Send + Sync {
+    /// Requests `url`, from byte `offset` onwards if it isn't zero
+    ///
+    /// A transport that honours the offset responds with 206 Partial
+    /// Content, or 416 if it's past the end, and one that doesn't with 200
+    /// and the whole body.
+    fn get(&self, url: &str, offset: u64) -> Result<Response, Error>;
+}
+
+/// Responds with the part of `body` from `offset`, the way a server
+/// handles a Range request
+fn ranged_response<R: Read + Seek + Send + 'static>(mut body: R, len: u64, offset: u64) -> Result<Response, Error> {
+    match offset {
+        0 => Ok(Response::new(StatusCode::OK, body)),
+        offset if offset >= len => Ok(Response::empty(StatusCode::RANGE_NOT_SATISFIABLE)),
+        offset => {
+            body.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+            Ok(Response::new(StatusCode::PARTIAL_CONTENT, body))
+        },
+    }
+}
+
+/// Requests over http(s) with reqwest
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    client: Client,
+}
+
+impl HttpTransport {
+    /// Without a `proxy`, reqwest's default of the `HTTP_PROXY` and
+    /// `HTTPS_PROXY` environment variables applies
+    pub fn new(user_agent: &str, proxy: Option<&str>) -> Result<Self, Error> {
+        let mut builder = Client::builder().user_agent(user_agent);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(Proxy::all(proxy).map_err(Error::Reqwest)?);
+        }
+        let client = builder.build().map_err(Error::Reqwest)?;
+        Ok(HttpTransport { client })
+    }
+}
+
+impl Transport for HttpTransport {
+    fn get(&self, url: &str, offset: u64) -> Result<Response, Error> {
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send().map_err(Error::Reqwest)?;
+        Ok(Response::new(response.status(), response))
+    }
+}
+
+/// Serves `file://` urls from a local mirror of nemweb
+///
+/// Directories are served as a page like nemweb's own listings, so a mirror
+/// is scraped the same way as the real thing.
+#[derive(Debug, Clone, Default)]
+pub struct FileTransport;
+
+impl Transport for FileTransport {
+    fn get(&self, url: &str, offset: u64) -> Result<Response, Error> {
+        let path = Url::parse(url).ok()
+            .filter(|u| u.scheme() == "file")
+            .and_then(|u| u.to_file_path().ok())
+            .ok_or(Error::UnsupportedUrl(url.to_string()))?;
+        if path.is_dir() {
+            let listing = directory_listing(&path)?.into_bytes();
+            return Ok(Response::new(StatusCode::OK, Cursor::new(listing)))
+        }
+        match File::open(&path) {
+            Ok(file) => {
+                let len = file.metadata().map_err(Error::Io)?.len();
+                ranged_response(file, len, offset)
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Response::empty(StatusCode::NOT_FOUND)),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+}
+
+/// A nemweb style listing of `dir`, each entry preceded by its modified
+/// time and size
+fn directory_listing(dir: &Path) -> Result<String, Error> {
+    let mut paths = dir.read_dir()
+        .map_err(Error::Io)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::Io)?;
+    paths.sort();
+    let mut html = String::from("<html><body><pre>\n");
+    for path in paths {
+        let meta = metadata(&path).map_err(Error::Io)?;
+        let modified = meta.modified()
+            .map(|t| DateTime::<Local>::from(t).format("%A, %B %-d, %Y %-I:%M %p").to_string())
+            .unwrap_or_default();
+        let size = match meta.is_dir() {
+            true => "&lt;dir&gt;".to_string(),
+            false => meta.len().to_string(),
+        };
+        let url = Url::from_file_path(&path)
+            .map_err(|_| Error::InvalidFilename(path.clone()))?;
+        let name = path.file_name()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        html.push_str(&format!("{} {:>12} <A HREF=\"{}\">{}</A><br>\n", modified, size, url, name));
+    }
+    html.push_str("</pre></body></html>\n");
+    Ok(html)
+}
+
+/// Serves responses from memory, so fetching can be tested without a
+/// network
+///
+/// Urls that haven't been inserted are 404s, and failures queued with
+/// [`MemoryTransport::fail`] are responded with before the url is served.
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    failures: Mutex<HashMap<String, Vec<StatusCode>>>,
+    requests: Mutex<Vec<String>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+
+    pub fn insert<U: Into<String>, B: Into<Vec<u8>>>(&self, url: U, body: B) {
+        self.files.lock()
+            .expect("Memory transport lock poisoned")
+            .insert(url.into(), body.into());
+    }
+
+    /// Responds to the next request for `url` with `status`
+    pub fn fail<U: Into<String>>(&self, url: U, status: StatusCode) {
+        self.failures.lock()
+            .expect("Memory transport lock poisoned")
+            .entry(url.into())
+            .or_default()
+            .push(status);
+    }
+
+    /// Every url requested so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock()
+            .expect("Memory transport lock poisoned")
+            .clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn get(&self, url: &str, offset: u64) -> Result<Response, Error> {
+        self.requests.lock()
+            .expect("Memory transport lock poisoned")
+            .push(url.to_string());
+        let failure = self.failures.lock()
+            .expect("Memory transport lock poisoned")
+            .get_mut(url)
+            .filter(|statuses| !statuses.is_empty())
+            .map(|statuses| statuses.remove(0));
+        if let Some(status) = failure {
+            return Ok(Response::empty(status))
+        }
+        let body = self.files.lock()
+            .expect("Memory transport lock poisoned")
+            .get(url)
+            .cloned();
+        match body {
+            Some(body) => {
+                let len = body.len() as u64;
+                ranged_response(Cursor::new(body), len, offset)
+            },
+            None => Ok(Response::empty(StatusCode::NOT_FOUND)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::listing::NemwebListing;
+    use scraper::Html;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn read_body(response: Response) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut body = response.body;
+        body.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn memory_transport_ranges() {
+        let transport = MemoryTransport::new();
+        transport.insert("https://example.com/a.zip", "abcdef");
+        transport.fail("https://example.com/a.zip", StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = transport.get("https://example.com/a.zip", 0).unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        let response = transport.get("https://example.com/a.zip", 0).unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(read_body(response), b"abcdef");
+        let response = transport.get("https://example.com/a.zip", 4).unwrap();
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(read_body(response), b"ef");
+        let response = transport.get("https://example.com/a.zip", 6).unwrap();
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        let response = transport.get("https://example.com/b.zip", 0).unwrap();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(transport.requests().len(), 5);
+    }
+
+    #[test]
+    fn file_transport_listing() {
+        let root = std::env::temp_dir().join(format!("nem-mms-file-transport-{}", std::process::id()));
+        let dir = root.join("Reports").join("Current").join("Dispatch_SCADA");
+        create_dir_all(&dir).unwrap();
+        let fname = "PUBLIC_DISPATCHSCADA_202112251045_0000000354978413.zip";
+        write(dir.join(fname), b"not really a zip").unwrap();
+
+        let base_url = Url::from_directory_path(&root).unwrap().to_string();
+        let url = format!("{}Reports/Current/Dispatch_SCADA", base_url);
+        let page = read_body(FileTransport.get(&url, 0).unwrap());
+        let document = Html::parse_document(&String::from_utf8_lossy(&page));
+        let listing = NemwebListing::from_html(&url, &base_url, &document).unwrap();
+        assert_eq!(listing.files.len(), 1);
+        assert_eq!(listing.files[0].name, fname);
+        assert_eq!(listing.files[0].size, Some(16));
+        assert!(listing.files[0].published.is_some());
+
+        let response = FileTransport.get(&listing.files[0].url, 11).unwrap();
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(read_body(response), b"a zip");
+        let missing = FileTransport.get(&format!("{}missing.zip", base_url), 0).unwrap();
+        assert_eq!(missing.status, StatusCode::NOT_FOUND);
+        assert!(FileTransport.get("https://www.nemweb.com.au/Reports/Current/", 0).is_err());
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex},
+    thread,
+};
+use zip::read::ZipArchive;
+use crate::{
+    error::Error,
+    flatfile::{FlatFileReader, FlatFileTable, ReadOptions},
+    zip::read_zip_member_tables,
+};
+
+/// Number of tables a worker reads ahead of the one being written
+const BUFFERED_TABLES: usize = 4;
+
+/// A csv flat file, or a member of a zip of them, that can be read
+/// independently of the others
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Csv(PathBuf),
+    ZipMember { path: PathBuf, index: usize },
+}
+
+/// Lists the sources under `path`, a csv, a zip or a directory of them
+///
+/// Directories are listed in order of file name so runs are reproducible.
+pub fn sources<P: AsRef<Path>>(path: P) -> Result<Vec<Source>, Error> {
+    let path = path.as_ref();
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("csv") | Some("CSV") => Ok(vec![Source::Csv(path.to_path_buf())]),
+        Some("zip") | Some("ZIP") => {
+            let archive = open_archive(path)?;
+            Ok((0..archive.len())
+                .map(|index| Source::ZipMember { path: path.to_path_buf(), index })
+                .collect())
+        },
+        _ if path.is_dir() => {
+            let mut entries = path.read_dir()
+                .map_err(Error::Io)?
+                .map(|d| d.map(|d| d.path()).map_err(Error::Io))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            let mut out = Vec::new();
+            for entry in entries {
+                out.append(&mut sources(entry)?);
+            }
+            Ok(out)
+        },
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn open_archive(path: &Path) -> Result<ZipArchive<File>, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(Error::Io)?;
+    ZipArchive::new(file).map_err(Error::Zip)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+impl Source {
+    /// Streams the tables of the source into `f`, `archive` keeps the last
+    /// zip opened so its members can be read without reopening it
+    fn read_tables<F>(&self, options: &ReadOptions, archive: &mut Option<(PathBuf, ZipArchive<File>)>, f: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(FlatFileTable) -> Result<(), Error>
+    {
+        match self {
+            Source::Csv(path) => {
+                let rdr = csv::ReaderBuilder::new()
+                    .flexible(true)
+                    .has_headers(false)
+                    .from_path(path)
+                    .map_err(Error::Csv)?;
+                let reader = FlatFileReader::new(rdr)
+                    .with_options(options)
+                    .with_source(&file_name(path));
+                for table in reader {
+                    f(table?)?;
+                }
+                Ok(())
+            },
+            Source::ZipMember { path, index } => {
+                let opened = match archive.take() {
+                    Some((opened_path, opened)) if opened_path == *path => opened,
+                    _ => open_archive(path)?,
+                };
+                let (_, opened) = archive.insert((path.clone(), opened));
+                read_zip_member_tables(opened, *index, &file_name(path), options, f)
+            },
+        }
+    }
+}
+
+/// Streams the tables of every source into `f`, in the same order as reading
+/// them one after another
+///
+/// With more than one job the sources are read on that many threads. Each
+/// reads a few tables ahead into its own buffer, while `f` is given the
+/// tables of the earliest unfinished source as they're read. If reading or
+/// `f` fails the workers are stopped, and joined, before the error is
+/// returned.
+pub fn read_sources<F>(sources: Vec<Source>, options: &ReadOptions, jobs: usize, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(FlatFileTable) -> Result<(), Error>
+{
+    if jobs <= 1 {
+        let mut archive = None;
+        for source in sources.iter() {
+            source.read_tables(options, &mut archive, &mut f)?;
+        }
+        return Ok(())
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = sources.iter()
+        .map(|_| mpsc::sync_channel::<Result<FlatFileTable, Error>>(BUFFERED_TABLES))
+        .unzip();
+    // Workers take sources in order, so the earliest unfinished one is always
+    // being read
+    let queue = Arc::new(Mutex::new(sources.into_iter().zip(senders)));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let workers = (0..jobs)
+        .map(|_| {
+            let queue = queue.clone();
+            let cancelled = cancelled.clone();
+            let options = options.clone();
+            thread::spawn(move || {
+                let mut archive = None;
+                while !cancelled.load(Ordering::SeqCst) {
+                    let next = queue.lock()
+                        .expect("Source queue lock poisoned")
+                        .next();
+                    let (source, tx) = match next {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let read = source.read_tables(&options, &mut archive, &mut |table| {
+                        if cancelled.load(Ordering::SeqCst) {
+                            return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::Interrupted, "Parse stopped")))
+                        }
+                        tx.send(Ok(table))
+                            // Only fails once the reading side has given up
+                            .map_err(|_| Error::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Parse stopped")))
+                    });
+                    if let Err(e) = read {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut receivers = receivers.into_iter();
+    let written = (|| {
+        for rx in &mut receivers {
+            for table in rx {
+                f(table?)?;
+            }
+        }
+        Ok(())
+    })();
+    if written.is_err() {
+        cancelled.store(true, Ordering::SeqCst);
+    }
+    // Wakes any worker blocked on a full buffer to see it's been cancelled
+    drop(receivers);
+    for worker in workers {
+        if let Err(panic) = worker.join() {
+            std::panic::resume_unwind(panic)
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_matches_serial() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let sources = sources(&dir).unwrap();
+        assert!(sources.len() > 1);
+        let read = |jobs| {
+            let mut tables = Vec::new();
+            read_sources(sources.clone(), &ReadOptions::default(), jobs, |t| {
+                tables.push(t);
+                Ok(())
+            }).unwrap();
+            tables
+        };
+        let serial = read(1);
+        assert!(!serial.is_empty());
+        assert_eq!(read(4), serial);
+    }
+
+    #[test]
+    fn parallel_stops_on_error() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let sources = sources(&dir).unwrap();
+        let read = read_sources(sources, &ReadOptions::default(), 4, |_| {
+            Err(Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "Write failed")))
+        });
+        assert!(matches!(read, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::Other));
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+use crate::error::Error;
+
+/// A cell of a data record that couldn't be converted to its column's type,
+/// or a whole record that couldn't be read, with an empty `column`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reject {
+    pub source_file: Option<String>,
+    /// Line of the data record in its source file, when it was read from one
+    pub line: Option<u64>,
+    pub report_type: String,
+    pub report_subtype: String,
+    pub column: String,
+    pub value: String,
+    pub error: String,
+}
+
+/// What happens to a data record with a bad cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectMode {
+    /// Drop the whole record
+    Skip,
+    /// Blank the bad cells, records are still dropped if a bad cell is in a
+    /// column that can't be null
+    Null,
+}
+
+impl Default for RejectMode {
+    fn default() -> Self {
+        RejectMode::Skip
+    }
+}
+
+impl RejectMode {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "skip" => Some(RejectMode::Skip),
+            "null" => Some(RejectMode::Null),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectMode::Skip => "skip",
+            RejectMode::Null => "null",
+        }
+    }
+}
+
+enum Output<W: Write> {
+    Csv(csv::Writer<W>),
+    Json(W),
+}
+
+/// Writes [`Reject`]s as csv with a header row, or as JSON lines
+pub struct RejectWriter<W: Write> {
+    output: Output<W>,
+    count: usize,
+}
+
+impl RejectWriter<BufWriter<File>> {
+    /// Creates the file, as JSON lines if it has a `json` or `jsonl`
+    /// extension and csv otherwise
+    ///
+    /// Fails if the file already exists, rather than losing the rejects of an
+    /// earlier run.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let json = matches!(
+            path.as_ref().extension().and_then(|e| e.to_str()),
+            Some("json") | Some("jsonl")
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(Error::Io)?;
+        let file = BufWriter::new(file);
+        Ok(if json { RejectWriter::json(file) } else { RejectWriter::csv(file) })
+    }
+}
+
+impl<W: Write> RejectWriter<W> {
+    pub fn csv(wtr: W) -> Self {
+        RejectWriter { output: Output::Csv(csv::Writer::from_writer(wtr)), count: 0 }
+    }
+
+    pub fn json(wtr: W) -> Self {
+        RejectWriter { output: Output::Json(wtr), count: 0 }
+    }
+
+    pub fn write(&mut self, reject: &Reject) -> Result<(), Error> {
+        match &mut self.output {
+            Output::Csv(wtr) => wtr.serialize(reject).map_err(Error::Csv)?,
+            Output::Json(wtr) => {
+                serde_json::to_writer(&mut *wtr, reject).map_err(|e| Error::Io(e.into()))?;
+                wtr.write_all(b"\n").map_err(Error::Io)?;
+            },
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of rejected values written so far, a record can have several
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn finish(self) -> Result<W, Error> {
+        match self.output {
+            Output::Csv(wtr) => wtr.into_inner().map_err(|e| Error::Io(e.into_error())),
+            Output::Json(mut wtr) => {
+                wtr.flush().map_err(Error::Io)?;
+                Ok(wtr)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reject() -> Reject {
+        Reject {
+            source_file: Some("PUBLIC_DISPATCHIS.zip/PUBLIC_DISPATCHIS.CSV".to_string()),
+            line: Some(9),
+            report_type: "DISPATCH".to_string(),
+            report_subtype: "PRICE".to_string(),
+            column: "RRP".to_string(),
+            value: "8x.33".to_string(),
+            error: "Datatype mismatch. Expected Float64 but got value 8x.33".to_string(),
+        }
+    }
+
+    #[test]
+    fn write_rejects() {
+        let mut wtr = RejectWriter::csv(Vec::new());
+        wtr.write(&reject()).unwrap();
+        assert_eq!(wtr.count(), 1);
+        let written = String::from_utf8(wtr.finish().unwrap()).unwrap();
+        assert_eq!(written, "source_file,line,report_type,report_subtype,column,value,error\n\
+                             PUBLIC_DISPATCHIS.zip/PUBLIC_DISPATCHIS.CSV,9,DISPATCH,PRICE,RRP,8x.33,\
+                             Datatype mismatch. Expected Float64 but got value 8x.33\n");
+
+        let mut wtr = RejectWriter::json(Vec::new());
+        wtr.write(&reject()).unwrap();
+        let written = String::from_utf8(wtr.finish().unwrap()).unwrap();
+        assert!(written.starts_with("{\"source_file\":\"PUBLIC_DISPATCHIS.zip/PUBLIC_DISPATCHIS.CSV\",\"line\":9,"));
+        assert!(written.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,130 @@
+use arrow::record_batch::RecordBatch;
+use std::io::Write;
+use crate::{
+    error::Error,
+    flatfile::{
+        CommentRecord,
+        CommentRecordEndOfReport,
+        FlatFileTable,
+        Record,
+    },
+    packages::Package,
+};
+
+/// Writes records in AEMO's flat file format
+///
+/// Fields are quoted the way AEMO does it, only when they contain a space,
+/// comma, quote or newline, and lines end with `\r\n`. The END OF REPORT
+/// record is written by `finish` with a count of every record in the file,
+/// so any passed to `write_record` are dropped.
+pub struct FlatFileWriter<W: Write> {
+    wtr: W,
+    count: u32,
+}
+
+impl<W: Write> FlatFileWriter<W> {
+    pub fn new(wtr: W) -> Self {
+        FlatFileWriter { wtr, count: 0 }
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        match record {
+            Record::Comment(CommentRecord::EOR(_)) => Ok(()),
+            _ => self.write_csv_record(&record.to_csv_record()),
+        }
+    }
+
+    pub fn write_table(&mut self, table: &FlatFileTable) -> Result<(), Error> {
+        self.write_csv_record(&table.information_record().to_csv_record())?;
+        for data_record in table.data_records() {
+            self.write_csv_record(&data_record.to_csv_record())?;
+        }
+        Ok(())
+    }
+
+    /// Writes a batch of a package as a table at the package's latest report
+    /// version
+    pub fn write_batch(&mut self, package: Package, batch: &RecordBatch) -> Result<(), Error> {
+        self.write_table(&FlatFileTable::from_arrow(package, batch)?)
+    }
+
+    /// Writes the END OF REPORT record and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        let end_of_report = CommentRecordEndOfReport::new(self.count + 1);
+        self.write_csv_record(&end_of_report.to_csv_record())?;
+        self.wtr.flush().map_err(Error::Io)?;
+        Ok(self.wtr)
+    }
+
+    fn write_csv_record(&mut self, record: &csv::StringRecord) -> Result<(), Error> {
+        let line = record.iter()
+            .map(quote)
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(self.wtr, "{}\r\n", line).map_err(Error::Io)?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+fn quote(field: &str) -> String {
+    if field.contains(|c: char| matches!(c, ' ' | ',' | '"' | '\r' | '\n')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+    use chrono::NaiveDate;
+    use crate::flatfile::{CommentRecordEMMS, FlatFile};
+
+    const DISPATCHIS: &str = include_str!("../tests/data/PUBLIC_DISPATCHIS_202112251045_0000000354978420.CSV");
+
+    fn read(sample: &[u8]) -> FlatFile {
+        let rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(sample);
+        FlatFile::read_csv(rdr).unwrap()
+    }
+
+    #[test]
+    fn round_trip_flatfile() {
+        let flatfile = read(DISPATCHIS.as_bytes());
+        let written = flatfile.write_csv(Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(written.clone()).unwrap(), DISPATCHIS);
+        assert_eq!(read(&written), flatfile);
+    }
+
+    #[test]
+    fn round_trip_arrow() {
+        let flatfile = read(DISPATCHIS.as_bytes());
+        let (package, batch) = flatfile.iter()
+            .filter_map(|t| t.to_arrow().ok())
+            .find(|(p, _)| *p == Package::DispatchPrice)
+            .unwrap();
+
+        let published = NaiveDate::from_ymd(2021, 12, 25).and_hms(10, 40, 12);
+        let header = CommentRecordEMMS::new("NEMP.WORLD", "DISPATCHIS", "AEMO", "PUBLIC", published);
+        let mut writer = FlatFileWriter::new(Vec::new());
+        writer.write_record(&Record::Comment(CommentRecord::EMMS(header))).unwrap();
+        writer.write_batch(package, &batch).unwrap();
+        let written = writer.finish().unwrap();
+
+        let lines = String::from_utf8(written.clone()).unwrap();
+        assert!(lines.starts_with("C,NEMP.WORLD,DISPATCHIS,AEMO,PUBLIC,2021/12/25,10:40:12,,,\r\nI,DISPATCH,PRICE,5,SETTLEMENTDATE,"));
+        assert!(lines.ends_with("C,\"END OF REPORT\",8\r\n"));
+        let tables = read(&written).iter().collect::<Vec<_>>();
+        assert_eq!(tables.len(), 1);
+        let (read_package, read_batch) = tables[0].to_arrow().unwrap();
+        assert_eq!(read_package, package);
+        assert_eq!(read_batch.schema(), batch.schema());
+        for (read_column, column) in read_batch.columns().iter().zip(batch.columns()) {
+            assert_eq!(read_column.data(), column.data());
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::io::{
 };
 use zip::read::ZipArchive;
 use crate::{
-    flatfile::FlatFile,
+    flatfile::{FlatFile, FlatFileReader, FlatFileTable, ReadOptions},
     error::Error
 };
 
@@ -38,3 +38,54 @@ pub fn read_zip<R: Read + Seek>(mut archive: ZipArchive<R>) -> Result<Vec<FlatFi
     }
     Ok(out)
 }
+
+/// Streams every table of the flat files in a (possibly nested) zip archive
+/// into `f`
+///
+/// Csv members are decompressed as they're read rather than buffered, only
+/// nested zips are held in memory since they need to be seekable. Each
+/// table's lineage records its source as `[source]/[member]`, where `source`
+/// names the archive itself.
+pub fn read_zip_tables<R, F>(mut archive: ZipArchive<R>, source: &str, options: &ReadOptions, f: &mut F) -> Result<(), Error>
+where
+    R: Read + Seek,
+    F: FnMut(FlatFileTable) -> Result<(), Error>
+{
+    for i in 0..archive.len() {
+        read_zip_member_tables(&mut archive, i, source, options, f)?;
+    }
+    Ok(())
+}
+
+/// Streams the tables of the member at `index` into `f`, see
+/// [`read_zip_tables`]. Members that aren't csvs or zips are skipped.
+pub fn read_zip_member_tables<R, F>(archive: &mut ZipArchive<R>, index: usize, source: &str, options: &ReadOptions, f: &mut F) -> Result<(), Error>
+where
+    R: Read + Seek,
+    F: FnMut(FlatFileTable) -> Result<(), Error>
+{
+    let mut file = archive.by_index(index).map_err(Error::Zip)?;
+    let fname = file.name().to_string();
+    match fname.split('.').collect::<Vec<_>>()[..] {
+        [.., "zip"] | [.., "ZIP"] => {
+            let mut buff = Cursor::new(Vec::new());
+            file.read_to_end(buff.get_mut()).map_err(Error::Io)?;
+            let sub_archive = ZipArchive::new(buff).map_err(Error::Zip)?;
+            read_zip_tables(sub_archive, &format!("{}/{}", source, fname), options, f)?;
+        },
+        [.., "csv"] | [.., "CSV"] => {
+            let rdr = csv::ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(false)
+                .from_reader(file);
+            let reader = FlatFileReader::new(rdr)
+                .with_options(options)
+                .with_source(&format!("{}/{}", source, fname));
+            for table in reader {
+                f(table?)?;
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Generated by `nem-mms generate` from the MMS Data Model, do not edit by hand
+
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use lazy_static::lazy_static;
+
+/// Tables of the MMS Data Model that aren't built in packages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeneratedPackage {
+    Bidofferperiod,
+}
+
+impl GeneratedPackage {
+    pub const ALL: &[GeneratedPackage] = &[
+        GeneratedPackage::Bidofferperiod,
+    ];
+
+    pub fn all() -> Vec<GeneratedPackage> {
+        GeneratedPackage::ALL.to_vec()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        GeneratedPackage::ALL.iter()
+            .copied()
+            .find(|p| p.name() == name)
+    }
+
+    pub fn from_report_type(report_type: &str, report_subtype: &str) -> Option<Self> {
+        GeneratedPackage::ALL.iter()
+            .copied()
+            .find(|p| p.report_type() == (report_type, report_subtype))
+    }
+
+    /// Package name, the table's name in the MMS Data Model
+    pub fn name(&self) -> &'static str {
+        match *self {
+            GeneratedPackage::Bidofferperiod => "BIDOFFERPERIOD",
+        }
+    }
+
+    /// Report type and subtype of the table's information records
+    pub fn report_type(&self) -> (&'static str, &'static str) {
+        match *self {
+            GeneratedPackage::Bidofferperiod => ("BID", "BIDOFFERPERIOD"),
+        }
+    }
+
+    /// Report version the schema was generated for
+    pub fn version(&self) -> u32 {
+        match *self {
+            GeneratedPackage::Bidofferperiod => 1,
+        }
+    }
+
+    pub fn primary_key(&self) -> &'static [&'static str] {
+        match *self {
+            GeneratedPackage::Bidofferperiod => &["DUID", "BIDTYPE", "TRADINGDATE", "OFFERDATETIME", "PERIODID"],
+        }
+    }
+
+    pub fn schema(&self) -> &'static Schema {
+        match *self {
+            GeneratedPackage::Bidofferperiod => &BIDOFFERPERIOD,
+        }
+    }
+
+    /// Schema with `NUMBER(p,s)` columns as `Decimal(p, s)` rather than `Float64`
+    pub fn decimal_schema(&self) -> &'static Schema {
+        match *self {
+            GeneratedPackage::Bidofferperiod => &BIDOFFERPERIOD_DECIMAL,
+        }
+    }
+}
+
+lazy_static! {
+    static ref BIDOFFERPERIOD: Schema = Schema::new(
+        vec![
+            Field::new("DUID", DataType::Utf8, false),
+            Field::new("BIDTYPE", DataType::Utf8, false),
+            Field::new("TRADINGDATE", DataType::Timestamp(TimeUnit::Second, None), false),
+            Field::new("OFFERDATETIME", DataType::Timestamp(TimeUnit::Second, None), false),
+            Field::new("PERIODID", DataType::Int16, false),
+            Field::new("MAXAVAIL", DataType::Float64, true),
+            Field::new("LASTCHANGED", DataType::Timestamp(TimeUnit::Second, None), true),
+        ]
+    );
+
+    static ref BIDOFFERPERIOD_DECIMAL: Schema = Schema::new(
+        vec![
+            Field::new("DUID", DataType::Utf8, false),
+            Field::new("BIDTYPE", DataType::Utf8, false),
+            Field::new("TRADINGDATE", DataType::Timestamp(TimeUnit::Second, None), false),
+            Field::new("OFFERDATETIME", DataType::Timestamp(TimeUnit::Second, None), false),
+            Field::new("PERIODID", DataType::Int16, false),
+            Field::new("MAXAVAIL", DataType::Decimal(12, 6), true),
+            Field::new("LASTCHANGED", DataType::Timestamp(TimeUnit::Second, None), true),
+        ]
+    );
+}